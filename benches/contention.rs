@@ -0,0 +1,113 @@
+//! Sends `Data` frames from several threads at once, each on its own stream of a single local
+//! connection, while the connection is driven and the frames are consumed on a runtime.
+//!
+//! Every frame is waited for until it is queued, so only frames which reach the remote are
+//! counted. The `connection lock` group sends through the connection's context, as every stream
+//! did before streams were locked individually, and serves as the baseline for the
+//! `stream lock` group.
+//!
+//! Run with `cargo bench --bench contention`.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::{executor, future, StreamExt};
+use spaniel::connection::ConnectionError;
+use spaniel::frames::{Data, Frame};
+use spaniel::local;
+use spaniel::stream::{StreamId, StreamRef};
+use spaniel::ConnectionConfig;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Instant;
+
+const STREAMS: u32 = 8;
+
+/// Opens `STREAMS` streams on a local connection, consuming everything received on them
+fn open_streams(runtime: &tokio::runtime::Runtime) -> Vec<StreamRef> {
+    let (driver, (client, _), (_, mut incoming)) = local::pair(ConnectionConfig::default());
+    runtime.spawn(driver);
+    runtime.spawn(async move {
+        while let Some(Ok(stream)) = incoming.next().await {
+            tokio::spawn(stream.for_each(|_| async {}));
+        }
+    });
+    let opened = (1..=STREAMS).map(move |id| client.open_stream(StreamId(id), 1 << 20));
+    runtime.block_on(future::try_join_all(opened)).unwrap()
+}
+
+/// Sends `count` frames on `stream`, waiting for room for each of them
+fn send_with_stream_lock(stream: &mut StreamRef, payload: &Bytes, count: u64) {
+    executor::block_on(async {
+        for _ in 0..count {
+            stream.send(payload.clone()).await.unwrap();
+        }
+    })
+}
+
+/// Sends `count` frames on `stream` through the connection's context, retrying until each of
+/// them is queued
+fn send_with_connection_lock(stream: &mut StreamRef, payload: &Bytes, count: u64) {
+    let ctx = stream.clone_ctx();
+    for _ in 0..count {
+        loop {
+            let data = Data::new(stream.stream_id(), 0, payload.clone());
+            match ctx.lock().unwrap().send_frame(Frame::Data(data)) {
+                Ok(()) => break,
+                Err(ConnectionError::QueueFull) | Err(ConnectionError::InsufficientCredit) => {}
+                Err(err) => panic!("failed to send: {}", err),
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+fn bench_senders(
+    c: &mut Criterion,
+    name: &str,
+    streams: &[StreamRef],
+    send: fn(&mut StreamRef, &Bytes, u64),
+) {
+    let payload = Bytes::from_static(&[0u8; 64]);
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(1));
+    for threads in &[1, 2, 4, 8] {
+        let threads = *threads;
+        group.bench_function(format!("{} threads", threads), |b| {
+            b.iter_custom(|iters| {
+                let per_thread = iters / threads as u64 + 1;
+                let barrier = Arc::new(Barrier::new(threads));
+                let senders: Vec<_> = streams[..threads]
+                    .iter()
+                    .cloned()
+                    .map(|mut stream| {
+                        let barrier = barrier.clone();
+                        let payload = payload.clone();
+                        thread::spawn(move || {
+                            barrier.wait();
+                            let start = Instant::now();
+                            send(&mut stream, &payload, per_thread);
+                            start.elapsed()
+                        })
+                    })
+                    .collect();
+                // All `iters` frames were queued once the slowest thread is done
+                senders
+                    .into_iter()
+                    .map(|t| t.join().unwrap())
+                    .max()
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn send_data(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let streams = open_streams(&runtime);
+    bench_senders(c, "stream lock", &streams, send_with_stream_lock);
+    bench_senders(c, "connection lock", &streams, send_with_connection_lock);
+}
+
+criterion_group!(benches, send_data);
+criterion_main!(benches);
@@ -0,0 +1,161 @@
+//! Challenge/response authentication with a pre-shared key.
+//!
+//! After exchanging handshakes, each end sends a random nonce and then proves knowledge of the
+//! key with an HMAC-SHA256 over both nonces and its role in the connection. Including the role
+//! keeps a peer from reflecting a proof back to the end which computed it. The connection is not
+//! used for anything else until both proofs have been verified.
+
+use crate::connection::ConnectionError;
+use crate::protocol::frames::Auth;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+
+const NONCE_LEN: usize = 16;
+
+/// Pre-shared key which both ends of a connection must know
+#[derive(Clone)]
+pub struct AuthConfig {
+    key: Bytes,
+}
+
+impl AuthConfig {
+    pub fn new<K: Into<Bytes>>(key: K) -> Self {
+        AuthConfig { key: key.into() }
+    }
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Authentication state of one end of a connection
+pub(crate) struct Authenticator {
+    key: Bytes,
+    /// Whether this end opened the connection
+    initiator: bool,
+    nonce: Bytes,
+    peer_nonce: Option<Bytes>,
+    verified: bool,
+    /// Frame waiting to be sent to the peer
+    pub(crate) pending: Option<Auth>,
+}
+
+impl Authenticator {
+    pub fn new(cfg: &AuthConfig, initiator: bool) -> Self {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let nonce = Bytes::copy_from_slice(&nonce);
+        Authenticator {
+            key: cfg.key.clone(),
+            initiator,
+            nonce: nonce.clone(),
+            peer_nonce: None,
+            verified: false,
+            // The challenge carries this end's nonce
+            pending: Some(Auth {
+                nonce,
+                proof: Bytes::new(),
+            }),
+        }
+    }
+
+    /// Returns true once the peer's proof was verified and this end's proof was sent
+    pub fn is_complete(&self) -> bool {
+        self.verified && self.pending.is_none()
+    }
+
+    /// Handles an authentication frame from the peer, queueing this end's proof in response to
+    /// its challenge
+    pub fn on_auth(&mut self, auth: Auth) -> Result<(), ConnectionError> {
+        match self.peer_nonce {
+            None if auth.nonce.len() == NONCE_LEN && auth.proof.is_empty() => {
+                let proof = self
+                    .mac(self.initiator, &auth.nonce)
+                    .finalize()
+                    .into_bytes();
+                self.peer_nonce = Some(auth.nonce);
+                self.pending = Some(Auth {
+                    nonce: Bytes::new(),
+                    proof: Bytes::copy_from_slice(&proof),
+                });
+                Ok(())
+            }
+            Some(ref peer_nonce) if auth.nonce.is_empty() && !self.verified => {
+                self.mac(!self.initiator, peer_nonce)
+                    .verify_slice(&auth.proof)
+                    .map_err(|_| ConnectionError::Authentication)?;
+                self.verified = true;
+                Ok(())
+            }
+            _ => Err(ConnectionError::Authentication),
+        }
+    }
+
+    /// Starts the MAC proving that the end in the given role knows the key
+    fn mac(&self, initiator: bool, peer_nonce: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key");
+        let (initiator_nonce, acceptor_nonce) = if self.initiator {
+            (&self.nonce[..], peer_nonce)
+        } else {
+            (peer_nonce, &self.nonce[..])
+        };
+        let role: &[u8] = if initiator {
+            b"spaniel initiator"
+        } else {
+            b"spaniel acceptor"
+        };
+        mac.update(role);
+        mac.update(initiator_nonce);
+        mac.update(acceptor_nonce);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionConfig;
+    use crate::stream::StreamId;
+    use crate::transport::memory;
+    use futures::future::{self, Either};
+    use futures::StreamExt;
+
+    /// Connects two drivers with the given keys, returning the stream accepted by the second one
+    async fn connect(
+        client_key: &'static str,
+        server_key: &'static str,
+    ) -> Result<StreamId, ConnectionError> {
+        let cfg = |key| ConnectionConfig {
+            auth: Some(AuthConfig::new(key)),
+            ..ConnectionConfig::default()
+        };
+        let (client, mut server) = memory::drivers(cfg(client_key), cfg(server_key));
+        let handle = client.handle();
+        tokio::spawn(client);
+        tokio::spawn(handle.open_stream(StreamId(1), 1024));
+
+        let mut incoming = server.incoming_streams();
+        let accepted = Box::pin(async move { incoming.next().await.unwrap() });
+        // Whichever completes first: the accepted stream or the driver's failure
+        match future::select(server, accepted).await {
+            Either::Left((res, _)) => Err(res.err().unwrap_or(ConnectionError::Closed)),
+            Either::Right((stream, _)) => stream.map(|stream| stream.stream_id()),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_streams_only_from_peers_knowing_the_key() {
+        assert_eq!(connect("secret", "secret").await, Ok(StreamId(1)));
+        assert_eq!(
+            connect("guess", "secret").await,
+            Err(ConnectionError::Authentication)
+        );
+    }
+}
@@ -0,0 +1,377 @@
+//! Blocking interface for callers which do not run on an async runtime.
+//!
+//! A `BlockingConnection` drives its `ConnectionDriver` on a runtime with a background thread of
+//! its own, and blocks the calling thread until each operation completes. Sending blocks while the
+//! stream lacks credit or the connection's writer is above its high watermark, so backpressure
+//! reaches the caller just like it reaches async senders.
+//!
+//! None of these methods may be called from within an async runtime.
+
+use crate::connection::next_connection_id;
+use crate::connection::ConnectionConfig;
+use crate::connection::ConnectionDriver;
+use crate::connection::ConnectionError;
+use crate::connection::ConnectionHandle;
+use crate::metrics::{ConnectionMetrics, StreamMetrics};
+use crate::protocol::frames::Frame;
+use crate::socket::SocketConfig;
+use crate::stream::IncomingStreams;
+use crate::stream::StreamId;
+use crate::stream::StreamRef;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+
+/// Configuration of a `BlockingConnection`
+#[derive(Debug, Clone, Default)]
+pub struct BlockingConfig {
+    /// Options applied to the connection's socket
+    pub socket: SocketConfig,
+    pub connection: ConnectionConfig,
+    /// Maximum time to wait for the TCP connection to be established, or `None` to use the OS
+    /// default. Also bounds the wait for the remote's handshake on accepted connections, which
+    /// is unbounded if `None`.
+    pub connect_timeout: Option<Duration>,
+}
+
+/// Runs `future` to completion on `runtime`, failing with `ConnectionError::Timeout` if it takes
+/// longer than `timeout`
+fn block_on<T, F>(
+    runtime: &Runtime,
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<T, ConnectionError>
+where
+    F: Future<Output = Result<T, ConnectionError>>,
+{
+    match timeout {
+        None => runtime.block_on(future),
+        Some(timeout) => runtime.block_on(async {
+            tokio::time::timeout(timeout, future)
+                .await
+                .unwrap_or(Err(ConnectionError::Timeout))
+        }),
+    }
+}
+
+/// Connection whose methods block the calling thread
+pub struct BlockingConnection {
+    /// Shared with the connection's streams, which keep the connection driven while they are used
+    runtime: Arc<Runtime>,
+    handle: ConnectionHandle,
+    incoming: Mutex<IncomingStreams>,
+}
+
+impl BlockingConnection {
+    /// Connects to `addr`, blocking until the TCP connection is established
+    pub fn connect(addr: SocketAddr, cfg: BlockingConfig) -> Result<Self, ConnectionError> {
+        let socket = match cfg.connect_timeout {
+            Some(timeout) => net::TcpStream::connect_timeout(&addr, timeout)?,
+            None => net::TcpStream::connect(addr)?,
+        };
+        BlockingConnection::from_std(socket, cfg, true)
+    }
+
+    /// Accepts a connection on `listener`, blocking until a remote connects
+    pub fn accept(
+        listener: &net::TcpListener,
+        cfg: BlockingConfig,
+    ) -> Result<Self, ConnectionError> {
+        let (socket, addr) = listener.accept()?;
+        debug!(peer = %addr, "accepted connection");
+        BlockingConnection::from_std(socket, cfg, false)
+    }
+
+    fn from_std(
+        socket: net::TcpStream,
+        cfg: BlockingConfig,
+        initiator: bool,
+    ) -> Result<Self, ConnectionError> {
+        let runtime = new_runtime()?;
+        let socket = {
+            // Registering the socket requires the runtime's reactor
+            let _enter = runtime.enter();
+            socket.set_nonblocking(true)?;
+            TcpStream::from_std(socket)?
+        };
+        if let Err(err) = cfg.socket.configure(&socket) {
+            warn!(error = %err, "could not configure socket");
+        }
+        let (rx, tx) = tokio::io::split(socket);
+        let id = next_connection_id();
+        let driver = if initiator {
+            ConnectionDriver::with_config(rx, tx, id, cfg.connection)
+        } else {
+            // The session is only known once the remote's handshake has been received
+            let driver = ConnectionDriver::accept(rx, tx, id, cfg.connection);
+            let (mut driver, _) = block_on(&runtime, cfg.connect_timeout, driver.handshake())?;
+            driver.accept_session(None);
+            driver
+        };
+        Ok(BlockingConnection::spawn(runtime, driver))
+    }
+
+    /// Drives `driver` in the background.
+    ///
+    /// Transports which have to be registered with a runtime, such as tokio's sockets, cannot be
+    /// created outside of one; use `connect` or `accept` for TCP.
+    pub fn from_driver<I, O>(driver: ConnectionDriver<I, O>) -> Result<Self, ConnectionError>
+    where
+        I: AsyncRead + Unpin + Send + 'static,
+        O: AsyncWrite + Unpin + Send + 'static,
+    {
+        Ok(BlockingConnection::spawn(new_runtime()?, driver))
+    }
+
+    fn spawn<I, O>(runtime: Runtime, mut driver: ConnectionDriver<I, O>) -> Self
+    where
+        I: AsyncRead + Unpin + Send + 'static,
+        O: AsyncWrite + Unpin + Send + 'static,
+    {
+        let handle = driver.handle();
+        let incoming = driver.incoming_streams();
+        runtime.spawn(async move {
+            if let Err(err) = driver.await {
+                debug!(error = %err, "connection ended");
+            }
+        });
+        BlockingConnection {
+            runtime: Arc::new(runtime),
+            handle,
+            incoming: Mutex::new(incoming),
+        }
+    }
+
+    /// Opens a stream with the given credit capacity
+    pub fn open_stream(
+        &self,
+        stream_id: StreamId,
+        credit: u32,
+    ) -> Result<BlockingStream, ConnectionError> {
+        let stream = block_on(
+            &self.runtime,
+            None,
+            self.handle.open_stream(stream_id, credit),
+        )?;
+        Ok(BlockingStream::new(self.runtime.clone(), stream))
+    }
+
+    /// Waits for the remote to open a stream, for at most `timeout` if given
+    pub fn accept_stream(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<BlockingStream, ConnectionError> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let stream = block_on(&self.runtime, timeout, async {
+            incoming
+                .next()
+                .await
+                .unwrap_or(Err(ConnectionError::Closed))
+        })?;
+        Ok(BlockingStream::new(self.runtime.clone(), stream))
+    }
+
+    /// Returns a handle to the connection, for use from async code
+    pub fn handle(&self) -> ConnectionHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the error which closed the connection, if any
+    pub fn err(&self) -> Option<ConnectionError> {
+        self.handle.err()
+    }
+
+    /// Returns a snapshot of the connection's metrics, including those of all its streams
+    pub fn metrics(&self) -> ConnectionMetrics {
+        self.handle.metrics()
+    }
+
+    /// Closes the connection, failing all of its streams with `ConnectionError::Closed`
+    pub fn close(&self) {
+        self.handle.close();
+    }
+}
+
+fn new_runtime() -> io::Result<Runtime> {
+    Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("spaniel-blocking")
+        .enable_all()
+        .build()
+}
+
+/// Stream whose methods block the calling thread
+pub struct BlockingStream {
+    runtime: Arc<Runtime>,
+    stream: StreamRef,
+    /// Frames other than Data frames which were received by `recv`, kept for `recv_frame`
+    frames: VecDeque<Frame>,
+}
+
+impl BlockingStream {
+    fn new(runtime: Arc<Runtime>, stream: StreamRef) -> Self {
+        BlockingStream {
+            runtime,
+            stream,
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn stream_id(&self) -> StreamId {
+        self.stream.stream_id()
+    }
+
+    /// Sends `payload` in a Data frame, waiting for at most `timeout` if given until the stream has
+    /// credit and the connection has room for it
+    pub fn send(
+        &mut self,
+        payload: Bytes,
+        timeout: Option<Duration>,
+    ) -> Result<(), ConnectionError> {
+        let stream = &mut self.stream;
+        block_on(&self.runtime, timeout, stream.send(payload))
+    }
+
+    /// Sends `payload` as a single message of any length, waiting for at most `timeout` if given
+    /// until all of its fragments have been queued
+    pub fn send_message(
+        &mut self,
+        payload: Bytes,
+        timeout: Option<Duration>,
+    ) -> Result<(), ConnectionError> {
+        let stream = &mut self.stream;
+        block_on(&self.runtime, timeout, stream.send_message(payload))
+    }
+
+    /// Receives the payload of the next Data frame, waiting for at most `timeout` if given.
+    ///
+    /// Returns `None` once the stream has ended. The payload's credit is not returned to the
+    /// remote until `return_credit` is called. Other frames received in the meantime, such as
+    /// extension frames, are kept for `recv_frame`.
+    pub fn recv(&mut self, timeout: Option<Duration>) -> Result<Option<Bytes>, ConnectionError> {
+        let stream = &mut self.stream;
+        let frames = &mut self.frames;
+        block_on(&self.runtime, timeout, async {
+            loop {
+                match stream.next().await {
+                    Some(Ok(Frame::Data(data))) => return Ok(Some(data.payload())),
+                    Some(Ok(frame)) => frames.push_back(frame),
+                    Some(Err(err)) => return Err(err),
+                    None => return Ok(None),
+                }
+            }
+        })
+    }
+
+    /// Receives the next frame of any type, waiting for at most `timeout` if given.
+    ///
+    /// Frames kept by `recv` are returned first. Returns `None` once the stream has ended.
+    pub fn recv_frame(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Frame>, ConnectionError> {
+        if let Some(frame) = self.frames.pop_front() {
+            return Ok(Some(frame));
+        }
+        let stream = &mut self.stream;
+        block_on(&self.runtime, timeout, async {
+            stream.next().await.transpose()
+        })
+    }
+
+    /// Returns credit to the remote once received payloads have been consumed
+    pub fn return_credit(&mut self, credit: u32) {
+        let _res = self.stream.return_credit(credit);
+    }
+
+    /// Returns a snapshot of this stream's metrics
+    pub fn metrics(&self) -> Option<StreamMetrics> {
+        self.stream.metrics()
+    }
+
+    /// Returns the async handle of this stream
+    pub fn into_inner(self) -> StreamRef {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::frames::{Extension, MIN_EXTENSION_TYPE};
+    use std::thread;
+
+    #[test]
+    fn sends_and_receives_without_a_runtime() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut cfg = BlockingConfig::default();
+            cfg.connection
+                .extensions
+                .register_stream(MIN_EXTENSION_TYPE)
+                .unwrap();
+            let conn = BlockingConnection::accept(&listener, cfg).unwrap();
+            let mut stream = conn.accept_stream(Some(Duration::from_secs(5))).unwrap();
+            let received: Vec<_> = (0..3)
+                .map(|_| stream.recv(Some(Duration::from_secs(5))).unwrap().unwrap())
+                .collect();
+            let idle = stream.recv(Some(Duration::from_millis(50)));
+            let ext = match stream.recv_frame(None) {
+                Ok(Some(Frame::Extension(ext))) => ext.payload,
+                other => panic!("unexpected result: {:?}", other),
+            };
+            (received, idle, ext)
+        });
+
+        let conn = BlockingConnection::connect(addr, BlockingConfig::default()).unwrap();
+        let mut stream = conn.open_stream(StreamId(1), 1024).unwrap();
+        for i in 0..3 {
+            stream
+                .send(Bytes::from(format!("frame {}", i)), None)
+                .unwrap();
+            if i == 0 {
+                // Received in between Data frames, and kept for `recv_frame`
+                let ext = Extension::new(MIN_EXTENSION_TYPE, StreamId(1), Bytes::from("ext"));
+                conn.handle().send_extension(ext).unwrap();
+            }
+        }
+
+        let (received, idle, ext) = server.join().unwrap();
+        let expected: Vec<_> = (0..3)
+            .map(|i| Bytes::from(format!("frame {}", i)))
+            .collect();
+        assert_eq!(received, expected);
+        assert_eq!(idle, Err(ConnectionError::Timeout));
+        assert_eq!(ext, Bytes::from("ext"));
+        conn.close();
+        assert_eq!(
+            stream.send(Bytes::from("closed"), None),
+            Err(ConnectionError::Closed)
+        );
+    }
+
+    #[test]
+    fn times_out_waiting_for_handshake() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // Connected, but never sends a handshake
+        let _client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let cfg = BlockingConfig {
+            connect_timeout: Some(Duration::from_millis(50)),
+            ..BlockingConfig::default()
+        };
+        assert_eq!(
+            BlockingConnection::accept(&listener, cfg).err(),
+            Some(ConnectionError::Timeout)
+        );
+    }
+}
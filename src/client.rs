@@ -0,0 +1,724 @@
+//! Connector which keeps a connection to a remote address alive.
+//!
+//! When the connection's `ConnectionDriver` fails, the client re-establishes the connection
+//! after a jittered exponential backoff. Streams opened on the failed connection observe its
+//! error, while new streams are opened on the replacement connection.
+//!
+//! If resumption is enabled on both ends, the replacement connection continues the session
+//! instead: its streams survive and the frames lost with the failed connection are replayed.
+
+use crate::connection::next_connection_id;
+use crate::connection::ConnectionConfig;
+use crate::connection::ConnectionDriver;
+use crate::connection::ConnectionError;
+use crate::connection::ConnectionHandle;
+use crate::connection::SharedConnectionContext;
+use crate::socket::SocketConfig;
+use crate::stream::StreamId;
+use crate::stream::StreamRef;
+use crate::stream::StreamRequester;
+#[cfg(feature = "tls")]
+use crate::transport::tls::{TlsClientConfig, TlsConnector};
+#[cfg(unix)]
+use crate::transport::UnixConnector;
+use crate::transport::{Connector, TcpConnector};
+use rand::Rng;
+use std::cmp;
+use std::future::Future;
+#[cfg(feature = "tls")]
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::runtime::Handle;
+use tokio::time::Sleep;
+
+/// Jittered exponential backoff between connection attempts
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    /// Delay before the first reconnection attempt
+    pub initial: Duration,
+    /// Upper bound on the delay between attempts
+    pub max: Duration,
+    /// Factor by which the delay grows after each failed attempt
+    pub multiplier: f64,
+    /// Fraction of the delay, in `[0, 1]`, which is randomly subtracted from it
+    pub jitter: f64,
+    /// Number of consecutive failed attempts after which the client gives up, or `None` to retry
+    /// forever
+    pub max_retries: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_retries: None,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Returns the delay before the attempt following `failures` consecutive failures
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exp = self.multiplier.powi(cmp::min(failures, 64) as i32);
+        let max = self.max.as_secs_f64();
+        let delay = (self.initial.as_secs_f64() * exp).min(max);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+}
+
+/// Configuration of a `Client`
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    /// Options applied to every established socket
+    pub socket: SocketConfig,
+    pub backoff: BackoffConfig,
+    /// Options of every established connection
+    pub connection: ConnectionConfig,
+}
+
+/// State shared between the `Supervisor` and all `ClientHandle`s
+#[derive(Default)]
+struct Shared {
+    /// The currently established connection, if any
+    conn: Option<ConnectionHandle>,
+    /// Number of connections established so far
+    generation: u64,
+    /// Error which made the client give up, if any
+    err: Option<ConnectionError>,
+    /// Set once the client has been closed through a handle, or all of its handles were dropped
+    closed: bool,
+    /// Number of live `ClientHandle`s
+    handles: usize,
+    /// Tasks waiting for a connection to be established
+    waiters: Vec<Waker>,
+    /// Task which drives the supervisor
+    supervisor: Option<Waker>,
+}
+
+impl Shared {
+    fn notify_waiters(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Stops the supervisor, which closes the current connection
+    fn close(&mut self) {
+        self.closed = true;
+        self.notify_waiters();
+        if let Some(waker) = self.supervisor.take() {
+            waker.wake();
+        }
+    }
+}
+
+type SharedState = Arc<Mutex<Shared>>;
+
+/// Entry point for establishing reconnecting connections
+pub struct Client;
+
+impl Client {
+    /// Starts connecting to `addr` in the background, returning a handle right away.
+    ///
+    /// Must be called within a tokio runtime, on which the task keeping the connection alive is
+    /// spawned.
+    pub fn spawn(addr: SocketAddr, cfg: ClientConfig) -> Result<ClientHandle, ConnectionError> {
+        Client::spawn_with(TcpConnector::new(addr), cfg)
+    }
+
+    /// Starts connecting through `connector` in the background, returning a handle right away.
+    ///
+    /// Must be called within a tokio runtime, on which the task keeping the connection alive is
+    /// spawned.
+    pub fn spawn_with<C: Connector>(
+        connector: C,
+        cfg: ClientConfig,
+    ) -> Result<ClientHandle, ConnectionError> {
+        let supervisor = Supervisor::new(connector, cfg);
+        let handle = ClientHandle::new(supervisor.shared.clone());
+        Handle::try_current()
+            .map_err(|_| ConnectionError::General)?
+            .spawn(supervisor);
+        Ok(handle)
+    }
+
+    /// Connects to `addr`, resolving to a handle once the first connection is established.
+    ///
+    /// Must be polled within a tokio runtime, on which the task keeping the connection alive is
+    /// spawned.
+    pub fn connect(addr: SocketAddr, cfg: ClientConfig) -> Connect {
+        Client::connect_with(TcpConnector::new(addr), cfg)
+    }
+
+    /// Connects to `addr` over TLS, resolving to a handle once the first connection is
+    /// established.
+    ///
+    /// Fails right away if `tls` is invalid; failed TLS handshakes are retried like any other
+    /// failed connection attempt.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        addr: SocketAddr,
+        tls: &TlsClientConfig,
+        cfg: ClientConfig,
+    ) -> io::Result<Connect<TlsConnector<TcpConnector>>> {
+        let mut connector =
+            TlsConnector::new(TcpConnector::new(addr), tls.build()?, &tls.server_name)?;
+        connector.set_handshake_timeout(tls.handshake_timeout);
+        Ok(Client::connect_with(connector, cfg))
+    }
+
+    /// Connects to the Unix domain socket at `path`, resolving to a handle once the first
+    /// connection is established.
+    ///
+    /// `ClientConfig::socket` only applies to TCP and is ignored.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P, cfg: ClientConfig) -> Connect<UnixConnector> {
+        Client::connect_with(UnixConnector::new(path), cfg)
+    }
+
+    /// Connects through `connector`, resolving to a handle once the first connection is
+    /// established.
+    ///
+    /// Must be polled within a tokio runtime, on which the task keeping the connection alive is
+    /// spawned.
+    pub fn connect_with<C: Connector>(connector: C, cfg: ClientConfig) -> Connect<C> {
+        let supervisor = Supervisor::new(connector, cfg);
+        let handle = ClientHandle::new(supervisor.shared.clone());
+        Connect {
+            supervisor: Some(supervisor),
+            handle,
+        }
+    }
+}
+
+/// Future which resolves to a `ClientHandle` once the client has connected
+pub struct Connect<C: Connector = TcpConnector> {
+    supervisor: Option<Supervisor<C>>,
+    handle: ClientHandle,
+}
+
+impl<C: Connector> Future for Connect<C> {
+    type Output = Result<ClientHandle, ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(supervisor) = self.supervisor.take() {
+            Handle::try_current()
+                .map_err(|_| ConnectionError::General)?
+                .spawn(supervisor);
+        }
+        ready!(self.handle.poll_connection(cx))?;
+        Poll::Ready(Ok(self.handle.clone()))
+    }
+}
+
+/// Connection state of a client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Health {
+    /// The first connection has not been established yet
+    Connecting,
+    Connected,
+    /// The connection was lost and is being re-established
+    Reconnecting,
+    /// The client gave up or was closed
+    Failed(ConnectionError),
+}
+
+/// Cloneable handle to a client's current connection.
+///
+/// The client closes its connection and stops reconnecting once all of its handles are dropped.
+pub struct ClientHandle {
+    shared: SharedState,
+}
+
+impl Clone for ClientHandle {
+    fn clone(&self) -> Self {
+        ClientHandle::new(self.shared.clone())
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.handles -= 1;
+        if shared.handles == 0 {
+            shared.close();
+        }
+    }
+}
+
+impl ClientHandle {
+    fn new(shared: SharedState) -> Self {
+        shared.lock().unwrap().handles += 1;
+        ClientHandle { shared }
+    }
+
+    /// Returns the currently established connection, if any
+    pub fn connection(&self) -> Option<ConnectionHandle> {
+        self.shared.lock().unwrap().conn.clone()
+    }
+
+    /// Returns the number of connections established so far, including the current one
+    pub fn generation(&self) -> u64 {
+        self.shared.lock().unwrap().generation
+    }
+
+    /// Returns the state of the client's connection
+    pub fn health(&self) -> Health {
+        let shared = self.shared.lock().unwrap();
+        if shared.closed {
+            return Health::Failed(ConnectionError::Closed);
+        }
+        if let Some(ref err) = shared.err {
+            return Health::Failed(err.clone());
+        }
+        match shared.conn {
+            Some(_) => Health::Connected,
+            None if shared.generation == 0 => Health::Connecting,
+            None => Health::Reconnecting,
+        }
+    }
+
+    /// Returns a future which opens a stream once a connection is available
+    pub fn open_stream(&self, stream_id: StreamId, credit: u32) -> OpenStream {
+        OpenStream {
+            handle: self.clone(),
+            stream_id,
+            credit,
+            requester: None,
+        }
+    }
+
+    /// Closes the current connection and stops reconnecting
+    pub fn close(&self) {
+        self.shared.lock().unwrap().close();
+    }
+
+    /// Returns the current connection, or `Poll::Pending` while the client is reconnecting
+    pub fn poll_connection(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<ConnectionHandle, ConnectionError>> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.closed {
+            return Poll::Ready(Err(ConnectionError::Closed));
+        }
+        if let Some(ref err) = shared.err {
+            return Poll::Ready(Err(err.clone()));
+        }
+        match shared.conn.clone() {
+            Some(conn) => Poll::Ready(Ok(conn)),
+            None => {
+                shared.waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Waits for a connection to be available, returning it
+    pub async fn connected(&self) -> Result<ConnectionHandle, ConnectionError> {
+        futures::future::poll_fn(|cx| self.poll_connection(cx)).await
+    }
+}
+
+/// Future which opens a stream on the client's connection once one is available
+pub struct OpenStream {
+    handle: ClientHandle,
+    stream_id: StreamId,
+    credit: u32,
+    requester: Option<StreamRequester>,
+}
+
+impl Future for OpenStream {
+    type Output = Result<StreamRef, ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.requester.is_none() {
+            let conn = ready!(self.handle.poll_connection(cx))?;
+            self.requester = Some(conn.open_stream(self.stream_id, self.credit));
+        }
+        Pin::new(self.requester.as_mut().unwrap()).poll(cx)
+    }
+}
+
+type Driver<T> = ConnectionDriver<ReadHalf<T>, WriteHalf<T>>;
+
+enum State<C: Connector> {
+    Connecting(C::Future),
+    Connected(Box<Driver<C::Io>>),
+    Waiting(Pin<Box<Sleep>>),
+}
+
+enum Transition<T> {
+    Connected(T),
+    Failed(ConnectionError),
+    /// The connection was closed through one of its handles
+    Closed,
+    Retry,
+}
+
+/// Task which establishes connections and re-establishes them when they fail
+struct Supervisor<C: Connector> {
+    connector: C,
+    cfg: ClientConfig,
+    shared: SharedState,
+    state: State<C>,
+    /// Number of consecutive failed connection attempts
+    failures: u32,
+    /// Session continued by the next connection, if it can be resumed
+    session: Option<SharedConnectionContext>,
+}
+
+impl<C: Connector> Supervisor<C> {
+    fn new(connector: C, cfg: ClientConfig) -> Self {
+        Supervisor {
+            state: State::Connecting(connector.connect()),
+            connector,
+            cfg,
+            shared: Arc::new(Mutex::new(Shared::default())),
+            failures: 0,
+            session: None,
+        }
+    }
+
+    fn on_connected(&mut self, socket: C::Io) -> State<C> {
+        if let Err(err) = self.connector.configure(&socket, &self.cfg.socket) {
+            warn!(error = %err, "could not configure socket");
+        }
+        let (rx, tx) = tokio::io::split(socket);
+        let mut driver = match self.session {
+            Some(ref ctx) => ConnectionDriver::resume(rx, tx, ctx.clone()),
+            None => {
+                let cfg = self.cfg.connection.clone();
+                ConnectionDriver::with_config(rx, tx, next_connection_id(), cfg)
+            }
+        };
+        if self.cfg.connection.resumption.is_some() {
+            self.session = Some(driver.clone_ctx());
+        }
+
+        let mut shared = self.shared.lock().unwrap();
+        shared.conn = Some(driver.handle());
+        shared.generation += 1;
+        shared.notify_waiters();
+        info!(peer = %self.connector, generation = shared.generation, "connected");
+
+        self.failures = 0;
+        State::Connected(Box::new(driver))
+    }
+
+    /// Schedules the next connection attempt, or gives up if the client is out of retries or
+    /// `err` would recur on every attempt
+    fn on_failure(&mut self, err: ConnectionError) -> Option<State<C>> {
+        let resumable = self.session.as_ref().is_some_and(|ctx| {
+            let ctx = ctx.lock().unwrap();
+            !ctx.has_err() && ctx.is_resumable()
+        });
+        if !resumable {
+            self.session = None;
+        }
+        let out_of_retries = self
+            .cfg
+            .backoff
+            .max_retries
+            .is_some_and(|max_retries| self.failures >= max_retries);
+        // A rejected resumption is followed by a fresh session, while errors such as a failed
+        // authentication are not cured by reconnecting
+        let retryable = err.is_transport() || err == ConnectionError::ResumeRejected;
+        if out_of_retries || !retryable {
+            error!(peer = %self.connector, error = %err, "giving up on connection");
+            self.give_up(err);
+            return None;
+        }
+        self.shared.lock().unwrap().conn = None;
+        let delay = self.cfg.backoff.delay(self.failures);
+        self.failures += 1;
+        warn!(
+            peer = %self.connector,
+            error = %err,
+            delay_ms = delay.as_millis() as u64,
+            "connection lost, reconnecting"
+        );
+        Some(State::Waiting(Box::pin(tokio::time::sleep(delay))))
+    }
+
+    /// Abandons the session and fails every handle with `err`
+    fn give_up(&mut self, err: ConnectionError) {
+        if let Some(ctx) = self.session.take() {
+            ctx.lock().unwrap().abandon();
+        }
+        let mut shared = self.shared.lock().unwrap();
+        shared.conn = None;
+        shared.err = Some(err);
+        shared.notify_waiters();
+    }
+}
+
+impl<C: Connector> Future for Supervisor<C> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        loop {
+            {
+                let mut shared = this.shared.lock().unwrap();
+                if shared.closed {
+                    shared.conn = None;
+                    if let Some(ctx) = this.session.take() {
+                        ConnectionHandle::new(ctx).close();
+                    }
+                    return Poll::Ready(());
+                }
+                shared.supervisor = Some(cx.waker().clone());
+            }
+
+            let transition = match this.state {
+                State::Connecting(ref mut connect) => match Pin::new(connect).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(socket)) => Transition::Connected(socket),
+                    Poll::Ready(Err(err)) => Transition::Failed(err.into()),
+                },
+                State::Connected(ref mut driver) => match Pin::new(&mut **driver).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(()))
+                        if driver.clone_ctx().lock().unwrap().is_closed_locally() =>
+                    {
+                        Transition::Closed
+                    }
+                    Poll::Ready(Ok(())) => Transition::Failed(ConnectionError::Closed),
+                    Poll::Ready(Err(err)) => Transition::Failed(err),
+                },
+                State::Waiting(ref mut delay) => match delay.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(()) => Transition::Retry,
+                },
+            };
+            this.state = match transition {
+                Transition::Retry => State::Connecting(this.connector.connect()),
+                Transition::Connected(socket) => this.on_connected(socket),
+                Transition::Closed => {
+                    info!(peer = %this.connector, "connection closed locally, not reconnecting");
+                    this.give_up(ConnectionError::Closed);
+                    return Poll::Ready(());
+                }
+                Transition::Failed(err) => match this.on_failure(err) {
+                    Some(state) => state,
+                    None => return Poll::Ready(()),
+                },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthConfig;
+    use crate::connection::ResumeConfig;
+    use crate::frames::Frame;
+    use crate::server::{Server, ServerConfig};
+    use bytes::Bytes;
+    use futures::channel::{mpsc, oneshot};
+    use futures::StreamExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn reconnects_after_connection_loss() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind(&addr, ServerConfig::default()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let cfg = ClientConfig {
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                ..BackoffConfig::default()
+            },
+            ..ClientConfig::default()
+        };
+
+        let client = Client::connect(addr, cfg).await.unwrap();
+        // Close the first accepted connection, forcing the client to reconnect
+        let (first, _) = server.next().await.unwrap().unwrap();
+        first.close();
+        let (conn, _incoming) = server.next().await.unwrap().unwrap();
+
+        // Wait for the client to observe the loss and reconnect
+        while client.connection().is_none() || client.generation() != 2 {
+            tokio::task::yield_now().await;
+        }
+        let live = client.connection().unwrap().err().is_none();
+        client.close();
+        assert_eq!(client.generation(), 2);
+        assert!(live);
+        assert_eq!(conn.err(), None);
+    }
+
+    #[tokio::test]
+    async fn stops_after_connection_is_closed_locally() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind(&addr, ServerConfig::default()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let cfg = ClientConfig {
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                ..BackoffConfig::default()
+            },
+            ..ClientConfig::default()
+        };
+
+        let client = Client::connect(addr, cfg).await.unwrap();
+        let (_conn, _incoming) = server.next().await.unwrap().unwrap();
+        client.connection().unwrap().close();
+        while client.health() == Health::Connected {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(client.health(), Health::Failed(ConnectionError::Closed));
+        let reconnected = tokio::time::timeout(Duration::from_millis(100), server.next()).await;
+        assert!(reconnected.is_err());
+        assert_eq!(client.generation(), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_failed_authentication() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind(&addr, ServerConfig::default()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+        let mut cfg = ClientConfig::default();
+        cfg.backoff.initial = Duration::from_millis(10);
+        cfg.connection.auth = Some(AuthConfig::new("secret"));
+
+        let client = Client::spawn(addr, cfg).unwrap();
+        while !matches!(client.health(), Health::Failed(_)) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(client.generation(), 1);
+        assert_eq!(
+            client.health(),
+            Health::Failed(ConnectionError::Authentication)
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_once_every_handle_is_dropped() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind(&addr, ServerConfig::default()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let cfg = ClientConfig {
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                ..BackoffConfig::default()
+            },
+            ..ClientConfig::default()
+        };
+
+        let client = Client::connect(addr, cfg).await.unwrap();
+        let other = client.clone();
+        let (conn, _incoming) = server.next().await.unwrap().unwrap();
+        drop(client);
+        assert!(other.connection().is_some());
+        drop(other);
+        while conn.err().is_none() {
+            tokio::task::yield_now().await;
+        }
+        let reconnected = tokio::time::timeout(Duration::from_millis(100), server.next()).await;
+        assert!(reconnected.is_err());
+    }
+
+    /// Forwards connections accepted on `listener` to `target` until `kill` is triggered
+    async fn proxy(
+        listener: TcpListener,
+        target: SocketAddr,
+        kill: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+    ) {
+        loop {
+            let (mut inbound, _) = listener.accept().await.expect("proxy accept failed");
+            let (killed_tx, killed) = oneshot::channel::<()>();
+            kill.lock().unwrap().push(killed_tx);
+            tokio::spawn(async move {
+                let mut outbound = match TcpStream::connect(target).await {
+                    Ok(outbound) => outbound,
+                    Err(_) => return,
+                };
+                tokio::select! {
+                    _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => (),
+                    _ = killed => (),
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_streams_after_connection_loss() {
+        let connection = ConnectionConfig {
+            resumption: Some(ResumeConfig::default()),
+            ..ConnectionConfig::default()
+        };
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server_cfg = ServerConfig {
+            connection: connection.clone(),
+            ..ServerConfig::default()
+        };
+        let mut server = Server::bind(&addr, server_cfg).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let (accepted_tx, mut accepted) = mpsc::unbounded();
+        tokio::spawn(async move {
+            while let Some(conn) = server.next().await {
+                let _ = accepted_tx.unbounded_send(conn.expect("accept failed"));
+            }
+        });
+
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let kill = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(proxy(listener, server_addr, kill.clone()));
+
+        let cfg = ClientConfig {
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                ..BackoffConfig::default()
+            },
+            connection,
+            ..ClientConfig::default()
+        };
+        let client = Client::connect(proxy_addr, cfg).await.unwrap();
+        let mut local = client.open_stream(StreamId(1), 1024).await.unwrap();
+        local.send_data(Bytes::from("before")).unwrap();
+
+        let (_conn, mut incoming) = accepted.next().await.expect("no connection accepted");
+        let mut remote = incoming.next().await.unwrap().unwrap();
+        match remote.next().await {
+            Some(Ok(Frame::Data(data))) => assert_eq!(data.payload(), Bytes::from("before")),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        // Cut the connection underneath both ends
+        for killed in kill.lock().unwrap().drain(..) {
+            let _ = killed.send(());
+        }
+        local.send_data(Bytes::from("after")).unwrap();
+
+        match remote.next().await {
+            Some(Ok(Frame::Data(data))) => {
+                assert_eq!(data.seq_num, 1);
+                assert_eq!(data.payload(), Bytes::from("after"));
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+        assert_eq!(client.generation(), 2);
+        // The resumed session is not accepted as a new connection
+        assert!(accepted.try_recv().is_err());
+        client.close();
+    }
+}
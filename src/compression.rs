@@ -0,0 +1,275 @@
+//! Compression of the payloads of Data frames.
+//!
+//! Each stream may compress the payloads it sends with a codec of its own. The codec is recorded
+//! in the flags of every Data frame, so the receiving end needs no configuration and payloads which are too
+//! small to be worth compressing are sent as they are.
+//!
+//! Credits account for the uncompressed size of payloads: the sender uses up credit before a
+//! payload is compressed, and the receiver gets back the decompressed payload to return credit
+//! for. A stream's credit capacity thus bounds the memory its received payloads take up,
+//! however well they compress.
+
+use crate::connection::ConnectionError;
+use crate::protocol::frames::{FLAG_CODEC_MASK, FLAG_LZ4, FLAG_ZSTD};
+use bytes::Bytes;
+
+/// Payloads smaller than this are sent uncompressed by default
+pub const DEFAULT_MIN_SIZE: usize = 512;
+/// Maximum size a payload may decompress to, so that a remote cannot make us allocate without
+/// bound
+pub const MAX_DECOMPRESSED_LEN: usize = 64 * 1024 * 1024;
+
+/// Codecs of Data payloads
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    /// Requires the `lz4` feature
+    Lz4,
+    /// Requires the `zstd` feature
+    Zstd,
+}
+
+impl Codec {
+    /// Returns the codec selected by the `FLAG_CODEC_MASK` bits of a Data frame's flags
+    pub fn from_flags(flags: u8) -> Option<Self> {
+        match flags & FLAG_CODEC_MASK {
+            0 => Some(Codec::None),
+            FLAG_LZ4 => Some(Codec::Lz4),
+            FLAG_ZSTD => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Returns the flag a Data frame whose payload is compressed with this codec carries
+    pub fn flag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => FLAG_LZ4,
+            Codec::Zstd => FLAG_ZSTD,
+        }
+    }
+
+    /// Returns true if payloads can be compressed and decompressed with this codec
+    pub fn is_supported(self) -> bool {
+        match self {
+            Codec::None => true,
+            Codec::Lz4 => cfg!(feature = "lz4"),
+            Codec::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+}
+
+/// Compression of the Data payloads sent on a stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    pub codec: Codec,
+    /// Compression level, only used by zstd. 0 selects zstd's default level.
+    pub level: i32,
+    /// Payloads smaller than this are sent uncompressed
+    pub min_size: usize,
+}
+
+impl CompressionConfig {
+    pub fn new(codec: Codec) -> Self {
+        CompressionConfig {
+            codec,
+            level: 0,
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+
+    pub fn lz4() -> Self {
+        CompressionConfig::new(Codec::Lz4)
+    }
+
+    pub fn zstd(level: i32) -> Self {
+        CompressionConfig {
+            level,
+            ..CompressionConfig::new(Codec::Zstd)
+        }
+    }
+}
+
+/// Compresses `payload` as configured by `cfg`, returning the codec it was compressed with.
+///
+/// The payload is returned as is if it is smaller than the configured minimum, or if compressing
+/// it did not make it any smaller.
+pub(crate) fn compress(cfg: &CompressionConfig, payload: Bytes) -> (Codec, Bytes) {
+    if payload.len() < cfg.min_size {
+        return (Codec::None, payload);
+    }
+    let compressed: Option<Vec<u8>> = match cfg.codec {
+        Codec::None => None,
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => Some(lz4_flex::compress_prepend_size(&payload)),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::bulk::compress(&payload, cfg.level).ok(),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    };
+    match compressed {
+        Some(compressed) if compressed.len() < payload.len() => (cfg.codec, compressed.into()),
+        _ => (Codec::None, payload),
+    }
+}
+
+/// Returns the size `payload` decompresses to, as recorded by the codec it was compressed with.
+///
+/// Fails if the size is unknown or exceeds `MAX_DECOMPRESSED_LEN`.
+pub(crate) fn decompressed_len(codec: Codec, payload: &[u8]) -> Result<usize, ConnectionError> {
+    let len = match codec {
+        Codec::None => Some(payload.len()),
+        // Prepended by `compress_prepend_size`
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 if payload.len() >= 4 => {
+            Some(u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize)
+        }
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => match zstd::zstd_safe::get_frame_content_size(payload) {
+            Ok(Some(len)) if len <= MAX_DECOMPRESSED_LEN as u64 => Some(len as usize),
+            _ => None,
+        },
+        _ => None,
+    };
+    match len {
+        Some(len) if codec == Codec::None || len <= MAX_DECOMPRESSED_LEN => Ok(len),
+        _ => Err(ConnectionError::Compression),
+    }
+}
+
+/// Decompresses a payload which was compressed with `codec`
+pub(crate) fn decompress(codec: Codec, payload: Bytes) -> Result<Bytes, ConnectionError> {
+    // Rejects payloads which would decompress to more than `MAX_DECOMPRESSED_LEN`
+    decompressed_len(codec, &payload)?;
+    match codec {
+        Codec::None => Ok(payload),
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => lz4_flex::decompress_size_prepended(&payload)
+            .map(Bytes::from)
+            .map_err(|_| ConnectionError::Compression),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::bulk::decompress(&payload, decompressed_len(codec, &payload)?)
+            .map(Bytes::from)
+            .map_err(|_| ConnectionError::Compression),
+        #[allow(unreachable_patterns)]
+        _ => Err(ConnectionError::Compression),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(cfg: CompressionConfig) -> Codec {
+        let payload = Bytes::from("spaniel ".repeat(256));
+        let (codec, compressed) = compress(&cfg, payload.clone());
+        if codec != Codec::None {
+            assert!(compressed.len() < payload.len());
+        }
+        assert_eq!(decompress(codec, compressed), Ok(payload));
+        codec
+    }
+
+    #[test]
+    fn compresses_payloads_above_the_threshold() {
+        assert_eq!(round_trip(CompressionConfig::new(Codec::None)), Codec::None);
+        #[cfg(feature = "lz4")]
+        assert_eq!(round_trip(CompressionConfig::lz4()), Codec::Lz4);
+        #[cfg(feature = "zstd")]
+        assert_eq!(round_trip(CompressionConfig::zstd(3)), Codec::Zstd);
+
+        let small = Bytes::from("spaniel");
+        let (codec, payload) = compress(&CompressionConfig::zstd(3), small.clone());
+        assert_eq!((codec, payload), (Codec::None, small));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn delivers_decompressed_payloads() {
+        use crate::connection::ConnectionConfig;
+        use crate::protocol::frames::{Frame, FrameType};
+        use crate::stream::StreamId;
+        use crate::transport::memory;
+        use futures::StreamExt;
+
+        let (handle, _, mut incoming) =
+            memory::connect(ConnectionConfig::default(), ConnectionConfig::default());
+
+        let mut stream = handle.open_stream(StreamId(1), 1 << 16).await.unwrap();
+        stream
+            .set_compression(Some(CompressionConfig::lz4()))
+            .unwrap();
+        let payload = Bytes::from("spaniel ".repeat(256));
+        stream.send_data(payload.clone()).unwrap();
+
+        let mut received = incoming.next().await.unwrap().unwrap();
+        match received.next().await {
+            Some(Ok(Frame::Data(data))) => {
+                assert_eq!(data.codec, Codec::None);
+                assert_eq!(data.payload(), payload);
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+        let metrics = handle.metrics();
+        assert!(metrics.outbound.get(FrameType::Data).bytes < payload.len() as u64);
+        // Like credits, the stream's counters account for the uncompressed payload
+        assert_eq!(metrics.streams[0].outbound.bytes, payload.len() as u64);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn charges_credit_for_uncompressed_payloads() {
+        use crate::connection::ConnectionConfig;
+        use crate::flow_control::FlowControlStrategy;
+        use crate::protocol::frames::FrameType;
+        use crate::stream::StreamId;
+        use crate::transport::memory;
+        use futures::StreamExt;
+
+        let cfg = ConnectionConfig {
+            flow_control_strategy: FlowControlStrategy::credit_based(),
+            ..ConnectionConfig::default()
+        };
+        let (handle, _, mut incoming) = memory::connect(cfg.clone(), cfg);
+
+        let mut stream = handle.open_stream(StreamId(1), 4096).await.unwrap();
+        stream
+            .set_compression(Some(CompressionConfig::lz4()))
+            .unwrap();
+        let payload = Bytes::from("spaniel ".repeat(256));
+        stream.send_data(payload.clone()).unwrap();
+        assert_eq!(stream.metrics().unwrap().credit_available, 2048);
+
+        let mut received = incoming.next().await.unwrap().unwrap();
+        received.next().await.unwrap().unwrap();
+        assert!(handle.metrics().outbound.get(FrameType::Data).bytes < payload.len() as u64);
+        assert_eq!(received.metrics().unwrap().credit_available, 2048);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn sends_uncompressed_payloads_on_local_connections() {
+        use crate::connection::ConnectionConfig;
+        use crate::local::pair;
+        use crate::protocol::frames::Frame;
+        use crate::stream::StreamId;
+        use futures::StreamExt;
+
+        let (driver, (client, _), (_, mut incoming)) = pair(ConnectionConfig::default());
+        tokio::spawn(async { driver.await.unwrap() });
+
+        let mut stream = client.open_stream(StreamId(1), 1 << 16).await.unwrap();
+        stream
+            .set_compression(Some(CompressionConfig::lz4()))
+            .unwrap();
+        let payload = Bytes::from("spaniel ".repeat(256));
+        stream.send_data(payload.clone()).unwrap();
+
+        let mut received = incoming.next().await.unwrap().unwrap();
+        match received.next().await {
+            // Handed over without being compressed, or even copied
+            Some(Ok(Frame::Data(data))) => assert_eq!(data.payload().as_ptr(), payload.as_ptr()),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+}
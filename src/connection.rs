@@ -1,37 +1,165 @@
-use flow_control::{Credits, FlowControlStrategy};
-use futures::sync::mpsc;
-use futures::sync::mpsc::Receiver;
-use futures::sync::mpsc::Sender;
-use futures::task;
-use futures::task::Task;
-use futures::Async;
-use futures::Future;
-use futures::Poll;
-use protocol::codec::reader::FrameReader;
-use protocol::codec::writer::FrameWriter;
-use protocol::frames;
-use protocol::frames::Frame;
-use protocol::frames::FramingError;
+use crate::auth::{AuthConfig, Authenticator};
+use crate::compression;
+use crate::extension;
+use crate::extension::{Delivery, ExtensionRegistry, ExtensionSender};
+use crate::flow_control::FlowControlStrategy;
+use crate::fragment::FragmentConfig;
+use crate::metrics::{ConnectionMetrics, ConnectionStats, Registry, StreamMetrics};
+use crate::protocol::codec::reader::FrameReader;
+use crate::protocol::codec::writer::FrameWriter;
+use crate::protocol::codec::writer::WriteError;
+use crate::protocol::frames;
+use crate::protocol::frames::Frame;
+use crate::protocol::frames::FramingError;
+use crate::protocol::frames::Handshake;
+use crate::stream::seq_before;
+use crate::stream::IncomingStreams;
+use crate::stream::SharedStreamState;
+use crate::stream::StreamId;
+use crate::stream::StreamRef;
+use crate::stream::StreamRequester;
+use crate::stream::StreamState;
+use futures::channel::mpsc;
+use futures::channel::mpsc::Receiver;
+use futures::channel::mpsc::Sender;
+use futures::Stream;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
-use stream::IncomingStreams;
-use stream::StreamId;
-use stream::StreamState;
-use tokio_io::AsyncRead;
-use tokio_io::AsyncWrite;
+use std::sync::MutexGuard;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tracing::Span;
 
-type ConnectionId = u32;
+pub type ConnectionId = u32;
 
-#[derive(Debug)]
+static NEXT_CONNECTION_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Number of frames read in one poll before yielding, so that a busy remote cannot keep the
+/// reading task from doing anything else
+const READ_BUDGET: usize = 64;
+
+/// Returns a `ConnectionId` which is unique within this process
+pub fn next_connection_id() -> ConnectionId {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the handshake flags announcing that `cfg` requires authentication or checksums
+fn config_flags(cfg: &ConnectionConfig) -> u8 {
+    let mut flags = 0;
+    if cfg.auth.is_some() {
+        flags |= frames::HANDSHAKE_AUTH;
+    }
+    if cfg.checksums {
+        flags |= frames::HANDSHAKE_CHECKSUM;
+    }
+    flags
+}
+
+/// Returns the maximum frame length advertised in the handshake for `cfg`
+fn advertised_max_frame_len(cfg: &ConnectionConfig) -> u32 {
+    cfg.max_frame_len.min(u32::MAX as usize) as u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ConnectionError {
     InvalidStreamId,
     UnknownFrame,
     General,
     InsufficientCredit, // TODO this should really be in its own category, maybe in some nested ConnError
+    /// The remote end closed the connection
+    Closed,
+    /// The underlying transport failed
+    Io(io::ErrorKind),
+    /// The stream has too many unacknowledged frames to send another one
+    ReplayBufferFull,
+    /// The remote did not start the connection with a valid handshake
+    Handshake,
+    /// The remote could not resume the session
+    ResumeRejected,
+    /// The remote did not prove knowledge of the pre-shared key
+    Authentication,
+    /// A Data frame skipped ahead of its stream's sequence
+    SequenceGap {
+        expected: u32,
+        received: u32,
+    },
+    /// A blocking operation did not complete within its timeout
+    Timeout,
+    /// A frame did not match its checksum
+    ChecksumMismatch,
+    /// A payload was compressed with a codec which is not supported, or could not be decompressed
+    Compression,
+    /// An extension frame type lies outside the reserved range, or was registered twice
+    InvalidFrameType,
+    /// A message exceeds the length its receiver reassembles
+    MessageTooLarge,
+    /// A frame is longer than the remote reads
+    FrameTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// The connection has no room to queue another frame until queued frames are written
+    QueueFull,
+    /// Another handle of the stream is sending a message, between whose fragments no other Data
+    /// frame may be sent
+    MessageInProgress,
+}
+
+impl ConnectionError {
+    /// Returns true if the error describes the loss of the transport rather than a failure of the
+    /// session running over it
+    pub fn is_transport(&self) -> bool {
+        matches!(*self, ConnectionError::Closed | ConnectionError::Io(_))
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectionError::InvalidStreamId => write!(f, "invalid stream id"),
+            ConnectionError::UnknownFrame => write!(f, "unknown frame"),
+            ConnectionError::General => write!(f, "connection error"),
+            ConnectionError::InsufficientCredit => write!(f, "insufficient credit"),
+            ConnectionError::Closed => write!(f, "connection closed"),
+            ConnectionError::Io(kind) => write!(f, "I/O error: {:?}", kind),
+            ConnectionError::ReplayBufferFull => write!(f, "replay buffer full"),
+            ConnectionError::Handshake => write!(f, "invalid handshake"),
+            ConnectionError::ResumeRejected => write!(f, "session could not be resumed"),
+            ConnectionError::Authentication => write!(f, "authentication failed"),
+            ConnectionError::SequenceGap { expected, received } => write!(
+                f,
+                "expected frame {} but received frame {}",
+                expected, received
+            ),
+            ConnectionError::Timeout => write!(f, "timed out"),
+            ConnectionError::ChecksumMismatch => write!(f, "frame checksum mismatch"),
+            ConnectionError::Compression => {
+                write!(f, "unsupported or corrupted compressed payload")
+            }
+            ConnectionError::InvalidFrameType => write!(f, "invalid extension frame type"),
+            ConnectionError::MessageTooLarge => write!(f, "message too large"),
+            ConnectionError::FrameTooLarge { len, max } => write!(
+                f,
+                "frame of {} bytes exceeds maximum frame length of {} bytes",
+                len, max
+            ),
+            ConnectionError::QueueFull => write!(f, "outbound queue full"),
+            ConnectionError::MessageInProgress => write!(f, "message in progress"),
+        }
+    }
 }
 
+impl std::error::Error for ConnectionError {}
+
 impl From<()> for ConnectionError {
     fn from(_: ()) -> Self {
         ConnectionError::General
@@ -39,19 +167,178 @@ impl From<()> for ConnectionError {
 }
 
 impl From<FramingError> for ConnectionError {
-    fn from(_err: FramingError) -> Self {
-        ConnectionError::General
+    fn from(err: FramingError) -> Self {
+        match err {
+            FramingError::Io(err) => ConnectionError::Io(err.kind()),
+            FramingError::ChecksumMismatch => ConnectionError::ChecksumMismatch,
+            FramingError::UnsupportedFrameType => ConnectionError::UnknownFrame,
+            _ => ConnectionError::General,
+        }
     }
 }
 
-#[derive(Debug)]
+impl<T> From<mpsc::TrySendError<T>> for ConnectionError {
+    fn from(err: mpsc::TrySendError<T>) -> Self {
+        if err.is_disconnected() {
+            ConnectionError::Closed
+        } else {
+            ConnectionError::QueueFull
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(err: io::Error) -> Self {
+        ConnectionError::Io(err.kind())
+    }
+}
+
+impl From<WriteError> for ConnectionError {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::Io => ConnectionError::Io(io::ErrorKind::Other),
+            WriteError::FrameTooLarge { len, max } => ConnectionError::FrameTooLarge { len, max },
+            _ => ConnectionError::General,
+        }
+    }
+}
+
+/// Resumption of a session's streams after its transport is lost.
+///
+/// Each outbound Data frame is kept until the remote acknowledges it. When a new transport is
+/// bound to the session, both ends exchange the sequence number of the next frame they expect on
+/// every stream and replay whatever the other end missed. Frames may thus be delivered more than
+/// once, but none are lost while the session can be resumed.
+#[derive(Debug, Clone)]
+pub struct ResumeConfig {
+    /// Maximum number of unacknowledged Data frames kept per stream
+    pub replay_capacity: usize,
+    /// Number of Data frames received on a stream before they are acknowledged
+    pub ack_interval: u32,
+    /// How long the accepting end keeps a disconnected session before failing its streams
+    pub timeout: Duration,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        ResumeConfig {
+            replay_capacity: 1024,
+            ack_interval: 32,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Handling of an inbound Data frame which skips ahead of its stream's sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GapPolicy {
+    /// Fail the stream with `ConnectionError::SequenceGap` once the frames received before the
+    /// gap have been consumed
+    Error,
+    /// Fail the stream right away, discarding the frames which have not been consumed yet
+    Reset,
+    /// Deliver the frame and continue from its sequence number, only counting the gap
+    Report,
+}
+
+/// Handling of an inbound Data frame whose sequence number has already been received
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Count the frame and drop it, so that frames replayed after resuming are delivered once
+    Drop,
+    /// Count the frame and deliver it anyway
+    Deliver,
+}
+
+#[derive(Debug, Clone)]
 pub struct ConnectionConfig {
-    flow_control_strategy: FlowControlStrategy,
+    /// Limits the Data sent on each stream by the credit granted by its receiver. Credit
+    /// accounts for the uncompressed size of payloads, on both ends.
+    pub flow_control_strategy: FlowControlStrategy,
+    /// Enables resuming streams after reconnecting, if the remote supports it too
+    pub resumption: Option<ResumeConfig>,
+    pub on_gap: GapPolicy,
+    pub on_duplicate: DuplicatePolicy,
+    /// Requires the remote to prove knowledge of a pre-shared key before any streams are opened
+    pub auth: Option<AuthConfig>,
+    /// Appends a CRC32C checksum to every frame following the handshakes. Checksums are used in
+    /// both directions if either end enables them.
+    pub checksums: bool,
+    /// Extension frame types understood by this end
+    pub extensions: ExtensionRegistry,
+    /// Splitting of messages sent with `StreamRef::send_message`, and reassembly of those received
+    pub fragments: FragmentConfig,
+    /// Longest frame this end reads, including its length prefix and checksum. Advertised in the
+    /// handshake, so that the remote sends no longer frames; must be at least
+    /// `MIN_MAX_FRAME_LEN`.
+    pub max_frame_len: usize,
 }
 impl Default for ConnectionConfig {
     fn default() -> Self {
         ConnectionConfig {
             flow_control_strategy: FlowControlStrategy::Disabled,
+            resumption: None,
+            on_gap: GapPolicy::Error,
+            on_duplicate: DuplicatePolicy::Drop,
+            auth: None,
+            checksums: false,
+            extensions: ExtensionRegistry::new(),
+            fragments: FragmentConfig::default(),
+            max_frame_len: frames::DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// How frames are written for the remote, which limits their length
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Framing {
+    /// Longest frame the remote reads, including its length prefix and checksum
+    pub max_frame_len: usize,
+    /// Whether each frame ends with a checksum trailer
+    pub checksums: bool,
+}
+
+impl Framing {
+    /// Returns the length of `frame` once written, including its length prefix and checksum
+    pub fn frame_len(&self, frame: &Frame) -> usize {
+        frames::FRAME_HEAD_LEN as usize + frame.encoded_len() + self.checksum_len()
+    }
+
+    /// Returns the length of a Data frame carrying `payload_len` bytes once written
+    pub fn data_frame_len(&self, payload_len: usize) -> usize {
+        payload_len + frames::DATA_OVERHEAD + self.checksum_len()
+    }
+
+    /// Fails with `FrameTooLarge` if a frame of `len` bytes is longer than the remote reads
+    pub fn check_len(&self, len: usize) -> Result<(), ConnectionError> {
+        if len > self.max_frame_len {
+            warn!(
+                len,
+                max = self.max_frame_len,
+                "frame exceeds maximum length"
+            );
+            return Err(ConnectionError::FrameTooLarge {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+        Ok(())
+    }
+
+    fn checksum_len(&self) -> usize {
+        if self.checksums {
+            frames::CHECKSUM_LEN
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            max_frame_len: frames::DEFAULT_MAX_FRAME_LEN,
+            checksums: false,
         }
     }
 }
@@ -64,8 +351,11 @@ pub struct ConnectionContext {
     id: ConnectionId,
     /// Stores the current connection error, if there is one
     err: Option<ConnectionError>,
-    /// Stream management store
-    pub(crate) stream_states: HashMap<StreamId, StreamState>,
+    /// Set once the connection has been closed through one of its handles
+    closed_locally: bool,
+    /// Stream management store. Each stream is locked on its own, so that its handles never have
+    /// to lock the whole connection; the connection is always locked first when both are.
+    pub(crate) stream_states: HashMap<StreamId, SharedStreamState>,
     /// Channels for forwarding decoded frames to application
     pub(crate) stream_senders: HashMap<StreamId, Sender<frames::Frame>>,
     /// Channel for submitting frames for writing over the network
@@ -73,10 +363,29 @@ pub struct ConnectionContext {
     outbound_listener: Receiver<Frame>,
     new_streams: VecDeque<frames::StreamRequest>,
 
-    /// Task which drives the connection's I/O progress
-    pub(crate) conn_task: Option<Task>,
+    /// Task which writes the connection's outbound frames
+    pub(crate) conn_task: Option<Waker>,
+    /// Task which reads the connection's inbound frames
+    read_task: Option<Waker>,
     /// Task which awaits new streams
-    pub(crate) new_stream_task: Option<Task>,
+    pub(crate) new_stream_task: Option<Waker>,
+    /// Span under which this connection's events are recorded
+    span: Span,
+    /// Connection-wide counters, shared with the connection's reader and writer
+    stats: Arc<ConnectionStats>,
+    /// Identifies the session across the transports bound to this context
+    session_id: u64,
+    /// Whether both ends agreed to resume the session after losing its transport
+    resumable: bool,
+    /// Failure of the lost transport, while the session waits to be resumed
+    link_err: Option<ConnectionError>,
+    /// Number of transports bound to this context so far
+    generation: u64,
+    /// Frames written ahead of the outbound channel, such as those replayed after resuming
+    control: VecDeque<Frame>,
+    /// How frames are written for the remote, as negotiated in the handshakes, or `None` if they
+    /// are handed to it without being encoded
+    framing: Option<Framing>,
 }
 
 /// Frame-handling helper
@@ -88,34 +397,346 @@ enum AsyncHandle<T> {
 // impl ConnectionContext
 impl ConnectionContext {
     pub fn new(id: ConnectionId) -> Self {
+        ConnectionContext::with_config(id, ConnectionConfig::default())
+    }
+
+    pub fn with_config(id: ConnectionId, cfg: ConnectionConfig) -> Self {
         let (tx, rx) = mpsc::channel(1024);
         ConnectionContext {
-            cfg: ConnectionConfig::default(), // TODO custommize
+            framing: Some(Framing {
+                max_frame_len: cfg.max_frame_len,
+                checksums: cfg.checksums,
+            }),
+            cfg,
             id,
             err: None,
+            closed_locally: false,
             conn_task: None,
+            read_task: None,
             new_stream_task: None,
             stream_states: HashMap::new(),
             stream_senders: HashMap::new(),
             outbound: tx,
             outbound_listener: rx,
             new_streams: VecDeque::new(),
+            span: info_span!("connection", conn_id = id),
+            stats: Arc::new(ConnectionStats::default()),
+            session_id: rand::random(),
+            resumable: false,
+            link_err: None,
+            generation: 1,
+            control: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.cfg
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Returns true if the session survives the loss of its transport
+    pub fn is_resumable(&self) -> bool {
+        self.resumable
+    }
+
+    /// Returns true while the session waits for a new transport to be bound to it
+    pub fn is_disconnected(&self) -> bool {
+        self.link_err.is_some()
+    }
+
+    /// Returns the number of transports bound to this context so far
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the maximum number of unacknowledged frames per stream, if frames are kept for
+    /// replay at all
+    fn replay_capacity(&self) -> Option<usize> {
+        self.cfg.resumption.as_ref().map(|cfg| cfg.replay_capacity)
+    }
+
+    /// Completes the handshake of the transport currently bound to this context
+    fn on_established(&mut self, resumed: bool, remote_resumable: bool) {
+        if resumed {
+            self.span
+                .in_scope(|| info!(generation = self.generation, "session resumed"));
+            self.on_resumed();
+            return;
+        }
+        self.resumable = self.cfg.resumption.is_some() && remote_resumable;
+        if !self.resumable {
+            // Nothing will ever be replayed
+            self.cfg.resumption = None;
+            for state in self.stream_states.values() {
+                let mut state = state.lock().unwrap();
+                state.replay_capacity = None;
+                state.replay.clear();
+            }
+        }
+    }
+
+    /// Limits the frames sent from now on, including those of existing streams, to what the
+    /// remote reads, or lifts the limit if frames are not encoded at all
+    pub(crate) fn set_framing(&mut self, framing: Option<Framing>) {
+        self.framing = framing;
+        for state in self.stream_states.values() {
+            state.lock().unwrap().framing = framing;
+        }
+    }
+
+    /// Re-announces this end's streams to the remote, and asks it to replay what was lost
+    fn on_resumed(&mut self) {
+        let mut stream_ids: Vec<&StreamId> = self.stream_states.keys().collect();
+        stream_ids.sort();
+        for stream_id in stream_ids {
+            let state = self.stream_states[stream_id].lock().unwrap();
+            if state.local {
+                let request = frames::StreamRequest::new(*stream_id, state.credits.capacity());
+                self.control.push_back(Frame::StreamRequest(request));
+            }
+            self.control.push_back(Frame::Resume(frames::Resume {
+                stream_id: *stream_id,
+                next_seq: state.next_recv_seq,
+            }));
+        }
+    }
+
+    /// Marks the transport as lost, keeping the streams around until the session is resumed.
+    ///
+    /// Data sent on the streams in the meantime is only kept for replay.
+    fn disconnect(&mut self, err: ConnectionError) {
+        for state in self.stream_states.values() {
+            state.lock().unwrap().awaiting_resume = true;
+        }
+        self.link_err = Some(err);
+        // Whichever direction of the transport is still running has to stop too
+        self.notify_conn_task();
+        self.notify_read_task();
+    }
+
+    /// Records `err` as the failure of the transport bound as `generation`, waking all tasks
+    /// waiting on the connection.
+    ///
+    /// Resumable sessions only lose their transport, keeping their streams until it is replaced.
+    fn fail_transport(&mut self, generation: u64, err: ConnectionError) -> ConnectionError {
+        if self.generation != generation {
+            // Another transport has taken over the session
+            return err;
+        }
+        if self.resumable && err.is_transport() {
+            warn!(error = %err, "connection lost, awaiting resumption");
+            self.disconnect(err.clone());
+            return err;
+        }
+        match err {
+            ConnectionError::Closed => info!("connection closed by remote"),
+            _ => error!(error = %err, "closing connection"),
+        }
+        self.set_err(err.clone());
+        err
+    }
+
+    /// Prepares the context for a new transport, returning its generation.
+    ///
+    /// Data frames still waiting in the outbound channel for streams which await resumption are
+    /// dropped, since they are replayed once the remote tells us where to resume.
+    fn rebind(&mut self) -> u64 {
+        let mut kept = Vec::new();
+        while let Ok(frame) = self.outbound_listener.try_recv() {
+            if let Frame::Data(ref data) = frame {
+                let awaiting = self
+                    .stream_states
+                    .get(&data.stream_id)
+                    .is_none_or(|state| state.lock().unwrap().awaiting_resume);
+                if awaiting {
+                    continue;
+                }
+            }
+            kept.push(frame);
+        }
+        for frame in kept {
+            let _res = self.outbound.try_send(frame);
+        }
+        self.control.clear();
+        self.link_err = None;
+        self.generation += 1;
+        self.generation
+    }
+
+    /// Gives up on resuming the session, failing its streams with the transport's error
+    pub(crate) fn abandon(&mut self) {
+        if self.has_err() {
+            return;
+        }
+        let err = self.link_err.take().unwrap_or(ConnectionError::Closed);
+        self.span
+            .in_scope(|| warn!(error = %err, "session could not be resumed"));
+        self.set_err(err);
+    }
+
+    /// Returns a snapshot of the connection's metrics, including those of all its streams
+    pub fn metrics(&self) -> ConnectionMetrics {
+        let mut streams: Vec<StreamMetrics> = self
+            .stream_states
+            .iter()
+            .map(|(id, state)| state.lock().unwrap().metrics(*id))
+            .collect();
+        streams.sort_by_key(|m| m.stream_id);
+        self.stats.snapshot(self.id, streams)
+    }
+
+    /// Returns the number of streams which have not failed and still have a handle on this end
+    pub fn open_streams(&self) -> usize {
+        self.stream_states
+            .values()
+            .filter(|state| Arc::strong_count(state) > 1 && state.lock().unwrap().err.is_none())
+            .count()
+    }
+
+    /// Returns a snapshot of the stream's metrics, or `None` if the stream is unknown
+    pub fn stream_metrics(&self, stream_id: &StreamId) -> Option<StreamMetrics> {
+        self.stream_states
+            .get(stream_id)
+            .map(|state| state.lock().unwrap().metrics(*stream_id))
+    }
+
+    /// Returns the span under which this connection's events are recorded
+    pub fn span(&self) -> Span {
+        self.span.clone()
+    }
+
+    /// Returns the span of the stream, falling back to the connection's span for unknown streams
+    pub fn stream_span(&self, stream_id: &StreamId) -> Span {
+        match self.stream_states.get(stream_id) {
+            Some(state) => state.lock().unwrap().span.clone(),
+            None => self.span(),
         }
     }
 
-    pub fn get_stream_state_mut(&mut self, stream_id: &StreamId) -> Option<&mut StreamState> {
-        self.stream_states.get_mut(stream_id)
+    /// Creates the state for a new stream whose events are recorded under this connection's span
+    pub(crate) fn new_stream_state(
+        &self,
+        stream_id: StreamId,
+        credit_capacity: u32,
+        data: Receiver<Frame>,
+    ) -> StreamState {
+        let span = debug_span!(parent: &self.span, "stream", stream_id = stream_id.0);
+        let mut state = StreamState::new(credit_capacity, data, span);
+        if let FlowControlStrategy::CreditBased(ref ratio) = self.cfg.flow_control_strategy {
+            state.flow_controlled = true;
+            state.credit_update_ratio = *ratio;
+        }
+        state.replay_capacity = self.replay_capacity();
+        state.fragments = self.cfg.fragments.clone();
+        state.framing = self.framing;
+        state
     }
 
-    /// Delegates work according to frame type
-    fn handle_frame(&mut self, f: Frame) -> Result<AsyncHandle<Frame>, ConnectionError> {
+    pub fn get_stream_state_mut(
+        &mut self,
+        stream_id: &StreamId,
+    ) -> Option<MutexGuard<'_, StreamState>> {
+        self.stream_states
+            .get(stream_id)
+            .map(|state| state.lock().unwrap())
+    }
+
+    /// Returns a handle for the stream, or `None` if the stream is unknown
+    pub(crate) fn stream_ref(
+        &self,
+        ctx: &SharedConnectionContext,
+        stream_id: StreamId,
+    ) -> Option<StreamRef> {
+        self.stream_states.get(&stream_id).map(|state| {
+            StreamRef::new(
+                stream_id,
+                ctx.clone(),
+                state.clone(),
+                self.outbound.clone(),
+                self.stats.clone(),
+            )
+        })
+    }
+
+    /// Delegates work according to frame type.
+    ///
+    /// Frames which cannot be handed to their stream yet are returned, and the task is woken up
+    /// once the stream has room for them.
+    fn handle_frame(
+        &mut self,
+        f: Frame,
+        cx: &mut Context<'_>,
+    ) -> Result<AsyncHandle<Frame>, ConnectionError> {
         match f {
             Frame::StreamRequest(frame) => self.on_stream_request(frame),
             Frame::CreditUpdate(frame) => self.on_credit_update(frame),
-            Frame::Data(frame) => self.on_data(frame),
+            Frame::Data(frame) => self.on_data(frame, cx),
             Frame::Ping(_, _) => Ok(AsyncHandle::Ready),
             Frame::Pong(_, _) => Ok(AsyncHandle::Ready),
-            Frame::Unknown => Err(ConnectionError::UnknownFrame),
+            Frame::Resume(frame) => self.on_resume(frame),
+            Frame::Ack(frame) => self.on_ack(frame),
+            // Only valid as the first frame, which is consumed by the driver
+            Frame::Handshake(_) => Err(ConnectionError::Handshake),
+            // Only valid while the connection is being established
+            Frame::Auth(_) => Err(ConnectionError::Authentication),
+            Frame::Extension(frame) => self.on_extension(frame, cx),
+            // Only decoded if the remote flagged it as ignorable
+            Frame::Unknown => Ok(AsyncHandle::Ready),
+        }
+    }
+
+    /// Hands an extension frame to the handler or stream its type is registered for
+    fn on_extension(
+        &mut self,
+        ext: frames::Extension,
+        cx: &mut Context<'_>,
+    ) -> Result<AsyncHandle<Frame>, ConnectionError> {
+        let delivery = match self.cfg.extensions.get(ext.frame_type) {
+            Some(delivery) => delivery.clone(),
+            None if ext.has(frames::FLAG_IGNORABLE) => {
+                debug!(
+                    frame_type = ext.frame_type,
+                    "skipped unknown extension frame"
+                );
+                return Ok(AsyncHandle::Ready);
+            }
+            None => {
+                warn!(frame_type = ext.frame_type, "unknown extension frame");
+                return Err(ConnectionError::UnknownFrame);
+            }
+        };
+        match delivery {
+            Delivery::Handler(handler) => {
+                let mut sender = ExtensionSender::new(self.outbound.clone(), self.framing);
+                handler(ext, &mut sender);
+                Ok(AsyncHandle::Ready)
+            }
+            Delivery::Stream => {
+                let stream_id = ext.stream_id;
+                if !self.stream_states.contains_key(&stream_id) {
+                    return Err(ConnectionError::InvalidStreamId);
+                }
+                let sender = match self.stream_senders.get_mut(&stream_id) {
+                    Some(sender) => sender,
+                    // Nobody is going to consume the frames of a failed stream
+                    None => return Ok(AsyncHandle::Ready),
+                };
+                if sender
+                    .poll_ready(cx)
+                    .map_err(|_| ConnectionError::General)?
+                    .is_pending()
+                {
+                    self.stats.record_head_of_line_stall();
+                    return Ok(AsyncHandle::NotReady(Frame::Extension(ext)));
+                }
+                if let Err(err) = sender.try_send(Frame::Extension(ext)) {
+                    return Ok(AsyncHandle::NotReady(err.into_inner()));
+                }
+                Ok(AsyncHandle::Ready)
+            }
         }
     }
 
@@ -124,89 +745,301 @@ impl ConnectionContext {
         request: frames::StreamRequest,
     ) -> Result<AsyncHandle<Frame>, ConnectionError> {
         let stream_id = request.stream_id;
-        println!("on_stream_request {:?}", stream_id);
-        match self.stream_states.get_mut(&stream_id) {
-            Some(_) => return Err(ConnectionError::InvalidStreamId),
-            None => (),
+        // After a session has been resumed, the remote re-announces its streams in case their
+        // requests were lost with the previous transport
+        let reannounced = self.resumable && self.generation > 1;
+        if self.stream_states.contains_key(&stream_id) {
+            if !reannounced {
+                return Err(ConnectionError::InvalidStreamId);
+            }
+            self.send_resume(stream_id);
+            return Ok(AsyncHandle::Ready);
         }
         let (tx, rx) = mpsc::channel(1);
-        let state = StreamState {
-            credits: Credits::new(request.credit_capacity),
-            data_buffer: VecDeque::new(),
-            data: rx,
-            send_task: None,
-            recv_task: None,
-        };
-        self.stream_states.insert(stream_id, state);
+        let state = self.new_stream_state(stream_id, request.credit_capacity, rx);
+        state.span.in_scope(|| {
+            debug!(
+                credit_capacity = request.credit_capacity,
+                "stream opened by remote"
+            )
+        });
+        self.stream_states
+            .insert(stream_id, Arc::new(Mutex::new(state)));
         self.stream_senders.insert(stream_id, tx);
 
         self.new_streams.push_back(request);
         self.notify_new_stream_task();
+        if reannounced {
+            self.send_resume(stream_id);
+        }
+        Ok(AsyncHandle::Ready)
+    }
+
+    /// Tells the remote where to resume sending on the stream
+    fn send_resume(&mut self, stream_id: StreamId) {
+        if let Some(state) = self.stream_states.get(&stream_id) {
+            self.control.push_back(Frame::Resume(frames::Resume {
+                stream_id,
+                next_seq: state.lock().unwrap().next_recv_seq,
+            }));
+            self.notify_conn_task();
+        }
+    }
+
+    fn on_resume(&mut self, resume: frames::Resume) -> Result<AsyncHandle<Frame>, ConnectionError> {
+        let mut stream_state = match self.stream_states.get(&resume.stream_id) {
+            None => return Err(ConnectionError::InvalidStreamId),
+            Some(state) => state.lock().unwrap(),
+        };
+        stream_state.acknowledge(resume.next_seq);
+        if stream_state.awaiting_resume {
+            stream_state.awaiting_resume = false;
+            stream_state.span.in_scope(|| {
+                debug!(
+                    next_seq = resume.next_seq,
+                    replayed = stream_state.replay.len(),
+                    "resuming stream"
+                )
+            });
+            self.control
+                .extend(stream_state.replay.iter().cloned().map(Frame::Data));
+        }
+        stream_state.notify_data_tx();
+        Ok(AsyncHandle::Ready)
+    }
+
+    fn on_ack(&mut self, ack: frames::Ack) -> Result<AsyncHandle<Frame>, ConnectionError> {
+        let mut stream_state = match self.stream_states.get(&ack.stream_id) {
+            None => return Err(ConnectionError::InvalidStreamId),
+            Some(state) => state.lock().unwrap(),
+        };
+        stream_state.acknowledge(ack.next_seq);
+        // Wake up a sender waiting for room in the replay buffer
+        stream_state.notify_data_tx();
         Ok(AsyncHandle::Ready)
     }
 
     fn on_credit_update(
         &mut self,
-        _request: frames::CreditUpdate,
+        update: frames::CreditUpdate,
     ) -> Result<AsyncHandle<Frame>, ConnectionError> {
-        // TODO
+        let mut stream_state = match self.stream_states.get(&update.stream_id) {
+            None => return Err(ConnectionError::InvalidStreamId),
+            Some(state) => state.lock().unwrap(),
+        };
+        let available = stream_state.credits.add_credit(update.credit);
+        stream_state.span.in_scope(|| {
+            trace!(
+                granted = update.credit,
+                available,
+                "credit granted by remote"
+            )
+        });
+        // Wake up the sender waiting in `poll_stream_capacity`
+        stream_state.notify_data_tx();
         Ok(AsyncHandle::Ready)
     }
 
-    fn on_data(&mut self, data: frames::Data) -> Result<AsyncHandle<Frame>, ConnectionError> {
+    fn on_data(
+        &mut self,
+        data: frames::Data,
+        cx: &mut Context<'_>,
+    ) -> Result<AsyncHandle<Frame>, ConnectionError> {
         let stream_id = data.stream_id;
+        let seq_num = data.seq_num;
 
-        let stream_state = match self.stream_states.get_mut(&stream_id) {
+        let state = match self.stream_states.get(&stream_id) {
             None => return Err(ConnectionError::InvalidStreamId),
-            Some(state) => state,
+            Some(state) => state.clone(),
         };
+        let mut stream_state = state.lock().unwrap();
+        if stream_state.err.is_some() {
+            // Nobody is going to consume the frames of a failed stream
+            return Ok(AsyncHandle::Ready);
+        }
         let sender = self.stream_senders.get_mut(&stream_id).unwrap();
-        if let Async::NotReady = sender.poll_ready().map_err(|_| ConnectionError::General)? {
+        if sender
+            .poll_ready(cx)
+            .map_err(|_| ConnectionError::General)?
+            .is_pending()
+        {
+            stream_state.stats.head_of_line_stalls += 1;
+            self.stats.record_head_of_line_stall();
             return Ok(AsyncHandle::NotReady(Frame::Data(data)));
         }
 
-        let frame_size = data.payload_ref().len() as u32;
+        let expected = stream_state.next_recv_seq;
+        let duplicate = seq_before(seq_num, expected);
+        if duplicate {
+            stream_state.stats.duplicates += 1;
+            self.stats.record_duplicate();
+            if self.cfg.on_duplicate == DuplicatePolicy::Drop {
+                stream_state
+                    .span
+                    .in_scope(|| trace!(seq_num, expected, "dropped duplicate frame"));
+                return Ok(AsyncHandle::Ready);
+            }
+        } else if seq_num != expected {
+            stream_state.stats.sequence_gaps += 1;
+            self.stats.record_sequence_gap();
+            stream_state
+                .span
+                .in_scope(|| warn!(seq_num, expected, "gap in stream sequence"));
+            let err = ConnectionError::SequenceGap {
+                expected,
+                received: seq_num,
+            };
+            match self.cfg.on_gap {
+                GapPolicy::Error => {
+                    drop(stream_state);
+                    self.fail_stream(stream_id, err, false);
+                    return Ok(AsyncHandle::Ready);
+                }
+                GapPolicy::Reset => {
+                    drop(stream_state);
+                    self.fail_stream(stream_id, err, true);
+                    return Ok(AsyncHandle::Ready);
+                }
+                GapPolicy::Report => (),
+            }
+        }
+
+        // Credits account for the uncompressed size of payloads
+        let frame_size = match compression::decompressed_len(data.codec, data.payload_ref()) {
+            Ok(len) => len as u32,
+            Err(err) => {
+                stream_state
+                    .span
+                    .in_scope(|| warn!(seq_num, codec = ?data.codec, "unsupported payload"));
+                drop(stream_state);
+                self.fail_stream(stream_id, err, false);
+                return Ok(AsyncHandle::Ready);
+            }
+        };
         if self.cfg.flow_control_strategy != FlowControlStrategy::Disabled {
             if !stream_state.credits.has_capacity(frame_size) {
                 return Err(ConnectionError::InsufficientCredit);
             }
             let _res = stream_state.credits.use_credit(frame_size);
+            stream_state.span.in_scope(|| {
+                trace!(
+                    used = frame_size,
+                    available = stream_state.credits.available(),
+                    "credit used by inbound data"
+                )
+            });
         }
 
         // TODO should really leverage futures executor for this logic
         if let Err(err) = sender.try_send(frames::Frame::Data(data)) {
+            stream_state.stats.head_of_line_stalls += 1;
+            self.stats.record_head_of_line_stall();
             return Ok(AsyncHandle::NotReady(err.into_inner()));
         }
+        stream_state.stats.record_inbound(frame_size as usize);
+        if duplicate {
+            return Ok(AsyncHandle::Ready);
+        }
+        stream_state.next_recv_seq = seq_num.wrapping_add(1);
+
+        if self.resumable {
+            stream_state.unacked += 1;
+            let interval = self
+                .cfg
+                .resumption
+                .as_ref()
+                .map_or(1, |cfg| cfg.ack_interval);
+            if stream_state.unacked >= interval {
+                stream_state.unacked = 0;
+                let ack = frames::Ack {
+                    stream_id,
+                    next_seq: stream_state.next_recv_seq,
+                };
+                let _res = self.outbound.try_send(Frame::Ack(ack));
+            }
+        }
 
         Ok(AsyncHandle::Ready)
     }
 
+    /// Fails a single stream, leaving the rest of the connection untouched.
+    ///
+    /// Frames which were already received remain available to the stream unless `discard` is set.
+    fn fail_stream(&mut self, stream_id: StreamId, err: ConnectionError, discard: bool) {
+        // Dropping the sender ends the stream once its buffered frames have been consumed
+        self.stream_senders.remove(&stream_id);
+        if let Some(state) = self.stream_states.get(&stream_id) {
+            let mut state = state.lock().unwrap();
+            if discard {
+                while state.data.try_recv().is_ok() {}
+            }
+            state
+                .span
+                .in_scope(|| debug!(error = %err, "stream failed"));
+            state.err = Some(err);
+            state.notify_data_rx();
+            state.notify_data_tx();
+        }
+    }
+
     /// Returns true if the connection has an error
     pub fn has_err(&self) -> bool {
         self.err.is_some()
     }
 
-    /// Stores an error for this connection
-    fn set_err(&mut self, err: ConnectionError) {
+    /// Returns a copy of the connection's error, if there is one
+    pub fn err(&self) -> Option<ConnectionError> {
+        self.err.clone()
+    }
+
+    /// Returns true if the connection was closed through one of its handles, rather than by the
+    /// remote or a failure
+    pub fn is_closed_locally(&self) -> bool {
+        self.closed_locally
+    }
+
+    /// Stores an error for this connection and wakes every task waiting on it, so that streams
+    /// observe the failure instead of waiting on a connection which will never make progress.
+    pub(crate) fn set_err(&mut self, err: ConnectionError) {
+        for state in self.stream_states.values() {
+            let mut state = state.lock().unwrap();
+            state
+                .span
+                .in_scope(|| debug!(error = %err, "stream closed"));
+            state.conn_err = Some(err.clone());
+        }
         self.err = Some(err);
+        self.notify_all();
     }
 
-    /// Notifies all connection-related `Task`s
+    /// Wakes all connection-related tasks
     fn notify_all(&mut self) {
         self.notify_conn_task();
+        self.notify_read_task();
         self.notify_new_stream_task();
+        for state in self.stream_states.values() {
+            let mut state = state.lock().unwrap();
+            state.notify_data_tx();
+            state.notify_data_rx();
+        }
     }
 
     // Notifies connection-driving task to wake up
     fn notify_conn_task(&mut self) {
-        if let Some(task) = self.conn_task.take() {
-            task.notify()
+        if let Some(waker) = self.conn_task.take() {
+            waker.wake()
+        }
+    }
+    // Notifies connection-reading task to wake up
+    fn notify_read_task(&mut self) {
+        if let Some(waker) = self.read_task.take() {
+            waker.wake()
         }
     }
     // Notifies stream-listening task to wake up
     fn notify_new_stream_task(&mut self) {
-        if let Some(task) = self.new_stream_task.take() {
-            task.notify()
+        if let Some(waker) = self.new_stream_task.take() {
+            waker.wake()
         }
     }
 
@@ -214,92 +1047,206 @@ impl ConnectionContext {
         self.new_streams.pop_front()
     }
 
-    /// Returns the number of credits available for the stream, or `Async::NotReady` if there are none.
+    /// Returns the number of credits available for the stream, or `Poll::Pending` if there are none.
     ///
-    /// Upon returning `Async::NotReady` the current task is stored and will be woken up once
+    /// Upon returning `Poll::Pending` the task's waker is stored and will be woken up once
     /// additional credits are assigned in `on_credit_update`.
-    pub fn poll_stream_capacity(&mut self, stream_id: StreamId) -> Poll<u32, ConnectionError> {
-        if self.has_err() {
-            return Err(ConnectionError::General);
+    pub fn poll_stream_capacity(
+        &mut self,
+        stream_id: StreamId,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<u32, ConnectionError>> {
+        if let Some(err) = self.err() {
+            return Poll::Ready(Err(err));
         }
-        try_ready!(
-            self.poll_conn_capacity()
-                .map_err(|_| ConnectionError::InsufficientCredit)
-        );
-        let stream_state = match self.stream_states.get_mut(&stream_id) {
-            None => {
-                return Err(ConnectionError::InvalidStreamId);
-            }
-            Some(state) => state,
-        };
-        let remaining = stream_state.credits.available();
-        if remaining == 0 {
-            stream_state.send_task = Some(task::current());
-            return Ok(Async::NotReady);
+        ready!(self.poll_conn_capacity(cx)).map_err(|_| ConnectionError::InsufficientCredit)?;
+        match self.stream_states.get(&stream_id) {
+            None => Poll::Ready(Err(ConnectionError::InvalidStreamId)),
+            Some(state) => state.lock().unwrap().poll_capacity(&self.stats, cx),
         }
-        Ok(Async::Ready(remaining))
     }
 
     pub fn sender(&self) -> Sender<Frame> {
         self.outbound.clone()
     }
 
-    pub fn poll_conn_capacity(&mut self) -> Poll<(), ()> {
-        self.outbound.poll_ready().map_err(|_| ())
+    pub fn poll_conn_capacity(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+        self.outbound.poll_ready(cx).map_err(|_| ())
     }
 
+    /// Queues `frame` for writing.
+    ///
+    /// Data frames are numbered in the order they are sent on their stream, regardless of the
+    /// sequence number they carry.
     pub fn send_frame(&mut self, frame: Frame) -> Result<(), ConnectionError> {
-        if let Frame::Data(ref data) = frame {
-            // Flow control checks
-            let stream_state = match self.stream_states.get_mut(&data.stream_id) {
-                None => {
-                    return Err(ConnectionError::InvalidStreamId);
-                }
-                Some(state) => state,
-            };
+        if let Some(err) = self.err() {
+            return Err(err);
+        }
+        match frame {
+            Frame::Data(data) => match self.stream_states.get(&data.stream_id) {
+                None => Err(ConnectionError::InvalidStreamId),
+                Some(state) => state.lock().unwrap().send_data(data, &mut self.outbound),
+            },
+            frame => {
+                self.outbound.try_send(frame)?;
+                self.notify_conn_task();
+                Ok(())
+            }
+        }
+    }
 
-            // TODO move into own FC module
-            if self.cfg.flow_control_strategy != FlowControlStrategy::Disabled {
-                let size = data.payload_ref().len() as u32;
-                if !stream_state.credits.has_capacity(size) {
-                    return Err(ConnectionError::InsufficientCredit);
+    pub fn poll_complete<T: AsyncWrite + Unpin>(
+        &mut self,
+        tx: &mut FrameWriter<T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ConnectionError>> {
+        ready!(tx.poll_buffer_ready(cx))?;
+
+        while let Some(frame) = self.control.pop_front() {
+            let _res = ready!(tx.buffer_and_flush(frame, cx))?;
+            ready!(tx.poll_buffer_ready(cx))?;
+        }
+        loop {
+            match Pin::new(&mut self.outbound_listener).poll_next(cx) {
+                Poll::Ready(Some(frame)) => {
+                    let _res = ready!(tx.buffer_and_flush(frame, cx))?;
+                    ready!(tx.poll_buffer_ready(cx))?;
+                }
+                Poll::Ready(None) => break,
+                Poll::Pending => {
+                    // Frames buffered by an earlier, blocked flush still have to be written
+                    ready!(tx.poll_flush(cx))?;
+                    return Poll::Pending;
                 }
-                let _res = stream_state.credits.use_credit(size);
             }
         }
-        // TODO handle res error
-        let _res = self.outbound.try_send(frame);
-        self.notify_conn_task();
-        Ok(())
+        ready!(tx.poll_flush(cx))?;
+        Poll::Ready(Ok(()))
     }
 
-    pub fn poll_complete<T: AsyncWrite>(&mut self, tx: &mut FrameWriter<T>) -> Poll<(), ()> {
-        use futures::Stream;
-
-        try_ready!(tx.poll_buffer_ready().map_err(|_| ()));
+    /// Takes the next frame queued for the remote without encoding it, for remotes within the
+    /// same process.
+    ///
+    /// Frames are counted in the metrics with the size they would have on the wire.
+    pub(crate) fn poll_outbound(&mut self, cx: &mut Context<'_>) -> Poll<Option<Frame>> {
+        let frame = match self.control.pop_front() {
+            Some(frame) => Some(frame),
+            None => ready!(Pin::new(&mut self.outbound_listener).poll_next(cx)),
+        };
+        if let Some(ref frame) = frame {
+            let size = frame.encoded_len() + frames::FRAME_HEAD_LEN as usize;
+            self.stats.record_outbound(frame.frame_type(), size);
+        }
+        Poll::Ready(frame)
+    }
 
-        while let Some(frame) = try_ready!(self.outbound_listener.poll()) {
-            // TODO handle err
-            let _res = try_ready!(tx.buffer_and_flush(frame).map_err(|_| ()));
-            try_ready!(tx.poll_buffer_ready().map_err(|_| ()));
+    /// Handles a frame taken from a remote within the same process, returning it if it cannot be
+    /// delivered yet
+    pub(crate) fn deliver(
+        &mut self,
+        frame: Frame,
+        cx: &mut Context<'_>,
+    ) -> Result<Option<Frame>, ConnectionError> {
+        let frame_type = frame.frame_type();
+        let size = frame.encoded_len() + frames::FRAME_HEAD_LEN as usize;
+        match self.handle_frame(frame, cx)? {
+            AsyncHandle::Ready => {
+                self.stats.record_inbound(frame_type, size);
+                Ok(None)
+            }
+            AsyncHandle::NotReady(frame) => Ok(Some(frame)),
         }
-        Ok(Async::Ready(()))
     }
 }
 
 pub type SharedConnectionContext = Arc<Mutex<ConnectionContext>>;
+
+/// Cloneable handle to a connection whose I/O is driven by a `ConnectionDriver` elsewhere
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    id: ConnectionId,
+    ctx: SharedConnectionContext,
+}
+
+impl ConnectionHandle {
+    pub fn new(ctx: SharedConnectionContext) -> Self {
+        let id = ctx.lock().unwrap().id;
+        ConnectionHandle { id, ctx }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Returns a future which resolves to the requested stream once it has been opened
+    pub fn open_stream(&self, stream_id: StreamId, credit: u32) -> StreamRequester {
+        StreamRequester {
+            stream_id,
+            credit,
+            ctx: self.ctx.clone(),
+        }
+    }
+
+    /// Returns the error which closed the connection, if any
+    pub fn err(&self) -> Option<ConnectionError> {
+        self.ctx.lock().unwrap().err()
+    }
+
+    /// Returns a snapshot of the connection's metrics, including those of all its streams
+    pub fn metrics(&self) -> ConnectionMetrics {
+        self.ctx.lock().unwrap().metrics()
+    }
+
+    /// Returns the number of streams which have not failed and still have a handle on this end
+    pub fn open_streams(&self) -> usize {
+        self.ctx.lock().unwrap().open_streams()
+    }
+
+    /// Sends an extension frame which is not bound to any of this end's streams.
+    ///
+    /// Fails if its type lies outside the range reserved for extensions, or if it is longer than
+    /// the remote reads.
+    pub fn send_extension(&self, ext: frames::Extension) -> Result<(), ConnectionError> {
+        let mut ctx = self.ctx.lock().unwrap();
+        if let Some(err) = ctx.err() {
+            return Err(err);
+        }
+        let framing = ctx.framing;
+        extension::send_extension(&mut ctx.outbound, framing, ext)
+    }
+
+    /// Closes the connection, failing all of its streams with `ConnectionError::Closed`
+    pub fn close(&self) {
+        let mut ctx = self.ctx.lock().unwrap();
+        if !ctx.has_err() {
+            ctx.span.in_scope(|| info!("connection closed locally"));
+            ctx.closed_locally = true;
+            ctx.set_err(ConnectionError::Closed);
+        }
+    }
+
+    pub fn clone_ctx(&self) -> SharedConnectionContext {
+        self.ctx.clone()
+    }
+}
 pub type SharedFrameWriter<O> = Arc<Mutex<FrameWriter<O>>>;
 
-struct IoHandle<I: AsyncRead, O: AsyncWrite> {
+struct IoHandle<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> {
     rx: FrameReader<I>,
     tx: SharedFrameWriter<O>,
 }
 
-impl<I: AsyncRead, O: AsyncWrite> IoHandle<I, O> {
-    pub fn new(rx: I, tx: O) -> Self {
+impl<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> IoHandle<I, O> {
+    /// Creates the reader and writer of a transport, neither of which handles frames longer than
+    /// `max_frame_len` until the remote advertises its own maximum
+    pub fn new(rx: I, tx: O, stats: Arc<ConnectionStats>, max_frame_len: usize) -> Self {
+        let mut rx = FrameReader::new(rx, stats.clone());
+        rx.set_max_frame_len(max_frame_len);
+        let mut tx = FrameWriter::with_stats(tx, stats);
+        tx.set_max_frame_len(max_frame_len);
         IoHandle {
-            rx: FrameReader::new(rx),
-            tx: Arc::new(Mutex::new(FrameWriter::new(tx))),
+            rx,
+            tx: Arc::new(Mutex::new(tx)),
         }
     }
 
@@ -308,22 +1255,142 @@ impl<I: AsyncRead, O: AsyncWrite> IoHandle<I, O> {
     }
 }
 
-pub struct ConnectionDriver<I: AsyncRead, O: AsyncWrite> {
+pub struct ConnectionDriver<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> {
     handle: IoHandle<I, O>,
     ctx: SharedConnectionContext,
     head_of_line: Option<Frame>,
+    span: Span,
+    /// Generation of `ctx` this driver was bound as; a newer transport supersedes this one
+    generation: u64,
+    /// Handshake sent to the remote, once it has been decided
+    local: Option<Handshake>,
+    hello_sent: bool,
+    /// Handshake received from the remote
+    peer: Option<Handshake>,
+    /// Whether this end opened the connection
+    initiator: bool,
+    /// Progress of the authentication exchange, once it has started
+    auth: Option<Authenticator>,
+    established: bool,
 }
 
-impl<I: AsyncRead, O: AsyncWrite> ConnectionDriver<I, O> {
+impl<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> ConnectionDriver<I, O> {
     pub fn with_io(reader: I, writer: O, id: u32) -> Self {
-        let ctx = ConnectionContext::new(id);
-        let ctx = Arc::new(Mutex::new(ctx));
-        let handle = IoHandle::new(reader, writer);
+        ConnectionDriver::with_config(reader, writer, id, ConnectionConfig::default())
+    }
 
+    /// Creates a driver for a new session which sends its handshake right away
+    pub fn with_config(reader: I, writer: O, id: u32, cfg: ConnectionConfig) -> Self {
+        let mut driver = ConnectionDriver::accept(reader, writer, id, cfg);
+        driver.local = Some(driver.new_session_handshake());
+        driver.initiator = true;
+        driver
+    }
+
+    /// Creates a driver which waits for the remote's handshake before sending its own.
+    ///
+    /// The remote's handshake is obtained through `handshake`, after which `accept_session`
+    /// decides which session the connection belongs to.
+    pub fn accept(reader: I, writer: O, id: u32, cfg: ConnectionConfig) -> Self {
+        let ctx = Arc::new(Mutex::new(ConnectionContext::with_config(id, cfg)));
+        Registry::global().register(&ctx);
+        ConnectionDriver::bind(reader, writer, ctx, None)
+    }
+
+    /// Binds a new transport to a session whose previous transport was lost, asking the remote
+    /// to resume it
+    pub fn resume(reader: I, writer: O, ctx: SharedConnectionContext) -> Self {
+        let hello = {
+            let mut ctx = ctx.lock().unwrap();
+            ctx.rebind();
+            Handshake::new(
+                ctx.session_id,
+                frames::HANDSHAKE_RESUMABLE | frames::HANDSHAKE_RESUME | config_flags(&ctx.cfg),
+                advertised_max_frame_len(&ctx.cfg),
+            )
+        };
+        ConnectionDriver::bind(reader, writer, ctx, Some(hello))
+    }
+
+    fn bind(reader: I, writer: O, ctx: SharedConnectionContext, local: Option<Handshake>) -> Self {
+        let (span, stats, generation, max_frame_len) = {
+            let ctx = ctx.lock().unwrap();
+            (
+                ctx.span(),
+                ctx.stats.clone(),
+                ctx.generation,
+                ctx.cfg.max_frame_len,
+            )
+        };
         ConnectionDriver {
             head_of_line: None,
-            handle,
+            handle: IoHandle::new(reader, writer, stats, max_frame_len),
             ctx,
+            span,
+            generation,
+            initiator: local.is_some(),
+            local,
+            hello_sent: false,
+            peer: None,
+            auth: None,
+            established: false,
+        }
+    }
+
+    fn new_session_handshake(&self) -> Handshake {
+        let ctx = self.ctx.lock().unwrap();
+        let flags = match ctx.cfg.resumption {
+            Some(_) => frames::HANDSHAKE_RESUMABLE,
+            None => 0,
+        };
+        Handshake::new(
+            ctx.session_id,
+            flags | config_flags(&ctx.cfg),
+            advertised_max_frame_len(&ctx.cfg),
+        )
+    }
+
+    /// Returns a future which resolves to this driver once the remote's handshake has been received
+    pub fn handshake(self) -> Handshaking<I, O> {
+        Handshaking { driver: Some(self) }
+    }
+
+    /// Replies to the remote's handshake, continuing `session` in place of this driver's own
+    /// context if given.
+    ///
+    /// A session may only be continued if the remote asked to resume it.
+    pub fn accept_session(&mut self, session: Option<SharedConnectionContext>) {
+        let peer = match self.peer {
+            Some(ref peer) => peer.clone(),
+            None => return,
+        };
+        match session {
+            Some(ref ctx) if peer.has(frames::HANDSHAKE_RESUME) => {
+                let (stats, auth, max_frame_len) = {
+                    let mut ctx = ctx.lock().unwrap();
+                    self.generation = ctx.rebind();
+                    self.span = ctx.span();
+                    (
+                        ctx.stats.clone(),
+                        config_flags(&ctx.cfg),
+                        advertised_max_frame_len(&ctx.cfg),
+                    )
+                };
+                self.handle.rx.set_stats(stats.clone());
+                self.handle.rx.set_max_frame_len(max_frame_len as usize);
+                self.handle.tx.lock().unwrap().set_stats(stats);
+                self.ctx = ctx.clone();
+                self.local = Some(Handshake::new(
+                    peer.session_id,
+                    frames::HANDSHAKE_RESUMABLE | frames::HANDSHAKE_RESUME | auth,
+                    max_frame_len,
+                ));
+            }
+            _ => {
+                // The session is identified by the connecting end
+                self.ctx.lock().unwrap().session_id = peer.session_id;
+                self.local = Some(self.new_session_handshake());
+            }
         }
     }
 
@@ -332,6 +1399,11 @@ impl<I: AsyncRead, O: AsyncWrite> ConnectionDriver<I, O> {
         IncomingStreams::new(self.clone_ctx())
     }
 
+    /// Returns a handle for opening streams on this connection while it is being driven
+    pub fn handle(&self) -> ConnectionHandle {
+        ConnectionHandle::new(self.ctx.clone())
+    }
+
     pub fn clone_ctx(&mut self) -> SharedConnectionContext {
         self.ctx.clone()
     }
@@ -340,84 +1412,811 @@ impl<I: AsyncRead, O: AsyncWrite> ConnectionDriver<I, O> {
         self.handle.clone_writer()
     }
 
-    pub fn poll_read_progress(&mut self) -> Poll<(), ConnectionError> {
+    /// Returns a snapshot of the connection's metrics, including those of all its streams
+    pub fn metrics(&self) -> ConnectionMetrics {
+        self.ctx.lock().unwrap().metrics()
+    }
+
+    /// Returns the remote's handshake, reading it if it has not been received yet.
+    ///
+    /// The local handshake is written first if it has already been decided.
+    pub fn poll_handshake(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Handshake, ConnectionError>> {
+        if let Some(ref peer) = self.peer {
+            return Poll::Ready(Ok(peer.clone()));
+        }
+        ready!(self.poll_send_handshake(cx))?;
+        match ready!(self.handle.rx.poll_frame(cx))? {
+            Some(Frame::Handshake(peer)) => {
+                debug!(
+                    session_id = peer.session_id,
+                    flags = peer.flags,
+                    "received handshake"
+                );
+                self.peer = Some(peer.clone());
+                Poll::Ready(Ok(peer))
+            }
+            Some(frame) => {
+                warn!(frame_type = ?frame.frame_type(), "expected handshake");
+                Poll::Ready(Err(ConnectionError::Handshake))
+            }
+            None => Poll::Ready(Err(ConnectionError::Closed)),
+        }
+    }
+
+    /// Writes the local handshake once it has been decided
+    fn poll_send_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectionError>> {
+        let local = match self.local {
+            Some(ref local) => local.clone(),
+            None => return Poll::Ready(Ok(())),
+        };
+        let mut tx = self.handle.tx.lock().unwrap();
+        if !self.hello_sent {
+            ready!(tx.poll_buffer_ready(cx))?;
+            tx.buffer_frame(Frame::Handshake(local))?;
+            self.hello_sent = true;
+        }
+        // The handshake need not be flushed before waiting for the remote's
+        if let Poll::Ready(Err(err)) = tx.poll_flush(cx) {
+            return Poll::Ready(Err(err.into()));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Exchanges handshakes with the remote, completing once the session is established
+    fn poll_establish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectionError>> {
+        if self.established {
+            return Poll::Ready(Ok(()));
+        }
+        let peer = ready!(self.poll_handshake(cx))?;
+        if self.local.is_none() {
+            self.accept_session(None);
+        }
+        ready!(self.poll_send_handshake(cx))?;
+
+        let local = self.local.clone().unwrap();
+        if local.has(frames::HANDSHAKE_RESUME) && !peer.has(frames::HANDSHAKE_RESUME) {
+            return Poll::Ready(Err(ConnectionError::ResumeRejected));
+        }
+        if local.has(frames::HANDSHAKE_AUTH) != peer.has(frames::HANDSHAKE_AUTH) {
+            warn!("authentication required by only one end");
+            return Poll::Ready(Err(ConnectionError::Authentication));
+        }
+        let max_frame_len = peer.max_frame_len as usize;
+        if max_frame_len < frames::MIN_MAX_FRAME_LEN {
+            warn!(
+                max_frame_len,
+                "remote advertised too short a maximum frame length"
+            );
+            return Poll::Ready(Err(ConnectionError::Handshake));
+        }
+        // Frames must not be longer than the remote reads
+        self.handle
+            .tx
+            .lock()
+            .unwrap()
+            .set_max_frame_len(max_frame_len);
+        // Every frame following the handshakes carries a checksum if either end asked for it
+        let checksums =
+            local.has(frames::HANDSHAKE_CHECKSUM) || peer.has(frames::HANDSHAKE_CHECKSUM);
+        self.handle.rx.set_checksums(checksums);
+        self.handle.tx.lock().unwrap().set_checksums(checksums);
+        self.ctx.lock().unwrap().set_framing(Some(Framing {
+            max_frame_len,
+            checksums,
+        }));
+        if local.has(frames::HANDSHAKE_AUTH) {
+            ready!(self.poll_authenticate(cx))?;
+        }
+        let resumed = local.has(frames::HANDSHAKE_RESUME);
+        self.ctx
+            .lock()
+            .unwrap()
+            .on_established(resumed, peer.has(frames::HANDSHAKE_RESUMABLE));
+        self.established = true;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Proves knowledge of the pre-shared key to the remote and verifies its proof in turn
+    fn poll_authenticate(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnectionError>> {
+        if self.auth.is_none() {
+            let ctx = self.ctx.lock().unwrap();
+            let cfg = ctx
+                .cfg
+                .auth
+                .as_ref()
+                .ok_or(ConnectionError::Authentication)?;
+            self.auth = Some(Authenticator::new(cfg, self.initiator));
+        }
+        let auth = self.auth.as_mut().unwrap();
+        let mut tx = self.handle.tx.lock().unwrap();
+        loop {
+            if let Some(frame) = auth.pending.take() {
+                match tx.poll_buffer_ready(cx) {
+                    Poll::Ready(Ok(())) => (),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
+                    Poll::Pending => {
+                        auth.pending = Some(frame);
+                        return Poll::Pending;
+                    }
+                }
+                tx.buffer_frame(Frame::Auth(frame))?;
+            }
+            // Like the handshake, authentication frames need not be flushed before waiting
+            if let Poll::Ready(Err(err)) = tx.poll_flush(cx) {
+                return Poll::Ready(Err(err.into()));
+            }
+            if auth.is_complete() {
+                debug!("authenticated remote");
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.handle.rx.poll_frame(cx))? {
+                Some(Frame::Auth(frame)) => auth.on_auth(frame)?,
+                Some(frame) => {
+                    warn!(frame_type = ?frame.frame_type(), "expected authentication");
+                    return Poll::Ready(Err(ConnectionError::Authentication));
+                }
+                None => return Poll::Ready(Err(ConnectionError::Closed)),
+            }
+        }
+    }
+
+    /// Reads frames and hands them to their streams until there is nothing more to read, a stream
+    /// cannot accept any more frames, or `READ_BUDGET` frames have been read
+    pub fn poll_read_progress(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ConnectionError>> {
         use std::borrow::BorrowMut;
 
         let rx = self.handle.rx.borrow_mut();
 
-        loop {
+        for _ in 0..READ_BUDGET {
             // Continue looping until error, connection is closed, or there is nothing more to read
             let cur = match self.head_of_line.take() {
-                None => try_ready!(rx.poll_frame()),
+                None => ready!(rx.poll_frame(cx))?,
                 Some(head) => Some(head),
             };
             match cur {
-                None => return Ok(Async::Ready(())),
+                None => {
+                    // The remote end closed the connection
+                    return Poll::Ready(Ok(()));
+                }
                 Some(frame) => {
                     let mut ctx = self.ctx.lock().unwrap();
-                    match ctx.handle_frame(frame) {
+                    match ctx.handle_frame(frame, cx) {
                         Ok(AsyncHandle::Ready) => (),
                         Ok(AsyncHandle::NotReady(f)) => {
+                            trace!(frame_type = ?f.frame_type(), "frame blocked at head of line");
                             self.head_of_line = Some(f);
-                            return Ok(Async::NotReady);
+                            return Poll::Pending;
                         }
-                        Err(why) => {
-                            println!("handle_frame err: {:?}", why);
+                        // The remote broke the protocol, or relies on a frame type this end
+                        // does not understand
+                        Err(err) => {
+                            warn!(error = %err, "failed to handle frame");
+                            return Poll::Ready(Err(err));
                         }
                     }
                 }
             }
         }
+        // Yield, but continue reading right after whatever else the task has to do
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
 
-    // TODO errors
-    pub fn poll_write_progress(&mut self) -> Poll<(), ()> {
+    pub fn poll_write_progress(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ConnectionError>> {
         let mut ctx = self.ctx.lock().unwrap();
-        println!("poll write progress");
         let ctx = &mut *ctx;
+        // Registered before writing so that no frame queued in the meantime goes unnoticed
+        ctx.conn_task = Some(cx.waker().clone());
 
         let mut tx = self.handle.tx.lock().unwrap();
         let tx = &mut *tx;
 
-        ctx.poll_complete(tx)
+        ctx.poll_complete(tx, cx)
+    }
+
+    /// Splits the driver into a future which reads from the transport and one which writes to it,
+    /// so that both directions make progress independently of each other.
+    ///
+    /// The reader also establishes the connection, before which the writer does not write
+    /// anything. Both futures have to be spawned, and may run on different threads. Must be called
+    /// after `accept_session`, since resuming a session binds the driver to another context.
+    pub fn split(self) -> (ConnectionReader<I, O>, ConnectionWriter<O>) {
+        let established = Arc::new(AtomicBool::new(self.established));
+        let writer = ConnectionWriter {
+            tx: self.handle.tx.clone(),
+            ctx: self.ctx.clone(),
+            span: self.span.clone(),
+            generation: self.generation,
+            established: established.clone(),
+        };
+        let reader = ConnectionReader {
+            driver: self,
+            established,
+        };
+        (reader, writer)
+    }
+
+    /// Drives the connection, writing outbound frames too if `write` is set
+    fn poll_driver(
+        &mut self,
+        write: bool,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ConnectionError>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        {
+            let mut ctx = self.ctx.lock().unwrap();
+            if ctx.generation != self.generation {
+                debug!("connection superseded by a newer transport");
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(err) = ctx.err() {
+                // The connection was closed through a handle, or failed while writing
+                return Poll::Ready(match err {
+                    ConnectionError::Closed => Ok(()),
+                    err => Err(err),
+                });
+            }
+            if let Some(ref err) = ctx.link_err {
+                // The transport was lost while writing
+                return Poll::Ready(Err(err.clone()));
+            }
+            ctx.read_task = Some(cx.waker().clone());
+        }
+        match self.poll_establish(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(self.fail(err))),
+        }
+        match self.poll_read_progress(cx) {
+            Poll::Ready(Ok(())) => {
+                // Streams waiting on this connection will never make progress again
+                self.fail(ConnectionError::Closed);
+                return Poll::Ready(Ok(()));
+            }
+            Poll::Pending => (),
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(self.fail(err))),
+        }
+        if write {
+            if let Poll::Ready(Err(err)) = self.poll_write_progress(cx) {
+                return Poll::Ready(Err(self.fail(err)));
+            }
+        }
+        Poll::Pending
     }
 }
 
-impl<I: AsyncRead, O: AsyncWrite> Future for ConnectionDriver<I, O> {
-    type Item = ();
-    type Error = ();
+impl<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> ConnectionDriver<I, O> {
+    /// Records `err` as the connection's failure, waking all tasks waiting on the connection.
+    ///
+    /// Resumable sessions only lose their transport, keeping their streams until it is replaced.
+    fn fail(&mut self, err: ConnectionError) -> ConnectionError {
+        let mut ctx = self.ctx.lock().unwrap();
+        if ctx.generation == self.generation
+            && ctx.resumable
+            && err == ConnectionError::Authentication
+            && !self.initiator
+        {
+            // An unauthenticated transport must not be able to close the session it claimed
+            warn!(error = %err, "rejected transport, awaiting resumption");
+            ctx.disconnect(err.clone());
+            return err;
+        }
+        ctx.fail_transport(self.generation, err)
+    }
+}
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        loop {
-            match self.poll_read_progress() {
-                Ok(Async::Ready(())) => {
-                    return Ok(Async::Ready(()));
-                }
-                Ok(Async::NotReady) => {
-                    try_ready!(self.poll_write_progress());
+impl<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> Drop for ConnectionDriver<I, O> {
+    /// Dropping the driver closes the connection, so streams must not wait on it any longer
+    fn drop(&mut self) {
+        if let Ok(mut ctx) = self.ctx.lock() {
+            if !ctx.has_err() && !ctx.is_disconnected() && ctx.generation == self.generation {
+                ctx.set_err(ConnectionError::Closed);
+            }
+        }
+    }
+}
 
-                    // Store this task as the one responsible for making connection progress
-                    match self.ctx.lock() {
-                        Ok(mut ctx) => {
-                            ctx.conn_task = Some(task::current());
-                        }
-                        Err(_) => {
-                            // Should this ever be possible?
-                            let mut ctx = self.ctx.lock().unwrap();
-                            println!("Mutex poisoned");
-                            ctx.set_err(ConnectionError::General);
-                            return Err(());
-                        }
-                    }
-                    return Ok(Async::NotReady);
-                }
-                Err(err) => {
-                    let mut ctx = self.ctx.lock().unwrap();
-                    println!("Closing conn with err: {:?}", err);
-                    ctx.set_err(err);
-                    return Err(());
-                }
+impl<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> Future for ConnectionDriver<I, O> {
+    type Output = Result<(), ConnectionError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().poll_driver(true, cx)
+    }
+}
+
+/// Future which establishes a connection and then reads its inbound frames, created by
+/// `ConnectionDriver::split`.
+///
+/// Completes once the connection is closed, failing if it failed in either direction.
+pub struct ConnectionReader<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> {
+    driver: ConnectionDriver<I, O>,
+    /// Shared with the writer, which waits for the connection to be established
+    established: Arc<AtomicBool>,
+}
+
+impl<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> Future for ConnectionReader<I, O> {
+    type Output = Result<(), ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let res = self.driver.poll_driver(false, cx);
+        if self.driver.established && !self.established.load(Ordering::Acquire) {
+            let mut ctx = self.driver.ctx.lock().unwrap();
+            self.established.store(true, Ordering::Release);
+            ctx.notify_conn_task();
+        }
+        res
+    }
+}
+
+/// Future which writes a connection's outbound frames, created by `ConnectionDriver::split`.
+///
+/// Completes once the connection is closed or its reader has stopped.
+pub struct ConnectionWriter<O: AsyncWrite + Unpin> {
+    tx: SharedFrameWriter<O>,
+    ctx: SharedConnectionContext,
+    span: Span,
+    generation: u64,
+    established: Arc<AtomicBool>,
+}
+
+impl<O: AsyncWrite + Unpin> Future for ConnectionWriter<O> {
+    type Output = Result<(), ConnectionError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let mut ctx = self.ctx.lock().unwrap();
+        if ctx.generation != self.generation || ctx.is_disconnected() {
+            // Either the session moved on to a newer transport, or the reader reports its loss
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(err) = ctx.err() {
+            return Poll::Ready(match err {
+                ConnectionError::Closed => Ok(()),
+                err => Err(err),
+            });
+        }
+        ctx.conn_task = Some(cx.waker().clone());
+        if !self.established.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+        let res = ctx.poll_complete(&mut self.tx.lock().unwrap(), cx);
+        match res {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(ctx.fail_transport(self.generation, err))),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl<O: AsyncWrite + Unpin> Drop for ConnectionWriter<O> {
+    /// Nothing writes to the connection once the writer is gone
+    fn drop(&mut self) {
+        if let Ok(mut ctx) = self.ctx.lock() {
+            if !ctx.has_err() && !ctx.is_disconnected() && ctx.generation == self.generation {
+                ctx.set_err(ConnectionError::Closed);
             }
         }
     }
 }
+
+/// Future which resolves to a driver together with the remote's handshake
+pub struct Handshaking<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> {
+    driver: Option<ConnectionDriver<I, O>>,
+}
+
+impl<I: AsyncRead + Unpin, O: AsyncWrite + Unpin> Future for Handshaking<I, O> {
+    type Output = Result<(ConnectionDriver<I, O>, Handshake), ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let peer = {
+            let driver = self
+                .driver
+                .as_mut()
+                .expect("polled Handshaking after completion");
+            let span = driver.span.clone();
+            let _enter = span.enter();
+            ready!(driver.poll_handshake(cx))?
+        };
+        Poll::Ready(Ok((self.driver.take().unwrap(), peer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::StreamRef;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use futures::future;
+    use futures::task::noop_waker_ref;
+    use futures::StreamExt;
+
+    /// Opens stream 1 as if requested by the remote, returning the local end of it
+    fn remote_stream(cfg: ConnectionConfig) -> StreamRef {
+        let ctx = Arc::new(Mutex::new(ConnectionContext::with_config(1, cfg)));
+        let request = frames::StreamRequest::new(StreamId(1), 1024);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        ctx.lock()
+            .unwrap()
+            .handle_frame(Frame::StreamRequest(request), &mut cx)
+            .unwrap();
+        let mut incoming = IncomingStreams::new(ctx);
+        match block_on(incoming.next()) {
+            Some(Ok(stream)) => stream,
+            _ => panic!("stream not opened"),
+        }
+    }
+
+    fn receive(stream: &StreamRef, seq_num: u32) {
+        let data = frames::Data::new(StreamId(1), seq_num, Bytes::from("payload"));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let ctx = stream.clone_ctx();
+        let mut ctx = ctx.lock().unwrap();
+        match ctx.handle_frame(Frame::Data(data), &mut cx) {
+            Ok(AsyncHandle::Ready) => (),
+            _ => panic!("frame not handled"),
+        }
+    }
+
+    fn next_seq(stream: &mut StreamRef) -> Result<Option<u32>, ConnectionError> {
+        match block_on(stream.next()) {
+            Some(Ok(Frame::Data(data))) => Ok(Some(data.seq_num)),
+            Some(Err(err)) => Err(err),
+            None => Ok(None),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn drops_duplicates_and_fails_stream_on_gap() {
+        let stream = remote_stream(ConnectionConfig::default());
+        receive(&stream, 0);
+        receive(&stream, 0);
+        receive(&stream, 2);
+
+        let metrics = stream.metrics().unwrap();
+        assert_eq!(metrics.duplicates, 1);
+        assert_eq!(metrics.sequence_gaps, 1);
+
+        // Frames received before the gap are still delivered
+        let mut stream = stream;
+        assert_eq!(next_seq(&mut stream), Ok(Some(0)));
+        assert_eq!(
+            next_seq(&mut stream),
+            Err(ConnectionError::SequenceGap {
+                expected: 1,
+                received: 2
+            })
+        );
+    }
+
+    #[test]
+    fn sends_data_while_the_connection_is_locked() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let mut stream = remote_stream(ConnectionConfig::default());
+        let ctx = stream.clone_ctx();
+        let guard = ctx.lock().unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let res = stream.send_data(Bytes::from("hello"));
+            let _ = done_tx.send((res, stream.metrics().unwrap().outbound.frames));
+        });
+        let sent = done_rx.recv_timeout(Duration::from_secs(5));
+        drop(guard);
+        assert_eq!(sent, Ok((Ok(()), 1)));
+    }
+
+    #[test]
+    fn fails_to_send_data_once_outbound_queue_is_full() {
+        let mut stream = remote_stream(ConnectionConfig::default());
+        let mut sent = 0;
+        let err = loop {
+            match stream.send_data(Bytes::from("hello")) {
+                Ok(()) => sent += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err, ConnectionError::QueueFull);
+
+        // Every frame which was accepted is queued, without skipping a sequence number
+        let ctx = stream.clone_ctx();
+        let mut queued = Vec::new();
+        while let Ok(frame) = ctx.lock().unwrap().outbound_listener.try_recv() {
+            if let Frame::Data(data) = frame {
+                queued.push(data.seq_num);
+            }
+        }
+        assert_eq!(queued, (0..sent).collect::<Vec<_>>());
+        assert_eq!(stream.metrics().unwrap().outbound.frames, sent as u64);
+        assert_eq!(stream.send_data(Bytes::from("hello")), Ok(()));
+    }
+
+    #[test]
+    fn sends_no_data_between_fragments_of_message_sent_by_another_handle() {
+        let mut cfg = ConnectionConfig::default();
+        cfg.fragments.fragment_size = 1;
+        let mut stream = remote_stream(cfg);
+        let mut other = stream.clone();
+        let ctx = stream.clone_ctx();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // Fills the outbound queue before the message is sent
+        let message = Bytes::from(vec![7; 2048]);
+        let mut sending = Box::pin(stream.send_message(message.clone()));
+        assert!(sending.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(
+            other.send_data(Bytes::from("other")),
+            Err(ConnectionError::MessageInProgress)
+        );
+        let sent = future::join(sending, other.send(Bytes::from("other")));
+        let mut sent = Box::pin(sent);
+
+        let mut payloads = Vec::new();
+        let (sent_message, sent_other) = loop {
+            if let Poll::Ready(sent) = sent.as_mut().poll(&mut cx) {
+                break sent;
+            }
+            while let Ok(Frame::Data(data)) = ctx.lock().unwrap().outbound_listener.try_recv() {
+                payloads.push(data.payload());
+            }
+        };
+        sent_message.unwrap();
+        sent_other.unwrap();
+        while let Ok(Frame::Data(data)) = ctx.lock().unwrap().outbound_listener.try_recv() {
+            payloads.push(data.payload());
+        }
+        assert_eq!(payloads.len(), message.len() + 1);
+        assert!(payloads[..message.len()].iter().all(|p| p[..] == [7]));
+        assert_eq!(payloads[message.len()], Bytes::from("other"));
+    }
+
+    #[test]
+    fn drops_fragments_queued_after_overlong_message() {
+        let mut cfg = ConnectionConfig::default();
+        cfg.fragments.max_message_len = 8;
+        let mut stream = remote_stream(cfg);
+        let ctx = stream.clone_ctx();
+        let receive = |seq_num: u32, payload: &'static str, more: bool| {
+            let mut data = frames::Data::new(StreamId(1), seq_num, Bytes::from(payload));
+            if more {
+                data.flags |= frames::FLAG_MORE_FRAGMENTS;
+            }
+            let mut cx = Context::from_waker(noop_waker_ref());
+            match ctx.lock().unwrap().handle_frame(Frame::Data(data), &mut cx) {
+                Ok(AsyncHandle::Ready) => (),
+                _ => panic!("frame not handled"),
+            }
+        };
+
+        receive(0, "hello", true);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(stream.poll_next_unpin(&mut cx).is_pending());
+        // Queued before the stream fails
+        receive(1, "world", true);
+        receive(2, "!", false);
+        for _ in 0..2 {
+            // The end of the message is dropped rather than yielded as a message of its own
+            match block_on(stream.next()) {
+                Some(Err(ConnectionError::MessageTooLarge)) => (),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn fails_stream_on_unsupported_payload() {
+        let mut stream = remote_stream(ConnectionConfig::default());
+        receive(&stream, 0);
+        let mut data = frames::Data::new(StreamId(1), 1, Bytes::from("?"));
+        // Too short to carry the size an LZ4 payload is prefixed with
+        data.codec = compression::Codec::Lz4;
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let ctx = stream.clone_ctx();
+        match ctx.lock().unwrap().handle_frame(Frame::Data(data), &mut cx) {
+            Ok(AsyncHandle::Ready) => (),
+            _ => panic!("frame not handled"),
+        }
+
+        assert_eq!(next_seq(&mut stream), Ok(Some(0)));
+        assert_eq!(next_seq(&mut stream), Err(ConnectionError::Compression));
+    }
+
+    #[test]
+    fn reports_gaps_and_delivers_duplicates() {
+        let mut stream = remote_stream(ConnectionConfig {
+            on_gap: GapPolicy::Report,
+            on_duplicate: DuplicatePolicy::Deliver,
+            ..ConnectionConfig::default()
+        });
+        receive(&stream, 1);
+
+        assert_eq!(next_seq(&mut stream), Ok(Some(1)));
+        receive(&stream, 1);
+        assert_eq!(next_seq(&mut stream), Ok(Some(1)));
+        receive(&stream, 2);
+        assert_eq!(next_seq(&mut stream), Ok(Some(2)));
+
+        let metrics = stream.metrics().unwrap();
+        assert_eq!(metrics.sequence_gaps, 1);
+        assert_eq!(metrics.duplicates, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn split_driver_streams_in_both_directions() {
+        use crate::transport::memory::{pipe, PipeConfig};
+
+        let (left, right) = pipe(PipeConfig {
+            capacity: 256,
+            chunk_size: Some(3),
+            latency: Some(Duration::from_millis(5)),
+        });
+        // Each end opens a stream and sends enough frames to fill the pipe in both directions
+        let ends =
+            vec![(left, true), (right, false)]
+                .into_iter()
+                .map(|(io, initiator)| async move {
+                    let (rx, tx) = tokio::io::split(io);
+                    let mut driver = if initiator {
+                        ConnectionDriver::with_config(
+                            rx,
+                            tx,
+                            next_connection_id(),
+                            Default::default(),
+                        )
+                    } else {
+                        ConnectionDriver::accept(rx, tx, next_connection_id(), Default::default())
+                    };
+                    let handle = driver.handle();
+                    let mut incoming = driver.incoming_streams();
+                    let (reader, writer) = driver.split();
+                    tokio::spawn(async { reader.await.unwrap() });
+                    tokio::spawn(async { writer.await.unwrap() });
+                    let stream_id = StreamId(if initiator { 1 } else { 2 });
+                    let send = async {
+                        let mut stream = handle.open_stream(stream_id, 1024).await.unwrap();
+                        for i in 0..100 {
+                            stream
+                                .send_data(Bytes::from(format!("frame {}", i)))
+                                .unwrap();
+                        }
+                        stream
+                    };
+                    let receive = async {
+                        let stream = incoming.next().await.unwrap().unwrap();
+                        stream.take(100).collect::<Vec<_>>().await
+                    };
+                    let (_stream, frames) = future::join(send, receive).await;
+                    (frames.len(), handle)
+                });
+
+        let received = future::join_all(ends).await;
+        assert_eq!(
+            received.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            vec![100, 100]
+        );
+    }
+
+    #[tokio::test]
+    async fn negotiates_checksums_enabled_by_one_end() {
+        use crate::transport::memory;
+
+        let cfg = ConnectionConfig {
+            checksums: true,
+            ..ConnectionConfig::default()
+        };
+        let (handle, _, mut incoming) = memory::connect(cfg, ConnectionConfig::default());
+
+        let mut stream = handle.open_stream(StreamId(1), 1024).await.unwrap();
+        stream.send(Bytes::from("checked")).await.unwrap();
+        let mut accepted = incoming.next().await.unwrap().unwrap();
+        match accepted.next().await {
+            Some(Ok(Frame::Data(data))) => assert_eq!(data.payload(), Bytes::from("checked")),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_connection_on_protocol_violations() {
+        use crate::transport::memory;
+
+        let violations = vec![
+            (
+                Frame::Data(frames::Data::new(StreamId(9), 0, Bytes::from("?"))),
+                ConnectionError::InvalidStreamId,
+            ),
+            (
+                Frame::Handshake(frames::Handshake::new(0, 0, 4096)),
+                ConnectionError::Handshake,
+            ),
+        ];
+        for (frame, expected) in violations {
+            let (client, mut server) =
+                memory::drivers(ConnectionConfig::default(), ConnectionConfig::default());
+            let handle = client.handle();
+            tokio::spawn(client);
+            let mut incoming = server.incoming_streams();
+            let server = tokio::spawn(server);
+            handle.open_stream(StreamId(1), 1024).await.unwrap();
+            let mut accepted = incoming.next().await.unwrap().unwrap();
+
+            let ctx = handle.clone_ctx();
+            ctx.lock().unwrap().outbound.try_send(frame).unwrap();
+            ctx.lock().unwrap().notify_conn_task();
+            assert_eq!(server.await.unwrap(), Err(expected.clone()));
+            assert_eq!(accepted.next().await.unwrap().err(), Some(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn wakes_blocked_handles_when_transport_is_lost() {
+        use crate::transport::memory;
+
+        let (client, mut server) =
+            memory::drivers(ConnectionConfig::default(), ConnectionConfig::default());
+        let handle = client.handle();
+        let client = tokio::spawn(client);
+        let mut incoming = server.incoming_streams();
+        tokio::spawn(server);
+        handle.open_stream(StreamId(1), 1024).await.unwrap();
+        let mut accepted = incoming.next().await.unwrap().unwrap();
+
+        let blocked = tokio::spawn(async move { (accepted.next().await, incoming.next().await) });
+        tokio::task::yield_now().await;
+        // Dropping the remote's driver drops its end of the transport
+        client.abort();
+        let (frame, stream) = blocked.await.unwrap();
+        assert_eq!(frame.unwrap().err(), Some(ConnectionError::Closed));
+        assert_eq!(stream.unwrap().err(), Some(ConnectionError::Closed));
+    }
+
+    #[tokio::test]
+    async fn limits_frames_to_length_advertised_by_remote() {
+        use crate::protocol::frames::FrameExt;
+        use crate::transport::memory;
+
+        let cfg = ConnectionConfig {
+            max_frame_len: 4096,
+            ..ConnectionConfig::default()
+        };
+        let (handle, server_handle, mut incoming) =
+            memory::connect(cfg, ConnectionConfig::default());
+
+        let mut stream = handle.open_stream(StreamId(1), 1024).await.unwrap();
+        // The remote has received the handshake before accepting the stream
+        let mut accepted = incoming.next().await.unwrap().unwrap();
+        let message = Bytes::from(vec![7; 8192]);
+        assert_eq!(
+            accepted.send_data(message.clone()),
+            Err(ConnectionError::FrameTooLarge {
+                len: 8192 + frames::DATA_OVERHEAD,
+                max: 4096
+            })
+        );
+        let ext =
+            frames::Extension::new(frames::MIN_EXTENSION_TYPE, StreamId::ZERO, message.clone());
+        let too_large = Err(ConnectionError::FrameTooLarge {
+            len: frames::FRAME_HEAD_LEN as usize + ext.encoded_len(),
+            max: 4096,
+        });
+        assert_eq!(server_handle.send_extension(ext.clone()), too_large);
+        assert_eq!(accepted.send_extension(ext), too_large);
+        accepted.send_message(message.clone()).await.unwrap();
+        match stream.next().await {
+            Some(Ok(Frame::Data(data))) => assert_eq!(data.payload(), message),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+        assert_eq!(stream.metrics().unwrap().inbound.frames, 3);
+    }
+}
@@ -0,0 +1,243 @@
+//! Frame types defined by applications.
+//!
+//! Frame types from `MIN_EXTENSION_TYPE` up are reserved for extensions. Each extension frame
+//! carries a stream id and a payload which the application encodes itself, typically by
+//! implementing `FrameExt` for its contents and using `Extension::encode` and `Extension::decode`.
+//!
+//! Every extension type a connection receives has to be registered in its `ExtensionRegistry`,
+//! which decides whether frames of that type are passed to a handler or delivered to the
+//! `StreamRef` of their stream. Frames of an unregistered type close the connection, unless the
+//! sender flagged them as ignorable.
+
+use crate::connection::{ConnectionError, Framing};
+use crate::protocol::frames::{Extension, Frame};
+use futures::channel::mpsc::Sender;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Callback receiving the extension frames of a registered type.
+///
+/// Handlers are called by the task reading the connection while it is locked, so they must not
+/// block or use the connection's handles; replies are queued through the given `ExtensionSender`.
+pub type ExtensionHandler = Arc<dyn Fn(Extension, &mut ExtensionSender) + Send + Sync>;
+
+/// How the extension frames of a registered type are received
+#[derive(Clone)]
+pub enum Delivery {
+    /// Passed to the handler as soon as they are read
+    Handler(ExtensionHandler),
+    /// Yielded by the `StreamRef` of the frame's stream, in order with its Data frames
+    Stream,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Delivery::Handler(_) => write!(f, "Handler"),
+            Delivery::Stream => write!(f, "Stream"),
+        }
+    }
+}
+
+/// Extension frame types understood by a connection
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionRegistry {
+    types: HashMap<u8, Delivery>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        ExtensionRegistry::default()
+    }
+
+    /// Passes received frames of `frame_type` to `handler`.
+    ///
+    /// Fails if the type lies outside the range reserved for extensions or is already registered.
+    pub fn register_handler<F>(&mut self, frame_type: u8, handler: F) -> Result<(), ConnectionError>
+    where
+        F: Fn(Extension, &mut ExtensionSender) + Send + Sync + 'static,
+    {
+        self.register(frame_type, Delivery::Handler(Arc::new(handler)))
+    }
+
+    /// Delivers received frames of `frame_type` to the `StreamRef` of their stream.
+    ///
+    /// Fails if the type lies outside the range reserved for extensions or is already registered.
+    pub fn register_stream(&mut self, frame_type: u8) -> Result<(), ConnectionError> {
+        self.register(frame_type, Delivery::Stream)
+    }
+
+    fn register(&mut self, frame_type: u8, delivery: Delivery) -> Result<(), ConnectionError> {
+        if !Extension::is_valid_type(frame_type) || self.types.contains_key(&frame_type) {
+            return Err(ConnectionError::InvalidFrameType);
+        }
+        self.types.insert(frame_type, delivery);
+        Ok(())
+    }
+
+    /// Returns how frames of `frame_type` are received, or `None` if the type is unknown
+    pub fn get(&self, frame_type: u8) -> Option<&Delivery> {
+        self.types.get(&frame_type)
+    }
+}
+
+/// Queues extension frames for the remote, from within an `ExtensionHandler`
+pub struct ExtensionSender {
+    outbound: Sender<Frame>,
+    framing: Option<Framing>,
+}
+
+impl ExtensionSender {
+    pub(crate) fn new(outbound: Sender<Frame>, framing: Option<Framing>) -> Self {
+        ExtensionSender { outbound, framing }
+    }
+
+    /// Queues `ext` for the remote.
+    ///
+    /// Fails if its type lies outside the range reserved for extensions, or if it is longer than
+    /// the remote reads.
+    pub fn send(&mut self, ext: Extension) -> Result<(), ConnectionError> {
+        send_extension(&mut self.outbound, self.framing, ext)
+    }
+}
+
+/// Queues `ext` on `outbound` if its type lies within the range reserved for extensions and it
+/// is no longer than `framing` allows
+pub(crate) fn send_extension(
+    outbound: &mut Sender<Frame>,
+    framing: Option<Framing>,
+    ext: Extension,
+) -> Result<(), ConnectionError> {
+    if !Extension::is_valid_type(ext.frame_type) {
+        return Err(ConnectionError::InvalidFrameType);
+    }
+    let frame = Frame::Extension(ext);
+    if let Some(framing) = framing {
+        framing.check_len(framing.frame_len(&frame))?;
+    }
+    outbound.try_send(frame)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{ConnectionConfig, ConnectionHandle};
+    use crate::protocol::frames::{FrameExt, FramingError, MIN_EXTENSION_TYPE};
+    use crate::stream::{IncomingStreams, StreamId};
+    use crate::transport::memory::{self, PipeDriver};
+    use bytes::{Buf, BufMut, Bytes};
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    const ECHO: u8 = MIN_EXTENSION_TYPE;
+    const ECHO_REPLY: u8 = MIN_EXTENSION_TYPE + 1;
+    const WINDOW: u8 = MIN_EXTENSION_TYPE + 2;
+    const UNKNOWN: u8 = MIN_EXTENSION_TYPE + 3;
+
+    /// Contents of an application-defined frame
+    #[derive(Debug, PartialEq)]
+    struct Window(u32);
+
+    impl FrameExt for Window {
+        fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
+            if src.remaining() < 4 {
+                return Err(FramingError::InvalidFrame);
+            }
+            Ok(Window(src.get_u32()))
+        }
+
+        fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
+            dst.put_u32(self.0);
+            Ok(())
+        }
+
+        fn encoded_len(&self) -> usize {
+            4
+        }
+    }
+
+    /// Connects a client whose replies to `ECHO` frames are forwarded to the returned receiver,
+    /// and a server which echoes them and delivers `WINDOW` frames to their streams
+    fn connect() -> (
+        ConnectionHandle,
+        IncomingStreams,
+        PipeDriver,
+        mpsc::UnboundedReceiver<Extension>,
+    ) {
+        let (replies_tx, replies) = mpsc::unbounded();
+        let mut client_cfg = ConnectionConfig::default();
+        client_cfg
+            .extensions
+            .register_handler(ECHO_REPLY, move |ext, _| {
+                let _ = replies_tx.unbounded_send(ext);
+            })
+            .unwrap();
+        let mut server_cfg = ConnectionConfig::default();
+        server_cfg
+            .extensions
+            .register_handler(ECHO, |ext, tx| {
+                tx.send(Extension::new(ECHO_REPLY, ext.stream_id, ext.payload))
+                    .unwrap();
+            })
+            .unwrap();
+        server_cfg.extensions.register_stream(WINDOW).unwrap();
+
+        let (client, mut server) = memory::drivers(client_cfg, server_cfg);
+        let handle = client.handle();
+        tokio::spawn(client);
+        let incoming = server.incoming_streams();
+        (handle, incoming, server, replies)
+    }
+
+    #[test]
+    fn registers_only_unused_extension_types() {
+        let mut registry = ExtensionRegistry::new();
+        assert_eq!(registry.register_stream(ECHO), Ok(()));
+        assert_eq!(
+            registry.register_stream(ECHO),
+            Err(ConnectionError::InvalidFrameType)
+        );
+        assert_eq!(
+            registry.register_handler(0x02, |_, _| ()),
+            Err(ConnectionError::InvalidFrameType)
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_extensions_to_handlers_and_streams() {
+        let (handle, mut incoming, server, mut replies) = connect();
+        tokio::spawn(server);
+
+        let mut stream = handle.open_stream(StreamId(1), 1024).await.unwrap();
+        let window = Extension::encode(WINDOW, StreamId::ZERO, &Window(42)).unwrap();
+        stream.send_extension(window).unwrap();
+        // Skipped by the server, which does not know the type
+        let unknown = Extension::new(UNKNOWN, StreamId::ZERO, Bytes::from("?")).ignorable();
+        handle.send_extension(unknown).unwrap();
+        let echo = Extension::new(ECHO, StreamId::ZERO, Bytes::from("echo"));
+        handle.send_extension(echo).unwrap();
+
+        let mut accepted = incoming.next().await.unwrap().unwrap();
+        match accepted.next().await {
+            Some(Ok(Frame::Extension(ext))) => {
+                assert_eq!(ext.stream_id, StreamId(1));
+                assert_eq!(ext.decode::<Window>().unwrap(), Window(42));
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+        let reply = replies.next().await.unwrap();
+        assert_eq!(reply.frame_type, ECHO_REPLY);
+        assert_eq!(reply.payload, Bytes::from("echo"));
+    }
+
+    #[tokio::test]
+    async fn closes_connection_on_unknown_extension() {
+        let (handle, _incoming, server, _replies) = connect();
+
+        let unknown = Extension::new(UNKNOWN, StreamId::ZERO, Bytes::from("?"));
+        handle.send_extension(unknown).unwrap();
+        assert_eq!(server.await, Err(ConnectionError::UnknownFrame));
+    }
+}
@@ -2,14 +2,25 @@ pub const FC_NUMERATOR: u32 = 1;
 pub const FC_DENOMINATOR: u32 = 2;
 
 // TODO: flow control strategies to allow user to disable FC checks (dynamically, per-stream?)
+/// Whether the Data sent on streams is limited by the credit their receivers grant
 #[derive(Debug, PartialEq, Clone)]
 pub enum FlowControlStrategy {
     Disabled,
+    /// Data frames use up credit for the uncompressed size of their payloads. Returned credit is
+    /// announced to the sender once the given fraction of the capacity is available again.
     CreditBased(FlowControlRatio),
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct FlowControlRatio(u32, u32);
+impl FlowControlStrategy {
+    /// Credit-based flow control with the default credit update threshold
+    pub fn credit_based() -> Self {
+        FlowControlStrategy::CreditBased(FlowControlRatio(FC_NUMERATOR, FC_DENOMINATOR))
+    }
+}
+
+/// Fraction of a stream's credit capacity, as numerator and denominator
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FlowControlRatio(pub u32, pub u32);
 
 #[derive(Debug)]
 pub struct Credits {
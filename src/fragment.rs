@@ -0,0 +1,209 @@
+//! Fragmentation of messages which are larger than a single Data frame.
+//!
+//! `StreamRef::send_message` splits a message into Data frames of at most
+//! `FragmentConfig::fragment_size` bytes, and of at most the stream's available credit if it is
+//! flow controlled. Every fragment but the last one is flagged with `FLAG_MORE_FRAGMENTS`, and
+//! the receiving `StreamRef` yields the reassembled message as a single Data frame.
+//!
+//! Each fragment is queued on the connection on its own, so the frames of other streams are sent
+//! in between the fragments of a large message. Fragments are numbered, compressed and replayed
+//! like any other Data frame.
+
+use crate::connection::ConnectionError;
+use crate::protocol::frames::{Data, FLAG_MORE_FRAGMENTS};
+use bytes::BytesMut;
+
+/// Largest payload sent in a single fragment by default
+pub const DEFAULT_FRAGMENT_SIZE: usize = 16 * 1024;
+/// Largest message reassembled from fragments by default
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Fragmentation of the messages sent and received on a connection's streams
+#[derive(Debug, Clone, PartialEq)]
+pub struct FragmentConfig {
+    /// Largest payload sent in a single fragment
+    pub fragment_size: usize,
+    /// Largest message reassembled from fragments; longer messages fail their stream
+    pub max_message_len: usize,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        FragmentConfig {
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
+/// Fragments of the message currently being received on a stream
+#[derive(Debug, Default)]
+pub struct Reassembly {
+    buf: Option<BytesMut>,
+    /// Set while the remaining fragments of a message which grew too long are dropped
+    discarding: bool,
+}
+
+impl Reassembly {
+    /// Adds a received fragment, returning the message once its last fragment has been added.
+    ///
+    /// Frames which are not fragments are returned as they are. Fails if the message grows
+    /// beyond `max_len`, in which case its remaining fragments are dropped up to and including
+    /// its last one.
+    pub fn push(
+        &mut self,
+        mut data: Data,
+        max_len: usize,
+    ) -> Result<Option<Data>, ConnectionError> {
+        let more = data.flags & FLAG_MORE_FRAGMENTS != 0;
+        if self.discarding {
+            self.discarding = more;
+            return Ok(None);
+        }
+        let mut buf = match self.buf.take() {
+            Some(buf) => buf,
+            None if !more => return Ok(Some(data)),
+            None => BytesMut::new(),
+        };
+        if buf.len() + data.payload_ref().len() > max_len {
+            self.discarding = more;
+            return Err(ConnectionError::MessageTooLarge);
+        }
+        buf.extend_from_slice(data.payload_ref());
+        if more {
+            self.buf = Some(buf);
+            return Ok(None);
+        }
+        // The message takes the sequence number of its last fragment
+        data.flags &= !FLAG_MORE_FRAGMENTS;
+        data.payload = buf.freeze();
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionConfig;
+    use crate::protocol::frames::Frame;
+    use crate::stream::StreamId;
+    use crate::transport::memory;
+    use bytes::Bytes;
+    use futures::{future, stream, StreamExt};
+
+    fn fragment(seq_num: u32, payload: &'static str, more: bool) -> Data {
+        let mut data = Data::new(StreamId(1), seq_num, Bytes::from(payload));
+        if more {
+            data.flags |= FLAG_MORE_FRAGMENTS;
+        }
+        data
+    }
+
+    #[test]
+    fn reassembles_fragments_up_to_maximum_length() {
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly
+            .push(fragment(0, "he", true), 8)
+            .unwrap()
+            .is_none());
+        assert!(reassembly
+            .push(fragment(1, "ll", true), 8)
+            .unwrap()
+            .is_none());
+        let message = reassembly
+            .push(fragment(2, "o", false), 8)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.seq_num, 2);
+        assert_eq!(message.flags, 0);
+        assert_eq!(message.payload(), Bytes::from("hello"));
+
+        let single = reassembly.push(fragment(3, "single", false), 8).unwrap();
+        assert_eq!(single.unwrap().payload(), Bytes::from("single"));
+
+        assert!(reassembly
+            .push(fragment(4, "hello", true), 8)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            reassembly.push(fragment(5, "world", false), 8).err(),
+            Some(ConnectionError::MessageTooLarge)
+        );
+    }
+
+    #[test]
+    fn drops_remaining_fragments_of_overlong_message() {
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly
+            .push(fragment(0, "hello", true), 8)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            reassembly.push(fragment(1, "world", true), 8).err(),
+            Some(ConnectionError::MessageTooLarge)
+        );
+        // Neither the rest of the message nor its end is yielded as a message of its own
+        assert!(reassembly
+            .push(fragment(2, "ab", true), 8)
+            .unwrap()
+            .is_none());
+        assert!(reassembly
+            .push(fragment(3, "c", false), 8)
+            .unwrap()
+            .is_none());
+
+        let next = reassembly.push(fragment(4, "next", false), 8).unwrap();
+        assert_eq!(next.unwrap().payload(), Bytes::from("next"));
+    }
+
+    #[tokio::test]
+    async fn reassembles_messages_sent_alongside_other_streams() {
+        let mut cfg = ConnectionConfig::default();
+        cfg.fragments.fragment_size = 4 * 1024;
+        let (handle, _, mut incoming) = memory::connect(cfg, ConnectionConfig::default());
+
+        let message: Bytes = (0..100 * 1024).map(|i| i as u8).collect::<Vec<_>>().into();
+        let mut large = handle.open_stream(StreamId(1), 1024).await.unwrap();
+        let mut small = handle.open_stream(StreamId(2), 1024).await.unwrap();
+        let chunks = stream::iter(vec![Bytes::from("chunked "), Bytes::from("message")]);
+        let (sent_large, sent_small) = future::join(large.send_message(message.clone()), async {
+            small.send(Bytes::from("small")).await?;
+            small.send_message_stream(chunks).await
+        })
+        .await;
+        sent_large.unwrap();
+        sent_small.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let mut stream = incoming.next().await.unwrap().unwrap();
+            let count = if stream.stream_id() == StreamId(1) {
+                1
+            } else {
+                2
+            };
+            let payloads: Vec<_> = (&mut stream)
+                .take(count)
+                .map(|frame| match frame {
+                    Ok(Frame::Data(data)) => data.payload(),
+                    other => panic!("unexpected frame: {:?}", other),
+                })
+                .collect()
+                .await;
+            let frames = stream.metrics().unwrap().inbound.frames;
+            received.push((stream.stream_id(), frames, payloads));
+        }
+        received.sort_by_key(|(stream_id, ..)| *stream_id);
+        assert_eq!(
+            received,
+            vec![
+                (StreamId(1), 25, vec![message]),
+                (
+                    StreamId(2),
+                    3,
+                    vec![Bytes::from("small"), Bytes::from("chunked message")]
+                ),
+            ]
+        );
+    }
+}
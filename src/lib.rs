@@ -1,28 +1,82 @@
 extern crate bytes;
+extern crate crc32c;
 #[macro_use]
 extern crate futures;
-extern crate tokio_io;
+extern crate hmac;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+extern crate rand;
+#[cfg(all(test, feature = "tls"))]
+extern crate rcgen;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate rustls_pemfile;
+extern crate sha2;
+extern crate socket2;
+extern crate tokio;
+#[cfg(feature = "tls")]
+extern crate tokio_rustls;
+extern crate tokio_util;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[macro_use]
+extern crate tracing;
 
 pub mod bytes_ext {
     pub use bytes::*;
 }
 
+pub mod auth;
+pub mod blocking;
 mod buffer;
+pub mod client;
+pub mod compression;
 pub mod connection;
+pub mod extension;
 pub(crate) mod flow_control;
+pub mod fragment;
+pub mod local;
+pub mod manager;
+pub mod metrics;
 mod protocol;
+pub mod server;
+pub mod socket;
 pub mod stream;
+pub mod transport;
 
-pub use connection::ConnectionDriver;
+pub use crate::auth::AuthConfig;
+pub use crate::blocking::{BlockingConfig, BlockingConnection, BlockingStream};
+pub use crate::client::{Client, ClientConfig, ClientHandle};
+pub use crate::compression::{Codec, CompressionConfig};
+pub use crate::connection::{
+    ConnectionConfig, ConnectionDriver, ConnectionHandle, ConnectionReader, ConnectionWriter,
+    DuplicatePolicy, GapPolicy, ResumeConfig,
+};
+pub use crate::extension::{ExtensionRegistry, ExtensionSender};
+pub use crate::flow_control::{FlowControlRatio, FlowControlStrategy};
+pub use crate::fragment::FragmentConfig;
+pub use crate::local::LocalDriver;
+pub use crate::manager::{ConnectionManager, ManagerConfig};
+pub use crate::server::{Server, ServerConfig};
+pub use crate::socket::SocketConfig;
+pub use crate::transport::{Connector, Listener};
 
 pub mod frames {
-    pub use protocol::frames::Frame;
-    pub use protocol::frames::{Data, FrameHead, StreamRequest};
+    pub use crate::protocol::frames::Frame;
+    pub use crate::protocol::frames::MIN_EXTENSION_TYPE;
+    pub use crate::protocol::frames::{
+        Ack, Auth, Data, Extension, FrameHead, FrameType, Handshake, Resume, StreamRequest,
+    };
+    pub use crate::protocol::frames::{FrameExt, FramingError};
+    pub use crate::protocol::frames::{FLAG_CHECKSUM, FLAG_IGNORABLE, FLAG_MORE_FRAGMENTS};
+    pub use crate::protocol::frames::{FLAG_CODEC_MASK, FLAG_LZ4, FLAG_ZSTD};
 }
 
 // Export codec-specific details
 pub mod codec {
-    pub use protocol::codec::FrameCodec;
+    pub use crate::protocol::codec::writer::WriteError;
+    pub use crate::protocol::codec::FrameCodec;
 }
 
 #[cfg(test)]
@@ -0,0 +1,224 @@
+//! Connections between two ends within the same process.
+//!
+//! Both ends of a local connection are regular connection contexts, so their streams behave
+//! exactly like those of a connection over a transport: the same `ConnectionHandle`,
+//! `IncomingStreams` and `StreamRef`s, the same credit and sequence checks. Instead of encoding
+//! frames with a `FrameWriter` and decoding them with a `FrameReader`, the `LocalDriver` hands
+//! every frame sent by one end directly to the other, so `Data` payloads are never copied.
+
+use crate::connection::next_connection_id;
+use crate::connection::ConnectionConfig;
+use crate::connection::ConnectionContext;
+use crate::connection::ConnectionError;
+use crate::connection::ConnectionHandle;
+use crate::connection::SharedConnectionContext;
+use crate::metrics::Registry;
+use crate::protocol::frames::Frame;
+use crate::stream::IncomingStreams;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// One end of a local connection
+pub type LocalEnd = (ConnectionHandle, IncomingStreams);
+
+/// Creates both ends of a local connection, together with the driver moving frames between them.
+///
+/// There is no transport to lose, so resumption is disabled regardless of `cfg`.
+pub fn pair(cfg: ConnectionConfig) -> (LocalDriver, LocalEnd, LocalEnd) {
+    let mut cfg = cfg;
+    cfg.resumption = None;
+    let left = new_context(cfg.clone());
+    let right = new_context(cfg);
+    let driver = LocalDriver {
+        left: Direction::new(left.clone()),
+        right: Direction::new(right.clone()),
+    };
+    (driver, end(left), end(right))
+}
+
+fn new_context(cfg: ConnectionConfig) -> SharedConnectionContext {
+    let mut ctx = ConnectionContext::with_config(next_connection_id(), cfg);
+    // Frames are handed to the other end as they are, so their length does not matter
+    ctx.set_framing(None);
+    let ctx = Arc::new(Mutex::new(ctx));
+    Registry::global().register(&ctx);
+    ctx
+}
+
+fn end(ctx: SharedConnectionContext) -> LocalEnd {
+    (
+        ConnectionHandle::new(ctx.clone()),
+        IncomingStreams::new(ctx),
+    )
+}
+
+/// Frames sent by one end, on their way to the other
+struct Direction {
+    ctx: SharedConnectionContext,
+    /// Frame which the other end could not accept yet
+    head_of_line: Option<Frame>,
+}
+
+impl Direction {
+    fn new(ctx: SharedConnectionContext) -> Self {
+        Direction {
+            ctx,
+            head_of_line: None,
+        }
+    }
+
+    /// Moves frames to `to` until either end has to wait
+    fn poll_forward(
+        &mut self,
+        to: &SharedConnectionContext,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ConnectionError>> {
+        loop {
+            let frame = match self.head_of_line.take() {
+                Some(frame) => frame,
+                None => {
+                    let mut from = self.ctx.lock().unwrap();
+                    from.conn_task = Some(cx.waker().clone());
+                    match ready!(from.poll_outbound(cx)) {
+                        Some(frame) => frame,
+                        None => return Poll::Ready(Ok(())),
+                    }
+                }
+            };
+            match to.lock().unwrap().deliver(frame, cx) {
+                Ok(None) => (),
+                Ok(Some(frame)) => {
+                    trace!(frame_type = ?frame.frame_type(), "frame blocked at head of line");
+                    self.head_of_line = Some(frame);
+                    return Poll::Pending;
+                }
+                // The other end relies on a frame type this end does not understand
+                Err(ConnectionError::UnknownFrame) => {
+                    return Poll::Ready(Err(ConnectionError::UnknownFrame))
+                }
+                Err(err) => warn!(error = %err, "failed to handle frame"),
+            }
+        }
+    }
+}
+
+/// Future which moves frames between the two ends of a local connection.
+///
+/// Completes once either end is closed, closing the other one too.
+pub struct LocalDriver {
+    left: Direction,
+    right: Direction,
+}
+
+impl LocalDriver {
+    /// Fails both ends with `err`, unless they have failed already
+    fn close(&mut self, err: &ConnectionError) {
+        for ctx in &[&self.left.ctx, &self.right.ctx] {
+            let mut ctx = ctx.lock().unwrap();
+            if !ctx.has_err() {
+                ctx.set_err(err.clone());
+            }
+        }
+    }
+
+    fn err(&self) -> Option<ConnectionError> {
+        let left = self.left.ctx.lock().unwrap().err();
+        left.or_else(|| self.right.ctx.lock().unwrap().err())
+    }
+}
+
+impl Future for LocalDriver {
+    type Output = Result<(), ConnectionError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(err) = this.err() {
+            this.close(&err);
+            return Poll::Ready(match err {
+                ConnectionError::Closed => Ok(()),
+                err => Err(err),
+            });
+        }
+        let right = this.right.ctx.clone();
+        let left = this.left.ctx.clone();
+        let mut forwarded = this.left.poll_forward(&right, cx);
+        if let Poll::Ready(Ok(())) | Poll::Pending = forwarded {
+            forwarded = this.right.poll_forward(&left, cx);
+        }
+        if let Poll::Ready(Err(err)) = forwarded {
+            this.close(&err);
+            return Poll::Ready(Err(err));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for LocalDriver {
+    /// Nothing moves frames between the ends once the driver is gone
+    fn drop(&mut self) {
+        self.close(&ConnectionError::Closed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::frames;
+    use crate::stream::StreamId;
+    use bytes::Bytes;
+    use futures::{StreamExt, TryStreamExt};
+
+    #[tokio::test]
+    async fn streams_between_local_ends() {
+        let (driver, (client, _), (_, mut incoming)) = pair(ConnectionConfig::default());
+        tokio::spawn(async { driver.await.unwrap() });
+
+        let mut stream = client.open_stream(StreamId(1), 1024).await.unwrap();
+        for i in 0..3 {
+            stream
+                .send_data(Bytes::from(format!("frame {}", i)))
+                .unwrap();
+        }
+        let received = incoming.next().await.unwrap().unwrap();
+        let frames: Vec<_> = received.take(3).try_collect().await.unwrap();
+
+        let seqs: Vec<_> = frames
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Data(data) => (data.seq_num, data.payload()),
+                other => panic!("unexpected frame: {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            seqs,
+            vec![
+                (0, Bytes::from("frame 0")),
+                (1, Bytes::from("frame 1")),
+                (2, Bytes::from("frame 2")),
+            ]
+        );
+        let metrics = client.metrics();
+        assert_eq!(metrics.streams[0].outbound.frames, 3);
+    }
+
+    #[tokio::test]
+    async fn sends_frames_longer_than_maximum_frame_length() {
+        let cfg = ConnectionConfig {
+            max_frame_len: frames::MIN_MAX_FRAME_LEN,
+            ..ConnectionConfig::default()
+        };
+        let (driver, (client, _), (_, mut incoming)) = pair(cfg);
+        tokio::spawn(async { driver.await.unwrap() });
+
+        let mut stream = client.open_stream(StreamId(1), 1024).await.unwrap();
+        let payload = Bytes::from(vec![7; 4 * frames::MIN_MAX_FRAME_LEN]);
+        stream.send_data(payload.clone()).unwrap();
+        let mut received = incoming.next().await.unwrap().unwrap();
+        match received.next().await {
+            Some(Ok(Frame::Data(data))) => assert_eq!(data.payload(), payload),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+}
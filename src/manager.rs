@@ -0,0 +1,363 @@
+//! Pool of connections shared by everything which talks to the same peers.
+//!
+//! The `ConnectionManager` lazily starts a reconnecting `Client` the first time a stream is
+//! opened to a peer, and reuses it for every later stream to that peer. Connections which carry no
+//! traffic and have no open streams for the configured idle timeout are closed, as are clients
+//! which gave up reconnecting; the next stream opened to such a peer starts a fresh client.
+
+use crate::client::OpenStream as ClientOpenStream;
+use crate::client::{Client, ClientConfig, ClientHandle, Health};
+use crate::connection::ConnectionError;
+use crate::connection::ConnectionHandle;
+use crate::stream::StreamId;
+use crate::stream::StreamRef;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::time::Instant;
+
+/// Identifies a peer and the address at which it is reached.
+///
+/// Implemented for `SocketAddr`; logical node ids can implement it to resolve their address.
+pub trait PeerKey: Eq + Hash + Clone + Send + Unpin + 'static {
+    fn addr(&self) -> SocketAddr;
+}
+
+impl PeerKey for SocketAddr {
+    fn addr(&self) -> SocketAddr {
+        *self
+    }
+}
+
+/// Configuration of a `ConnectionManager`
+#[derive(Debug, Clone)]
+pub struct ManagerConfig {
+    /// Configuration of the client connecting to each peer
+    pub client: ClientConfig,
+    /// Time after which connections without any traffic are closed, or `None` to keep them open.
+    /// Connections on which streams are still open are kept, however quiet they are.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        ManagerConfig {
+            client: ClientConfig::default(),
+            idle_timeout: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// Client connected to a single peer
+struct Peer {
+    client: ClientHandle,
+    /// Last time the connection was used or carried traffic
+    last_active: Instant,
+    /// Frames sent and received on the connection as of `last_active`
+    frames: u64,
+}
+
+impl Peer {
+    /// Returns the number of frames sent and received on the current connection
+    fn frames(&self) -> u64 {
+        self.client.connection().map_or(0, |conn| {
+            let metrics = conn.metrics();
+            metrics.inbound.total().frames + metrics.outbound.total().frames
+        })
+    }
+
+    /// Returns true if streams are open on the current connection
+    fn has_open_streams(&self) -> bool {
+        self.client
+            .connection()
+            .is_some_and(|conn| conn.open_streams() > 0)
+    }
+
+    /// Returns true if the peer carried no traffic and had no open streams since `deadline`
+    fn is_idle(&mut self, deadline: Instant) -> bool {
+        let frames = self.frames();
+        if frames != self.frames || self.has_open_streams() {
+            self.frames = frames;
+            self.last_active = Instant::now();
+        }
+        self.last_active <= deadline
+    }
+}
+
+struct Inner<K: PeerKey> {
+    cfg: ManagerConfig,
+    peers: Mutex<HashMap<K, Peer>>,
+    /// Set once the task evicting idle connections has been spawned
+    sweeping: AtomicBool,
+}
+
+impl<K: PeerKey> Drop for Inner<K> {
+    fn drop(&mut self) {
+        if let Ok(mut peers) = self.peers.lock() {
+            for (_, peer) in peers.drain() {
+                peer.client.close();
+            }
+        }
+    }
+}
+
+/// Cloneable pool holding one connection per peer
+pub struct ConnectionManager<K: PeerKey = SocketAddr> {
+    inner: Arc<Inner<K>>,
+}
+
+impl<K: PeerKey> Clone for ConnectionManager<K> {
+    fn clone(&self) -> Self {
+        ConnectionManager {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K: PeerKey> ConnectionManager<K> {
+    pub fn new(cfg: ManagerConfig) -> Self {
+        ConnectionManager {
+            inner: Arc::new(Inner {
+                cfg,
+                peers: Mutex::new(HashMap::new()),
+                sweeping: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Returns a future which opens a stream to `peer`, connecting to it first if needed.
+    ///
+    /// Must be polled within a tokio runtime, on which the peer's client is spawned.
+    pub fn open_stream(&self, peer: K, stream_id: StreamId, credit: u32) -> OpenStream<K> {
+        OpenStream {
+            manager: self.clone(),
+            peer,
+            stream_id,
+            credit,
+            open: None,
+        }
+    }
+
+    /// Returns the current connection to `peer`, if it is connected
+    pub fn connection(&self, peer: &K) -> Option<ConnectionHandle> {
+        let peers = self.inner.peers.lock().unwrap();
+        peers.get(peer).and_then(|p| p.client.connection())
+    }
+
+    /// Returns the state of the connection to `peer`, or `None` if the manager has none
+    pub fn health(&self, peer: &K) -> Option<Health> {
+        let peers = self.inner.peers.lock().unwrap();
+        peers.get(peer).map(|p| p.client.health())
+    }
+
+    /// Returns the peers the manager holds connections to
+    pub fn peers(&self) -> Vec<K> {
+        let peers = self.inner.peers.lock().unwrap();
+        peers.keys().cloned().collect()
+    }
+
+    /// Closes the connection to `peer`, returning whether there was one
+    pub fn remove(&self, peer: &K) -> bool {
+        let removed = self.inner.peers.lock().unwrap().remove(peer);
+        match removed {
+            Some(peer) => {
+                peer.client.close();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes idle connections without open streams and forgets failed ones, returning how many
+    /// were removed
+    pub fn evict_idle(&self) -> usize {
+        evict_idle(&self.inner)
+    }
+
+    /// Returns the client connected to `peer`, starting one if there is none or it has failed
+    fn client(&self, peer: &K) -> Result<ClientHandle, ConnectionError> {
+        let mut peers = self.inner.peers.lock().unwrap();
+        if let Some(existing) = peers.get_mut(peer) {
+            if let Health::Failed(_) = existing.client.health() {
+                debug!(peer = %peer.addr(), "replacing failed client");
+            } else {
+                existing.last_active = Instant::now();
+                return Ok(existing.client.clone());
+            }
+        }
+
+        let client = Client::spawn(peer.addr(), self.inner.cfg.client.clone())?;
+        debug!(peer = %peer.addr(), "connecting to new peer");
+        peers.insert(
+            peer.clone(),
+            Peer {
+                client: client.clone(),
+                last_active: Instant::now(),
+                frames: 0,
+            },
+        );
+        drop(peers);
+        self.start_sweeping();
+        Ok(client)
+    }
+
+    /// Spawns the task which periodically evicts idle connections, unless it is running already
+    fn start_sweeping(&self) {
+        let idle_timeout = match self.inner.cfg.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        if self.inner.sweeping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let runtime = match Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => {
+                warn!("could not spawn idle connection eviction");
+                self.inner.sweeping.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
+        let period = idle_timeout / 2;
+        let inner = Arc::downgrade(&self.inner);
+        runtime.spawn(async move {
+            let start = tokio::time::Instant::now() + period;
+            let mut interval = tokio::time::interval_at(start, period);
+            loop {
+                interval.tick().await;
+                if sweep(&inner).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Evicts idle connections until the manager is dropped
+fn sweep<K: PeerKey>(inner: &Weak<Inner<K>>) -> Result<(), ()> {
+    match inner.upgrade() {
+        Some(inner) => {
+            evict_idle(&inner);
+            Ok(())
+        }
+        None => Err(()),
+    }
+}
+
+fn evict_idle<K: PeerKey>(inner: &Inner<K>) -> usize {
+    // Nothing has been idle for longer than the clock has been running
+    let deadline = match inner
+        .cfg
+        .idle_timeout
+        .and_then(|idle_timeout| Instant::now().checked_sub(idle_timeout))
+    {
+        Some(deadline) => deadline,
+        None => return 0,
+    };
+    let mut peers = inner.peers.lock().unwrap();
+    let before = peers.len();
+    peers.retain(|key, peer| match peer.client.health() {
+        Health::Failed(err) => {
+            debug!(peer = %key.addr(), error = %err, "forgetting failed peer");
+            false
+        }
+        _ if peer.is_idle(deadline) => {
+            debug!(peer = %key.addr(), "closing idle connection");
+            peer.client.close();
+            false
+        }
+        _ => true,
+    });
+    before - peers.len()
+}
+
+/// Future which opens a stream to a peer managed by a `ConnectionManager`
+pub struct OpenStream<K: PeerKey> {
+    manager: ConnectionManager<K>,
+    peer: K,
+    stream_id: StreamId,
+    credit: u32,
+    open: Option<ClientOpenStream>,
+}
+
+impl<K: PeerKey> Future for OpenStream<K> {
+    type Output = Result<StreamRef, ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.open.is_none() {
+            let client = self.manager.client(&self.peer)?;
+            self.open = Some(client.open_stream(self.stream_id, self.credit));
+        }
+        Pin::new(self.open.as_mut().unwrap()).poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Server, ServerConfig};
+    use futures::StreamExt;
+    use futures::{executor, future};
+
+    #[tokio::test]
+    async fn shares_one_connection_per_peer() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind(&addr, ServerConfig::default()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let manager: ConnectionManager = ConnectionManager::new(ManagerConfig {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ManagerConfig::default()
+        });
+        let first = manager.open_stream(addr, StreamId(1), 1024);
+        let second = manager.open_stream(addr, StreamId(2), 1024);
+        let (first, second) = future::try_join(first, second).await.unwrap();
+        assert!(Arc::ptr_eq(&first.clone_ctx(), &second.clone_ctx()));
+        assert_eq!(manager.health(&addr), Some(Health::Connected));
+
+        // Both streams reach the server over a single connection
+        let (_, incoming) = server.next().await.unwrap().unwrap();
+        let streams: Vec<_> = incoming.take(2).collect().await;
+        assert_eq!(streams.len(), 2);
+
+        // Open streams keep the connection, however quiet they are
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_millis(600)).await;
+        assert_eq!(manager.evict_idle(), 0);
+        assert_eq!(manager.health(&addr), Some(Health::Connected));
+
+        // Without streams or traffic, the connection is closed once it has been idle for long enough
+        drop((first, second));
+        tokio::time::advance(Duration::from_millis(600)).await;
+        manager.evict_idle();
+        assert_eq!(manager.health(&addr), None);
+        assert!(manager.peers().is_empty());
+    }
+
+    #[test]
+    fn retries_opening_stream_after_failing_to_connect() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = runtime
+            .block_on(Server::bind(&addr, ServerConfig::default()))
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let manager: ConnectionManager = ConnectionManager::new(ManagerConfig::default());
+
+        // Without a runtime, the client cannot be spawned
+        let mut open = manager.open_stream(addr, StreamId(1), 1024);
+        assert_eq!(
+            executor::block_on(&mut open).err(),
+            Some(ConnectionError::General)
+        );
+        let stream = runtime.block_on(open).unwrap();
+        assert_eq!(stream.stream_id(), StreamId(1));
+    }
+}
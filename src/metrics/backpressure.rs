@@ -0,0 +1,190 @@
+//! Accounting of the time spent blocked by backpressure, over a sliding window.
+//!
+//! The window is split into a fixed number of buckets, each accumulating the blocked time which
+//! fell within it. Buckets age out as the window slides forward, so the resulting ratio reflects
+//! recent behaviour rather than the entire lifetime of a stream or connection.
+
+use super::as_nanos;
+use std::time::{Duration, Instant};
+
+/// Length of the sliding window over which blocked time is reported
+pub const BACKPRESSURE_WINDOW: Duration = Duration::from_secs(10);
+/// Number of buckets the window is divided into
+const BUCKETS: usize = 10;
+
+/// Tracks the fraction of time spent blocked within a sliding window
+#[derive(Debug)]
+pub struct BlockedTimer {
+    /// Reference point for bucket boundaries
+    origin: Instant,
+    /// Length of each bucket, in nanoseconds
+    bucket_len: u64,
+    /// Blocked nanoseconds accumulated in each bucket
+    blocked: [u64; BUCKETS],
+    /// Index (counted from `origin`) of the bucket each slot currently holds
+    epochs: [u64; BUCKETS],
+    /// Start of the ongoing blocked period, if any
+    blocked_since: Option<Instant>,
+}
+
+impl Default for BlockedTimer {
+    fn default() -> Self {
+        BlockedTimer::new(BACKPRESSURE_WINDOW)
+    }
+}
+
+impl BlockedTimer {
+    pub fn new(window: Duration) -> Self {
+        BlockedTimer {
+            origin: Instant::now(),
+            bucket_len: (as_nanos(window) / BUCKETS as u64).max(1),
+            blocked: [0; BUCKETS],
+            epochs: [0; BUCKETS],
+            blocked_since: None,
+        }
+    }
+
+    /// Returns whether a blocked period is ongoing
+    pub fn is_blocked(&self) -> bool {
+        self.blocked_since.is_some()
+    }
+
+    /// Starts a blocked period, unless one is already ongoing
+    pub fn block(&mut self) {
+        if self.blocked_since.is_none() {
+            self.blocked_since = Some(Instant::now());
+        }
+    }
+
+    /// Ends the ongoing blocked period, if any
+    pub fn unblock(&mut self) {
+        if let Some(since) = self.blocked_since.take() {
+            let start = as_nanos(since.duration_since(self.origin));
+            let end = as_nanos(self.origin.elapsed());
+            self.record(start, end);
+        }
+    }
+
+    /// Returns the fraction of the window, in `[0, 1]`, which was spent blocked
+    pub fn ratio(&self) -> f64 {
+        let now = as_nanos(self.origin.elapsed());
+        let current = now / self.bucket_len;
+        // The oldest bucket still (partially) within the window
+        let oldest = current.saturating_sub(BUCKETS as u64 - 1);
+
+        let mut blocked: u64 = self
+            .epochs
+            .iter()
+            .zip(self.blocked.iter())
+            .filter(|&(epoch, _)| *epoch >= oldest && *epoch <= current)
+            .map(|(_, nanos)| *nanos)
+            .sum();
+        if let Some(since) = self.blocked_since {
+            let start = as_nanos(since.duration_since(self.origin));
+            blocked += now - start.max(oldest * self.bucket_len);
+        }
+
+        // Timers which have existed for less than a window are measured over their lifetime
+        let observed = (now - oldest * self.bucket_len).max(1);
+        (blocked as f64 / observed as f64).min(1.0)
+    }
+
+    /// Spreads the blocked interval `[start, end)` over the buckets it covers
+    fn record(&mut self, start: u64, end: u64) {
+        let window = self.bucket_len * BUCKETS as u64;
+        // Anything older than the window would be discarded immediately
+        let mut start = start.max(end.saturating_sub(window));
+        while start < end {
+            let epoch = start / self.bucket_len;
+            let bucket_end = ((epoch + 1) * self.bucket_len).min(end);
+            let slot = (epoch % BUCKETS as u64) as usize;
+            if self.epochs[slot] != epoch {
+                self.epochs[slot] = epoch;
+                self.blocked[slot] = 0;
+            }
+            self.blocked[slot] += bucket_end - start;
+            start = bucket_end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    /// A timer with a one second bucket which was created `age` seconds ago
+    fn timer_aged(age: u64) -> BlockedTimer {
+        let mut timer = BlockedTimer::new(Duration::from_secs(BUCKETS as u64));
+        timer.origin = Instant::now() - Duration::from_secs(age);
+        timer
+    }
+
+    fn assert_ratio(timer: &BlockedTimer, expected: f64) {
+        let ratio = timer.ratio();
+        assert!(
+            (ratio - expected).abs() < 0.01,
+            "ratio {} != {}",
+            ratio,
+            expected
+        );
+    }
+
+    #[test]
+    fn accumulates_blocked_periods() {
+        let mut timer = timer_aged(20);
+        assert_ratio(&timer, 0.0);
+        // The window covers the buckets from 11s to now, a little over 9s
+        timer.record(12 * SECOND, 14 * SECOND);
+        timer.record(15 * SECOND, 16 * SECOND);
+        assert_ratio(&timer, 3.0 / 9.0);
+
+        timer.block();
+        assert!(timer.is_blocked());
+        // Blocking again does not restart the ongoing period
+        timer.blocked_since = Some(timer.origin + Duration::from_secs(18));
+        timer.block();
+        assert_ratio(&timer, 5.0 / 9.0);
+        timer.unblock();
+        assert!(!timer.is_blocked());
+        assert_ratio(&timer, 5.0 / 9.0);
+        timer.unblock();
+        assert_ratio(&timer, 5.0 / 9.0);
+    }
+
+    #[test]
+    fn measures_young_timers_over_their_lifetime() {
+        let mut timer = timer_aged(4);
+        timer.record(SECOND, 3 * SECOND);
+        assert_ratio(&timer, 0.5);
+    }
+
+    #[test]
+    fn forgets_blocked_periods_outside_the_window() {
+        let mut timer = timer_aged(20);
+        timer.record(2 * SECOND, 5 * SECOND);
+        assert_ratio(&timer, 0.0);
+
+        // Slots reused by a later bucket drop what the earlier bucket held
+        timer.record(12 * SECOND + SECOND / 2, 13 * SECOND);
+        assert_ratio(&timer, 0.5 / 9.0);
+        assert_eq!(timer.epochs[2], 12);
+        assert_eq!(timer.blocked[2], SECOND / 2);
+    }
+
+    #[test]
+    fn splits_blocked_periods_across_window_boundary() {
+        let mut timer = timer_aged(20);
+        // Only the part from 11s onwards is still within the window
+        timer.record(10 * SECOND + SECOND / 2, 12 * SECOND + SECOND / 2);
+        assert_ratio(&timer, 1.5 / 9.0);
+
+        // An ongoing period which started before the window covers all of it
+        let mut timer = timer_aged(20);
+        timer.blocked_since = Some(timer.origin + Duration::from_secs(5));
+        assert_ratio(&timer, 1.0);
+        timer.unblock();
+        assert_ratio(&timer, 1.0);
+    }
+}
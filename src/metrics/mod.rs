@@ -0,0 +1,438 @@
+//! Counters and gauges describing the health of connections and their streams.
+//!
+//! Connection-wide counters are updated through atomics so that the I/O paths never need to
+//! synchronize with readers of the metrics. Per-stream counters live next to the rest of the
+//! stream's state and are only touched while the connection context is already locked.
+
+use crate::protocol::frames::FrameType;
+use crate::stream::StreamId;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+mod backpressure;
+pub mod prometheus;
+mod registry;
+
+pub use self::backpressure::{BlockedTimer, BACKPRESSURE_WINDOW};
+pub use self::registry::Registry;
+
+/// Number of distinct `FrameType`s, including `FrameType::Unknown` and `FrameType::Extension`
+const FRAME_TYPE_SLOTS: usize = 11;
+
+/// Sentinel for "not currently above the high watermark"
+const NOT_BLOCKED: u64 = u64::MAX;
+
+fn slot(frame_type: FrameType) -> usize {
+    match frame_type {
+        // All extension types share the slot following the core types
+        FrameType::Extension => FRAME_TYPE_SLOTS - 1,
+        frame_type => frame_type as usize - 1,
+    }
+}
+
+/// Inverse of `slot`
+fn slot_type(slot: usize) -> FrameType {
+    match slot {
+        slot if slot == FRAME_TYPE_SLOTS - 1 => FrameType::Extension,
+        slot => FrameType::from(slot as u8 + 1),
+    }
+}
+
+/// Nanoseconds in `duration`, saturating at `u64::MAX`
+fn as_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Lock-free frame and byte counters for a single direction of a connection
+#[derive(Debug, Default)]
+struct TrafficCounters {
+    frames: [AtomicU64; FRAME_TYPE_SLOTS],
+    bytes: [AtomicU64; FRAME_TYPE_SLOTS],
+}
+
+impl TrafficCounters {
+    fn record(&self, frame_type: FrameType, bytes: usize) {
+        let slot = slot(frame_type);
+        self.frames[slot].fetch_add(1, Ordering::Relaxed);
+        self.bytes[slot].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TrafficMetrics {
+        let mut counts = [FrameCount::default(); FRAME_TYPE_SLOTS];
+        for (i, count) in counts.iter_mut().enumerate() {
+            count.frames = self.frames[i].load(Ordering::Relaxed);
+            count.bytes = self.bytes[i].load(Ordering::Relaxed);
+        }
+        TrafficMetrics { counts }
+    }
+}
+
+/// Live statistics of a connection, shared between its context, reader and writer
+#[derive(Debug)]
+pub struct ConnectionStats {
+    /// Reference point for the watermark timestamps below
+    created: Instant,
+    inbound: TrafficCounters,
+    outbound: TrafficCounters,
+    /// Bytes buffered by the writer which have not been written to the network yet
+    pending_bytes: AtomicUsize,
+    /// Number of times the writer has reached its high watermark
+    high_watermark_hits: AtomicU64,
+    /// Nanoseconds spent at the high watermark, excluding the ongoing period
+    high_watermark_nanos: AtomicU64,
+    /// Nanoseconds since `created` at which the ongoing high watermark period started
+    high_watermark_since: AtomicU64,
+    /// Number of times an inbound frame could not be delivered to its stream
+    head_of_line_stalls: AtomicU64,
+    /// Number of times a sender had to wait for stream credit
+    credit_stalls: AtomicU64,
+    /// Number of inbound Data frames which skipped ahead of their stream's sequence
+    sequence_gaps: AtomicU64,
+    /// Number of inbound Data frames whose sequence number had already been received
+    duplicate_frames: AtomicU64,
+    /// Time the connection spent waiting for the writer to drop below its high watermark
+    write_blocked: Mutex<BlockedTimer>,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        ConnectionStats {
+            created: Instant::now(),
+            inbound: TrafficCounters::default(),
+            outbound: TrafficCounters::default(),
+            pending_bytes: AtomicUsize::new(0),
+            high_watermark_hits: AtomicU64::new(0),
+            high_watermark_nanos: AtomicU64::new(0),
+            high_watermark_since: AtomicU64::new(NOT_BLOCKED),
+            head_of_line_stalls: AtomicU64::new(0),
+            credit_stalls: AtomicU64::new(0),
+            sequence_gaps: AtomicU64::new(0),
+            duplicate_frames: AtomicU64::new(0),
+            write_blocked: Mutex::new(BlockedTimer::default()),
+        }
+    }
+}
+
+impl ConnectionStats {
+    pub fn record_inbound(&self, frame_type: FrameType, bytes: usize) {
+        self.inbound.record(frame_type, bytes);
+    }
+
+    pub fn record_outbound(&self, frame_type: FrameType, bytes: usize) {
+        self.outbound.record(frame_type, bytes);
+    }
+
+    pub fn set_pending_bytes(&self, pending_bytes: usize) {
+        self.pending_bytes.store(pending_bytes, Ordering::Relaxed);
+    }
+
+    /// Marks the start of a period at the writer's high watermark
+    pub fn enter_high_watermark(&self) {
+        let now = as_nanos(self.created.elapsed());
+        self.high_watermark_hits.fetch_add(1, Ordering::Relaxed);
+        self.high_watermark_since.store(now, Ordering::Relaxed);
+    }
+
+    /// Marks the end of a period at the writer's high watermark
+    pub fn leave_high_watermark(&self) {
+        let since = self
+            .high_watermark_since
+            .swap(NOT_BLOCKED, Ordering::Relaxed);
+        if since != NOT_BLOCKED {
+            let now = as_nanos(self.created.elapsed());
+            self.high_watermark_nanos
+                .fetch_add(now.saturating_sub(since), Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_head_of_line_stall(&self) {
+        self.head_of_line_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_credit_stall(&self) {
+        self.credit_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sequence_gap(&self) {
+        self.sequence_gaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_duplicate(&self) {
+        self.duplicate_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks the connection as waiting for the writer's buffer to become ready
+    pub fn block_writer(&self) {
+        self.write_blocked.lock().unwrap().block();
+    }
+
+    /// Marks the writer's buffer as ready again
+    pub fn unblock_writer(&self) {
+        let mut timer = self.write_blocked.lock().unwrap();
+        if timer.is_blocked() {
+            timer.unblock();
+        }
+    }
+
+    fn time_at_high_watermark(&self) -> Duration {
+        let mut nanos = self.high_watermark_nanos.load(Ordering::Relaxed);
+        let since = self.high_watermark_since.load(Ordering::Relaxed);
+        if since != NOT_BLOCKED {
+            nanos += as_nanos(self.created.elapsed()).saturating_sub(since);
+        }
+        Duration::from_nanos(nanos)
+    }
+
+    /// Captures the current values of the connection-wide metrics
+    pub fn snapshot(&self, id: u32, streams: Vec<StreamMetrics>) -> ConnectionMetrics {
+        ConnectionMetrics {
+            id,
+            inbound: self.inbound.snapshot(),
+            outbound: self.outbound.snapshot(),
+            pending_bytes: self.pending_bytes.load(Ordering::Relaxed),
+            high_watermark_hits: self.high_watermark_hits.load(Ordering::Relaxed),
+            time_at_high_watermark: self.time_at_high_watermark(),
+            head_of_line_stalls: self.head_of_line_stalls.load(Ordering::Relaxed),
+            credit_stalls: self.credit_stalls.load(Ordering::Relaxed),
+            sequence_gaps: self.sequence_gaps.load(Ordering::Relaxed),
+            duplicate_frames: self.duplicate_frames.load(Ordering::Relaxed),
+            write_blocked_ratio: self.write_blocked.lock().unwrap().ratio(),
+            streams,
+        }
+    }
+}
+
+/// Per-stream counters, updated while holding the connection context's lock
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    pub inbound: FrameCount,
+    pub outbound: FrameCount,
+    pub head_of_line_stalls: u64,
+    pub credit_stalls: u64,
+    pub sequence_gaps: u64,
+    pub duplicates: u64,
+    /// Time the stream's sender spent waiting for credit
+    pub send_blocked: BlockedTimer,
+}
+
+impl StreamStats {
+    pub fn record_inbound(&mut self, bytes: usize) {
+        self.inbound.frames += 1;
+        self.inbound.bytes += bytes as u64;
+    }
+
+    pub fn record_outbound(&mut self, bytes: usize) {
+        self.outbound.frames += 1;
+        self.outbound.bytes += bytes as u64;
+    }
+}
+
+/// Number of frames and their total size in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameCount {
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+/// Frame and byte counts for a single direction of a connection, by frame type
+#[derive(Debug, Clone)]
+pub struct TrafficMetrics {
+    counts: [FrameCount; FRAME_TYPE_SLOTS],
+}
+
+impl TrafficMetrics {
+    /// Returns the counts for frames of type `frame_type`
+    pub fn get(&self, frame_type: FrameType) -> FrameCount {
+        self.counts[slot(frame_type)]
+    }
+
+    /// Returns the counts summed over all frame types
+    pub fn total(&self) -> FrameCount {
+        self.counts
+            .iter()
+            .fold(FrameCount::default(), |acc, c| FrameCount {
+                frames: acc.frames + c.frames,
+                bytes: acc.bytes + c.bytes,
+            })
+    }
+
+    /// Iterates over the counts of every frame type
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (FrameType, FrameCount)> + 'a {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (slot_type(i), *count))
+    }
+}
+
+/// Point-in-time view of a connection's metrics
+#[derive(Debug, Clone)]
+pub struct ConnectionMetrics {
+    pub id: u32,
+    /// Frames and bytes received, by frame type
+    pub inbound: TrafficMetrics,
+    /// Frames and bytes sent, by frame type
+    pub outbound: TrafficMetrics,
+    /// Bytes buffered by the writer which have not been written to the network yet
+    pub pending_bytes: usize,
+    /// Number of times the writer has reached its high watermark
+    pub high_watermark_hits: u64,
+    /// Total time the writer has spent at its high watermark
+    pub time_at_high_watermark: Duration,
+    /// Number of times an inbound frame could not be delivered because its stream was full
+    pub head_of_line_stalls: u64,
+    /// Number of times a sender had to wait for stream credit
+    pub credit_stalls: u64,
+    /// Number of inbound Data frames which skipped ahead of their stream's sequence
+    pub sequence_gaps: u64,
+    /// Number of inbound Data frames whose sequence number had already been received
+    pub duplicate_frames: u64,
+    /// Fraction of the last `BACKPRESSURE_WINDOW` spent waiting for the writer's high watermark
+    pub write_blocked_ratio: f64,
+    /// Metrics of every stream open on the connection
+    pub streams: Vec<StreamMetrics>,
+}
+
+/// Point-in-time view of a stream's metrics
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamMetrics {
+    pub stream_id: StreamId,
+    pub credit_available: u32,
+    pub credit_capacity: u32,
+    /// Data frames and payload bytes received on the stream
+    pub inbound: FrameCount,
+    /// Data frames and payload bytes sent on the stream
+    pub outbound: FrameCount,
+    /// Number of times an inbound frame could not be delivered because the stream was full
+    pub head_of_line_stalls: u64,
+    /// Number of times a sender had to wait for credit on the stream
+    pub credit_stalls: u64,
+    /// Number of inbound Data frames which skipped ahead of the stream's sequence
+    pub sequence_gaps: u64,
+    /// Number of inbound Data frames whose sequence number had already been received
+    pub duplicates: u64,
+    /// Fraction of the last `BACKPRESSURE_WINDOW` the sender spent waiting for credit
+    pub send_blocked_ratio: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn counts_traffic_by_frame_type() {
+        let stats = ConnectionStats::default();
+        stats.record_inbound(FrameType::Data, 100);
+        stats.record_inbound(FrameType::Data, 50);
+        stats.record_inbound(FrameType::Ping, 12);
+        stats.record_inbound(FrameType::Extension, 7);
+        stats.record_outbound(FrameType::Handshake, 30);
+
+        let metrics = stats.snapshot(4, Vec::new());
+        assert_eq!(metrics.id, 4);
+        assert_eq!(
+            metrics.inbound.get(FrameType::Data),
+            FrameCount {
+                frames: 2,
+                bytes: 150
+            }
+        );
+        assert_eq!(
+            metrics.inbound.get(FrameType::Extension),
+            FrameCount {
+                frames: 1,
+                bytes: 7
+            }
+        );
+        assert_eq!(metrics.inbound.get(FrameType::Pong), FrameCount::default());
+        assert_eq!(
+            metrics.inbound.total(),
+            FrameCount {
+                frames: 4,
+                bytes: 169
+            }
+        );
+        assert_eq!(
+            metrics.outbound.total(),
+            FrameCount {
+                frames: 1,
+                bytes: 30
+            }
+        );
+        // Every slot maps back to the frame type it counts
+        for (frame_type, count) in metrics.inbound.iter() {
+            assert_eq!(metrics.inbound.get(frame_type), count);
+        }
+        assert_eq!(metrics.inbound.iter().count(), FRAME_TYPE_SLOTS);
+    }
+
+    #[test]
+    fn snapshots_queue_watermarks() {
+        let stats = ConnectionStats::default();
+        stats.set_pending_bytes(4096);
+        assert_eq!(
+            stats.snapshot(1, Vec::new()).time_at_high_watermark,
+            Duration::from_secs(0)
+        );
+
+        stats.enter_high_watermark();
+        thread::sleep(Duration::from_millis(10));
+        // The ongoing period is included in snapshots
+        let during = stats.snapshot(1, Vec::new());
+        assert_eq!(during.high_watermark_hits, 1);
+        assert!(during.time_at_high_watermark >= Duration::from_millis(10));
+
+        stats.leave_high_watermark();
+        let after = stats.snapshot(1, Vec::new()).time_at_high_watermark;
+        thread::sleep(Duration::from_millis(5));
+        let metrics = stats.snapshot(1, Vec::new());
+        assert_eq!(metrics.pending_bytes, 4096);
+        assert_eq!(metrics.high_watermark_hits, 1);
+        assert_eq!(metrics.time_at_high_watermark, after);
+
+        // Leaving without having entered is a no-op
+        stats.leave_high_watermark();
+        assert_eq!(stats.snapshot(1, Vec::new()).time_at_high_watermark, after);
+    }
+
+    #[test]
+    fn snapshots_stall_counters() {
+        let stats = ConnectionStats::default();
+        stats.record_head_of_line_stall();
+        stats.record_credit_stall();
+        stats.record_credit_stall();
+        stats.record_sequence_gap();
+        stats.record_duplicate();
+        stats.record_duplicate();
+        stats.record_duplicate();
+        let stream = StreamMetrics {
+            stream_id: StreamId(1),
+            credit_available: 0,
+            credit_capacity: 16,
+            inbound: FrameCount::default(),
+            outbound: FrameCount::default(),
+            head_of_line_stalls: 0,
+            credit_stalls: 2,
+            sequence_gaps: 0,
+            duplicates: 0,
+            send_blocked_ratio: 0.0,
+        };
+
+        let metrics = stats.snapshot(1, vec![stream.clone()]);
+        assert_eq!(metrics.head_of_line_stalls, 1);
+        assert_eq!(metrics.credit_stalls, 2);
+        assert_eq!(metrics.sequence_gaps, 1);
+        assert_eq!(metrics.duplicate_frames, 3);
+        assert_eq!(metrics.write_blocked_ratio, 0.0);
+        assert_eq!(metrics.streams, vec![stream]);
+    }
+
+    #[test]
+    fn saturates_durations_which_overflow_nanoseconds() {
+        assert_eq!(as_nanos(Duration::new(2, 5)), 2_000_000_005);
+        assert_eq!(as_nanos(Duration::from_secs(u64::MAX)), u64::MAX);
+    }
+}
@@ -0,0 +1,361 @@
+//! Rendering of connection metrics in the Prometheus text exposition format.
+//!
+//! Connection-wide series are labelled with `connection_id`, and per-stream series additionally
+//! with `stream_id`. Frame counters carry a `frame_type` label.
+
+use crate::metrics::{ConnectionMetrics, FrameCount, Registry, StreamMetrics, TrafficMetrics};
+use crate::protocol::frames::FrameType;
+use std::fmt::Write;
+
+/// Renders the metrics of every live connection in `registry`
+pub fn render(registry: &Registry) -> String {
+    render_metrics(&registry.snapshot())
+}
+
+/// Renders the metrics of `connections`
+pub fn render_metrics(connections: &[ConnectionMetrics]) -> String {
+    let mut out = String::new();
+
+    traffic_family(
+        &mut out,
+        "spaniel_frames_received_total",
+        "Frames received, by frame type.",
+        connections,
+        |c| &c.inbound,
+        |count| count.frames,
+    );
+    traffic_family(
+        &mut out,
+        "spaniel_received_bytes_total",
+        "Bytes received, by frame type.",
+        connections,
+        |c| &c.inbound,
+        |count| count.bytes,
+    );
+    traffic_family(
+        &mut out,
+        "spaniel_frames_sent_total",
+        "Frames sent, by frame type.",
+        connections,
+        |c| &c.outbound,
+        |count| count.frames,
+    );
+    traffic_family(
+        &mut out,
+        "spaniel_sent_bytes_total",
+        "Bytes sent, by frame type.",
+        connections,
+        |c| &c.outbound,
+        |count| count.bytes,
+    );
+
+    connection_family(
+        &mut out,
+        "spaniel_writer_pending_bytes",
+        "gauge",
+        "Bytes buffered by the writer which have not been written yet.",
+        connections,
+        |c| c.pending_bytes.to_string(),
+    );
+    connection_family(
+        &mut out,
+        "spaniel_writer_high_watermark_hits_total",
+        "counter",
+        "Number of times the writer reached its high watermark.",
+        connections,
+        |c| c.high_watermark_hits.to_string(),
+    );
+    connection_family(
+        &mut out,
+        "spaniel_writer_high_watermark_seconds_total",
+        "counter",
+        "Time the writer has spent at its high watermark.",
+        connections,
+        |c| {
+            let t = c.time_at_high_watermark;
+            (t.as_secs() as f64 + f64::from(t.subsec_nanos()) / 1e9).to_string()
+        },
+    );
+    connection_family(
+        &mut out,
+        "spaniel_writer_blocked_ratio",
+        "gauge",
+        "Fraction of the backpressure window spent waiting for the writer's high watermark.",
+        connections,
+        |c| c.write_blocked_ratio.to_string(),
+    );
+    connection_family(
+        &mut out,
+        "spaniel_head_of_line_stalls_total",
+        "counter",
+        "Inbound frames which could not be delivered because their stream was full.",
+        connections,
+        |c| c.head_of_line_stalls.to_string(),
+    );
+    connection_family(
+        &mut out,
+        "spaniel_credit_stalls_total",
+        "counter",
+        "Number of times a sender had to wait for stream credit.",
+        connections,
+        |c| c.credit_stalls.to_string(),
+    );
+    connection_family(
+        &mut out,
+        "spaniel_sequence_gaps_total",
+        "counter",
+        "Inbound Data frames which skipped ahead of their stream's sequence.",
+        connections,
+        |c| c.sequence_gaps.to_string(),
+    );
+    connection_family(
+        &mut out,
+        "spaniel_duplicate_frames_total",
+        "counter",
+        "Inbound Data frames whose sequence number had already been received.",
+        connections,
+        |c| c.duplicate_frames.to_string(),
+    );
+
+    stream_family(
+        &mut out,
+        "spaniel_stream_credit_available",
+        "gauge",
+        "Credit currently available on the stream.",
+        connections,
+        |s| s.credit_available.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_credit_capacity",
+        "gauge",
+        "Credit capacity of the stream.",
+        connections,
+        |s| s.credit_capacity.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_frames_received_total",
+        "counter",
+        "Data frames received on the stream.",
+        connections,
+        |s| s.inbound.frames.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_received_bytes_total",
+        "counter",
+        "Payload bytes received on the stream.",
+        connections,
+        |s| s.inbound.bytes.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_frames_sent_total",
+        "counter",
+        "Data frames sent on the stream.",
+        connections,
+        |s| s.outbound.frames.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_sent_bytes_total",
+        "counter",
+        "Payload bytes sent on the stream.",
+        connections,
+        |s| s.outbound.bytes.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_head_of_line_stalls_total",
+        "counter",
+        "Inbound frames which could not be delivered because the stream was full.",
+        connections,
+        |s| s.head_of_line_stalls.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_credit_stalls_total",
+        "counter",
+        "Number of times a sender had to wait for credit on the stream.",
+        connections,
+        |s| s.credit_stalls.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_sequence_gaps_total",
+        "counter",
+        "Inbound Data frames which skipped ahead of the stream's sequence.",
+        connections,
+        |s| s.sequence_gaps.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_duplicate_frames_total",
+        "counter",
+        "Inbound Data frames whose sequence number had already been received on the stream.",
+        connections,
+        |s| s.duplicates.to_string(),
+    );
+    stream_family(
+        &mut out,
+        "spaniel_stream_send_blocked_ratio",
+        "gauge",
+        "Fraction of the backpressure window the sender spent waiting for credit.",
+        connections,
+        |s| s.send_blocked_ratio.to_string(),
+    );
+
+    out
+}
+
+fn frame_type_label(frame_type: FrameType) -> &'static str {
+    match frame_type {
+        FrameType::StreamRequest => "stream_request",
+        FrameType::Data => "data",
+        FrameType::CreditUpdate => "credit_update",
+        FrameType::Ping => "ping",
+        FrameType::Pong => "pong",
+        FrameType::Handshake => "handshake",
+        FrameType::Resume => "resume",
+        FrameType::Ack => "ack",
+        FrameType::Auth => "auth",
+        FrameType::Extension => "extension",
+        FrameType::Unknown => "unknown",
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn traffic_family<T, V>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    connections: &[ConnectionMetrics],
+    traffic: T,
+    value: V,
+) where
+    T: Fn(&ConnectionMetrics) -> &TrafficMetrics,
+    V: Fn(FrameCount) -> u64,
+{
+    header(out, name, "counter", help);
+    for conn in connections {
+        for (frame_type, count) in traffic(conn).iter() {
+            let _ = writeln!(
+                out,
+                "{}{{connection_id=\"{}\",frame_type=\"{}\"}} {}",
+                name,
+                conn.id,
+                frame_type_label(frame_type),
+                value(count)
+            );
+        }
+    }
+}
+
+fn connection_family<V>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    connections: &[ConnectionMetrics],
+    value: V,
+) where
+    V: Fn(&ConnectionMetrics) -> String,
+{
+    header(out, name, kind, help);
+    for conn in connections {
+        let _ = writeln!(
+            out,
+            "{}{{connection_id=\"{}\"}} {}",
+            name,
+            conn.id,
+            value(conn)
+        );
+    }
+}
+
+fn stream_family<V>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    connections: &[ConnectionMetrics],
+    value: V,
+) where
+    V: Fn(&StreamMetrics) -> String,
+{
+    header(out, name, kind, help);
+    for conn in connections {
+        for stream in &conn.streams {
+            let _ = writeln!(
+                out,
+                "{}{{connection_id=\"{}\",stream_id=\"{}\"}} {}",
+                name,
+                conn.id,
+                stream.stream_id.0,
+                value(stream)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{ConnectionStats, FrameCount};
+    use crate::stream::StreamId;
+
+    #[test]
+    fn renders_text_exposition_format() {
+        let stats = ConnectionStats::default();
+        stats.record_inbound(FrameType::Data, 20);
+        stats.record_inbound(FrameType::Data, 10);
+        stats.record_outbound(FrameType::Ping, 14);
+        stats.set_pending_bytes(128);
+        let stream = StreamMetrics {
+            stream_id: StreamId(3),
+            credit_available: 512,
+            credit_capacity: 1024,
+            inbound: FrameCount::default(),
+            outbound: FrameCount {
+                frames: 4,
+                bytes: 64,
+            },
+            head_of_line_stalls: 0,
+            credit_stalls: 1,
+            sequence_gaps: 0,
+            duplicates: 0,
+            send_blocked_ratio: 0.0,
+        };
+        let rendered = render_metrics(&[stats.snapshot(7, vec![stream])]);
+        let lines: Vec<_> = rendered.lines().collect();
+
+        for expected in &[
+            "# HELP spaniel_frames_received_total Frames received, by frame type.",
+            "# TYPE spaniel_frames_received_total counter",
+            "spaniel_frames_received_total{connection_id=\"7\",frame_type=\"data\"} 2",
+            "spaniel_received_bytes_total{connection_id=\"7\",frame_type=\"data\"} 30",
+            "spaniel_frames_sent_total{connection_id=\"7\",frame_type=\"ping\"} 1",
+            "spaniel_frames_sent_total{connection_id=\"7\",frame_type=\"data\"} 0",
+            "# TYPE spaniel_writer_pending_bytes gauge",
+            "spaniel_writer_pending_bytes{connection_id=\"7\"} 128",
+            "# TYPE spaniel_stream_credit_available gauge",
+            "spaniel_stream_credit_available{connection_id=\"7\",stream_id=\"3\"} 512",
+            "spaniel_stream_sent_bytes_total{connection_id=\"7\",stream_id=\"3\"} 64",
+            "spaniel_stream_credit_stalls_total{connection_id=\"7\",stream_id=\"3\"} 1",
+        ] {
+            assert!(lines.contains(expected), "missing line: {}", expected);
+        }
+        // Every family is introduced by its HELP and TYPE lines, before any of its samples
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                let name = help.split(' ').next().unwrap();
+                assert!(lines[i + 1].starts_with(&format!("# TYPE {} ", name)));
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Registry of live connections whose metrics can be collected together.
+
+use crate::connection::ConnectionContext;
+use crate::connection::SharedConnectionContext;
+use crate::metrics::ConnectionMetrics;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::Weak;
+
+static GLOBAL: OnceLock<Registry> = OnceLock::new();
+
+/// Tracks connections without keeping them alive.
+///
+/// Connections are dropped from the registry once their context is dropped or the connection has
+/// failed.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    connections: Arc<Mutex<Vec<Weak<Mutex<ConnectionContext>>>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    /// Returns the process-wide registry in which every `ConnectionDriver` registers itself
+    pub fn global() -> &'static Registry {
+        GLOBAL.get_or_init(Registry::new)
+    }
+
+    /// Adds the connection to the registry, forgetting connections which have been dropped
+    pub fn register(&self, ctx: &SharedConnectionContext) {
+        let mut connections = self.connections.lock().unwrap();
+        // Registries which are never scraped would otherwise grow with every connection
+        connections.retain(|conn| conn.strong_count() > 0);
+        connections.push(Arc::downgrade(ctx));
+    }
+
+    /// Returns the metrics of every live connection, ordered by connection id
+    pub fn snapshot(&self) -> Vec<ConnectionMetrics> {
+        let mut snapshot = Vec::new();
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|conn| match conn.upgrade() {
+            None => false,
+            Some(ctx) => {
+                let ctx = ctx.lock().unwrap();
+                if ctx.has_err() {
+                    return false;
+                }
+                snapshot.push(ctx.metrics());
+                true
+            }
+        });
+        snapshot.sort_by_key(|m| m.id);
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_dropped_connections_when_registering() {
+        let registry = Registry::new();
+        let first = Arc::new(Mutex::new(ConnectionContext::new(1)));
+        let second = Arc::new(Mutex::new(ConnectionContext::new(2)));
+        registry.register(&first);
+        registry.register(&second);
+        drop(first);
+
+        let third = Arc::new(Mutex::new(ConnectionContext::new(3)));
+        registry.register(&third);
+        assert_eq!(registry.connections.lock().unwrap().len(), 2);
+        let ids: Vec<_> = registry.snapshot().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+}
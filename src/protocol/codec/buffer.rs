@@ -1,13 +1,12 @@
 //! Buffer responsible for collecting and emitting buffers before they're sent over the network
 
-use bytes::IntoBuf;
 use std::collections::VecDeque;
 
-pub struct OutboundBuffer<B: IntoBuf> {
+pub struct OutboundBuffer<B> {
     buf: VecDeque<B>,
 }
 
-impl<B: IntoBuf> OutboundBuffer<B> {
+impl<B> OutboundBuffer<B> {
     pub fn with_capacity(capacity: usize) -> Self {
         OutboundBuffer {
             buf: VecDeque::with_capacity(capacity),
@@ -18,6 +17,7 @@ impl<B: IntoBuf> OutboundBuffer<B> {
         self.buf.push_back(value);
     }
 
+    #[allow(dead_code)]
     pub fn push_front(&mut self, value: B) {
         self.buf.push_front(value);
     }
@@ -26,15 +26,12 @@ impl<B: IntoBuf> OutboundBuffer<B> {
         self.push_back(value);
     }
 
+    /// Removes the oldest buffer, so that buffers are written in the order they were added
     pub fn next(&mut self) -> Option<B> {
-        self.buf.pop_back()
+        self.buf.pop_front()
     }
 
-    pub fn next_buf(&mut self) -> Option<B::Buf> {
-        if let Some(bytes) = self.next() {
-            return Some(bytes.into_buf());
-        } else {
-            return None;
-        }
+    pub fn next_buf(&mut self) -> Option<B> {
+        self.next()
     }
 }
@@ -1,26 +1,31 @@
-use bytes::Bytes;
-use bytes::BytesMut;
-use futures::Async;
-use futures::AsyncSink;
-use futures::Poll;
+use crate::protocol::codec::reader::verify_checksum;
+use crate::protocol::codec::writer::{encode_frame, WriteError};
+use crate::protocol::frames;
+use crate::protocol::frames::Frame;
+use crate::protocol::frames::FramingError;
+use bytes::{Buf, Bytes};
 use futures::Sink;
-use futures::StartSend;
 use futures::Stream;
-use protocol::frames::Frame;
-use protocol::frames::FrameHead;
-use tokio_io::codec::length_delimited::{self, Framed};
-use tokio_io::AsyncRead;
-use tokio_io::AsyncWrite;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 mod buffer;
 pub mod reader;
 pub mod writer;
 
+/// Reads and writes frames over a transport, validating them like a connection's reader and
+/// writer do
 pub struct FrameCodec<T>
 where
     T: AsyncRead + AsyncWrite,
 {
-    inner: Framed<T, Bytes>,
+    inner: Framed<T, LengthDelimitedCodec>,
+    /// Longest frame read or written, including its length prefix
+    max_frame_len: usize,
+    /// Whether frames are written with, and must be read with, a checksum trailer
+    checksums: bool,
 }
 
 impl<T> FrameCodec<T>
@@ -28,70 +33,124 @@ where
     T: AsyncRead + AsyncWrite,
 {
     pub fn new(conn: T) -> Self {
+        FrameCodec::with_max_frame_len(conn, frames::DEFAULT_MAX_FRAME_LEN)
+    }
+
+    /// Creates a codec which fails upon reading or writing a frame longer than `max_frame_len`
+    pub fn with_max_frame_len(conn: T, max_frame_len: usize) -> Self {
         Self {
-            inner: length_delimited::Builder::new()
+            inner: LengthDelimitedCodec::builder()
                 .big_endian()
                 .length_adjustment(-4)
                 .length_field_offset(0)
                 .length_field_length(4)
-                .max_frame_length(::std::u32::MAX as usize)
+                .max_frame_length(max_frame_len)
                 .new_framed(conn),
+            max_frame_len,
+            checksums: false,
         }
     }
+
+    /// Appends a checksum to subsequently written frames, and requires one on subsequently read
+    /// frames, if `enabled`
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.checksums = enabled;
+    }
 }
 
 impl<T> Stream for FrameCodec<T>
 where
-    T: AsyncRead + AsyncWrite,
+    T: AsyncRead + AsyncWrite + Unpin,
 {
-    type Item = Frame;
-    type Error = (); // TODO err
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let frame = match try_ready!(self.inner.poll().map_err(|_err| {
-            // TODO err
-            ()
-        })) {
-            Some(buf) => Some(Frame::decode_from(buf).expect("deserialization")),
-            None => None,
+    type Item = Result<Frame, FramingError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut bytes = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+            None => return Poll::Ready(None),
         };
-        Ok(Async::Ready(frame))
+        let frame =
+            verify_checksum(&mut bytes, self.checksums).and_then(|()| Frame::decode_from(bytes));
+        Poll::Ready(Some(frame))
     }
 }
 
-impl<T> Sink for FrameCodec<T>
+impl<T> Sink<Option<Frame>> for FrameCodec<T>
 where
-    T: AsyncRead + AsyncWrite,
+    T: AsyncRead + AsyncWrite + Unpin,
 {
-    // TODO err
-    type SinkItem = Option<Frame>;
-    type SinkError = ();
+    type Error = WriteError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WriteError>> {
+        Sink::<Bytes>::poll_ready(Pin::new(&mut self.inner), cx).map_err(|_| WriteError::Io)
+    }
 
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+    fn start_send(mut self: Pin<&mut Self>, item: Option<Frame>) -> Result<(), WriteError> {
         match item {
-            None => {
-                return Ok(AsyncSink::Ready);
-            }
+            None => Ok(()),
             Some(frame) => {
-                // TODO buffer provider
-                let size = FrameHead::encoded_len() + frame.encoded_len();
-                let mut buf = BytesMut::with_capacity(size);
-                frame.encode_into(&mut buf).expect("serialization");
-
-                match self.inner.start_send(buf.freeze()) {
-                    Ok(AsyncSink::NotReady(_)) => Ok(AsyncSink::NotReady(Some(frame))),
-                    Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
-                    Err(_err) => Err(()),
-                }
+                let mut buf = encode_frame(&frame, self.checksums, self.max_frame_len)?;
+                // The length prefix is written by the `length_delimited` encoder
+                buf.advance(4);
+                Pin::new(&mut self.inner)
+                    .start_send(buf.freeze())
+                    .map_err(|_| WriteError::Io)
             }
         }
     }
 
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.inner.poll_complete().map_err(|_| ()) // TODO err
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WriteError>> {
+        Sink::<Bytes>::poll_flush(Pin::new(&mut self.inner), cx).map_err(|_| WriteError::Io)
     }
 
-    fn close(&mut self) -> Poll<(), Self::SinkError> {
-        self.poll_complete()
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WriteError>> {
+        Sink::<Bytes>::poll_close(Pin::new(&mut self.inner), cx).map_err(|_| WriteError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::frames::Data;
+    use crate::stream::StreamId;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn fails_on_malformed_frames() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut codec = FrameCodec::new(client);
+        // A length prefix followed by a frame with an invalid magic number
+        server
+            .write_all(&[0, 0, 0, 10, 0, 0, 0, 0, 0x01, 0])
+            .await
+            .unwrap();
+        match codec.next().await {
+            Some(Err(FramingError::InvalidMagicNum)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_checksums() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut sender = FrameCodec::new(client);
+        let mut receiver = FrameCodec::new(server);
+        receiver.set_checksums(true);
+
+        let data = || Frame::Data(Data::new(StreamId(1), 0, Bytes::from("payload")));
+        sender.send(Some(data())).await.unwrap();
+        match receiver.next().await {
+            Some(Err(FramingError::ChecksumMismatch)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        sender.set_checksums(true);
+        sender.send(Some(data())).await.unwrap();
+        match receiver.next().await {
+            Some(Ok(Frame::Data(data))) => assert_eq!(data.payload(), Bytes::from("payload")),
+            other => panic!("unexpected result: {:?}", other),
+        }
     }
 }
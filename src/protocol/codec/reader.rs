@@ -1,28 +1,57 @@
-use bytes::BytesMut;
-use futures::Async;
-use futures::Poll;
+use crate::metrics::ConnectionStats;
+use crate::protocol::frames;
+use crate::protocol::frames::Frame;
+use crate::protocol::frames::FramingError;
+use bytes::{Buf, BytesMut};
 use futures::Stream;
-use protocol::frames::Frame;
-use protocol::frames::FramingError;
-use tokio_io::codec::length_delimited;
-use tokio_io::AsyncRead;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::AsyncRead;
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
 
 /// Reads and decodes frames from the underlying `Stream`
 pub struct FrameReader<T> {
-    src: length_delimited::FramedRead<T>,
+    src: FramedRead<T, LengthDelimitedCodec>,
+    /// Counters for the frames read
+    stats: Arc<ConnectionStats>,
+    /// Whether each frame ends with a checksum trailer to verify
+    checksums: bool,
 }
 
 // impl FrameRader
-impl<T: AsyncRead> FrameReader<T> {
-    /// Creates a new FrameReader backed by a length-delimited wire protocol
-    pub fn new(src: T) -> Self {
-        let src = length_delimited::Builder::new()
+impl<T: AsyncRead + Unpin> FrameReader<T> {
+    /// Creates a new FrameReader backed by a length-delimited wire protocol, recording the frames
+    /// it reads in `stats`
+    pub fn new(src: T, stats: Arc<ConnectionStats>) -> Self {
+        let src = LengthDelimitedCodec::builder()
             .big_endian()
             .length_adjustment(-4)
             .length_field_offset(0)
             .length_field_length(4)
+            .max_frame_length(frames::DEFAULT_MAX_FRAME_LEN)
             .new_read(src);
-        FrameReader { src }
+        FrameReader {
+            src,
+            stats,
+            checksums: false,
+        }
+    }
+
+    /// Records subsequently read frames in `stats`
+    pub fn set_stats(&mut self, stats: Arc<ConnectionStats>) {
+        self.stats = stats;
+    }
+
+    /// Fails upon reading a frame longer than `max_frame_len`, including its length prefix and
+    /// checksum, before buffering it
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.src.decoder_mut().set_max_frame_length(max_frame_len);
+    }
+
+    /// Requires subsequently read frames to carry a checksum trailer if `enabled`
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.checksums = enabled;
     }
 
     /// Decodes a `Frame` object from the provided `bytes`.
@@ -34,29 +63,155 @@ impl<T: AsyncRead> FrameReader<T> {
     }
 
     /// Attempts to extract bytes into a `Frame` from the underlying `AsyncRead`.
-    pub fn poll_frame(&mut self) -> Poll<Option<Frame>, FramingError> {
-        // Extract bytes from underlying source, delegating Async::NotReady responsibility to it
-        let bytes_res = try_ready!(self.src.poll().map_err(|err| FramingError::Io(err)));
+    pub fn poll_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Frame>, FramingError>> {
+        // Extract bytes from underlying source, delegating Poll::Pending responsibility to it
+        let bytes_res = ready!(Pin::new(&mut self.src).poll_next(cx)).transpose()?;
 
         match bytes_res {
-            Some(bytes) => {
+            Some(mut bytes) => {
+                // Account for the length field stripped by the `length_delimited` decoder
+                let len = bytes.len() + 4;
+                if let Err(err) = verify_checksum(&mut bytes, self.checksums) {
+                    warn!(len, "frame failed checksum verification");
+                    return Poll::Ready(Err(err));
+                }
                 let frame = self.decode_frame(bytes)?;
-                Ok(Async::Ready(Some(frame)))
+                self.stats.record_inbound(frame.frame_type(), len);
+                Poll::Ready(Ok(Some(frame)))
             }
             None => {
                 // Underlying codec is closed; need to propagate this upwards
-                Ok(Async::Ready(None))
+                Poll::Ready(Ok(None))
             }
         }
     }
 }
 
+/// Strips the checksum trailer from `bytes`, failing if it does not match the frame.
+///
+/// Frames without a checksum are only accepted while checksums are not `required`.
+pub(crate) fn verify_checksum(bytes: &mut BytesMut, required: bool) -> Result<(), FramingError> {
+    if bytes.len() <= frames::FLAGS_OFFSET {
+        return Err(FramingError::BufferCapacity);
+    }
+    if bytes[frames::FLAGS_OFFSET] & frames::FLAG_CHECKSUM == 0 {
+        if required {
+            return Err(FramingError::ChecksumMismatch);
+        }
+        return Ok(());
+    }
+    if bytes.len() < frames::FLAGS_OFFSET + 1 + frames::CHECKSUM_LEN {
+        return Err(FramingError::InvalidFrame);
+    }
+    let expected = bytes
+        .split_off(bytes.len() - frames::CHECKSUM_LEN)
+        .get_u32();
+    if frames::checksum(bytes) != expected {
+        return Err(FramingError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
 /// Continuous `Frame` stream wrapper around the `FrameReader`
-impl<T: AsyncRead> Stream for FrameReader<T> {
-    type Item = Frame;
-    type Error = FramingError;
+impl<T: AsyncRead + Unpin> Stream for FrameReader<T> {
+    type Item = Result<Frame, FramingError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_frame(cx).map(Result::transpose)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::codec::writer::{FrameWriter, WriteError};
+    use crate::stream::StreamId;
+    use bytes::Bytes;
+    use futures::future;
+    use std::io;
+
+    #[tokio::test]
+    async fn rejects_corrupted_frames() {
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.set_checksums(true);
+        let data = frames::Data::new(StreamId(1), 0, Bytes::from("payload"));
+        writer.buffer_frame(Frame::Data(data)).unwrap();
+        future::poll_fn(|cx| writer.poll_flush(cx)).await.unwrap();
+        let mut encoded = writer.into_inner();
+        // Flip a bit of the payload
+        let last = encoded.len() - frames::CHECKSUM_LEN - 1;
+        encoded[last] ^= 0x01;
+
+        let mut reader = FrameReader::new(&encoded[..], Arc::new(ConnectionStats::default()));
+        reader.set_checksums(true);
+        match future::poll_fn(|cx| reader.poll_frame(cx)).await {
+            Err(FramingError::ChecksumMismatch) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn carries_frame_flags() {
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.set_checksums(true);
+        let mut data = frames::Data::new(StreamId(1), 0, Bytes::from("payload"));
+        data.flags = 0x80;
+        writer.buffer_frame(Frame::Data(data)).unwrap();
+        future::poll_fn(|cx| writer.poll_flush(cx)).await.unwrap();
+        let encoded = writer.into_inner();
+
+        let mut reader = FrameReader::new(&encoded[..], Arc::new(ConnectionStats::default()));
+        reader.set_checksums(true);
+        let frame = future::poll_fn(|cx| reader.poll_frame(cx)).await.unwrap();
+        let frame = frame.unwrap();
+        assert_eq!(frame.flags(), 0x80);
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.poll_frame()
+        // Forwarded to a connection without checksums, the frame is written without a trailer
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.buffer_frame(frame).unwrap();
+        future::poll_fn(|cx| writer.poll_flush(cx)).await.unwrap();
+        let encoded = writer.into_inner();
+        let mut reader = FrameReader::new(&encoded[..], Arc::new(ConnectionStats::default()));
+        let frame = future::poll_fn(|cx| reader.poll_frame(cx)).await.unwrap();
+        match frame.unwrap() {
+            Frame::Data(data) => {
+                assert_eq!(data.flags, 0x80);
+                assert_eq!(data.payload(), Bytes::from("payload"));
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn enforces_maximum_frame_length() {
+        let data = || {
+            Frame::Data(frames::Data::new(
+                StreamId(1),
+                0,
+                Bytes::from(vec![0; 2048]),
+            ))
+        };
+        let mut writer = FrameWriter::new(Vec::new());
+        writer.set_max_frame_len(2048);
+        match writer.buffer_frame(data()) {
+            Err(WriteError::FrameTooLarge { len, max: 2048 }) => {
+                assert_eq!(len, 2048 + frames::DATA_OVERHEAD)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        writer.set_max_frame_len(frames::DEFAULT_MAX_FRAME_LEN);
+        writer.buffer_frame(data()).unwrap();
+        future::poll_fn(|cx| writer.poll_flush(cx)).await.unwrap();
+        let encoded = writer.into_inner();
+        let mut reader = FrameReader::new(&encoded[..], Arc::new(ConnectionStats::default()));
+        reader.set_max_frame_len(2048);
+        match future::poll_fn(|cx| reader.poll_frame(cx)).await {
+            Err(FramingError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            other => panic!("unexpected result: {:?}", other),
+        }
     }
 }
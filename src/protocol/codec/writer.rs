@@ -1,17 +1,16 @@
 //! Buffer-backed writer based on Netty's `ChannelOutboundBuffer` and writing/flushing semantics.
 
-use bytes::{Buf, Bytes, BytesMut, IntoBuf};
-use futures::task;
-use futures::task::Task;
-use futures::Async;
-use futures::Poll;
-use protocol::codec::buffer::OutboundBuffer;
-use protocol::frames;
-use protocol::frames::Frame;
+use crate::metrics::ConnectionStats;
+use crate::protocol::codec::buffer::OutboundBuffer;
+use crate::protocol::frames;
+use crate::protocol::frames::Frame;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std;
-use std::error::Error;
 use std::fmt::Formatter;
-use tokio_io::AsyncWrite;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use tokio::io::AsyncWrite;
 
 const LOW_WATERMARK: usize = 32 * 1024;
 const HIGH_WATERMARK: usize = 64 * 1024;
@@ -42,22 +41,25 @@ pub enum WriteError {
     NotReady(Bytes),
     WouldBlock,
     Io,
+    /// The encoded frame is longer than the remote reads
+    FrameTooLarge {
+        len: usize,
+        max: usize,
+    },
 }
 
-impl std::error::Error for WriteError {
-    fn description(&self) -> &str {
-        match *self {
+impl std::error::Error for WriteError {}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let description = match *self {
             WriteError::HighWatermark => "Buffering would exceed high watermark",
             WriteError::NotReady(_) => "Writer is not ready",
             WriteError::WouldBlock => "Writer would block",
             WriteError::Io => "I/O error",
-        }
-    }
-}
-
-impl std::fmt::Display for WriteError {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "writer error: {}", self.description())
+            WriteError::FrameTooLarge { .. } => "Frame exceeds maximum length",
+        };
+        write!(f, "writer error: {}", description)
     }
 }
 
@@ -84,27 +86,34 @@ impl WriteState {
     }
 }
 
-pub struct Writer<T, B: IntoBuf = Bytes> {
+pub struct Writer<T, B: Buf = Bytes> {
     /// Destination for writing bytes
     dst: T,
 
     /// Watermark-based buffer for storing byte buffers before writing
     buffer: OutboundBuffer<B>,
     /// Holds the next buffer to be written to the destination
-    current: Option<B::Buf>,
+    current: Option<B>,
 
     /// Tracks the writer's current state
     write_state: WriteState,
     /// Counter of the number of bytes waiting to be written TODO
     pending_bytes: usize,
     /// Task which waits for watermark progress on this writer
-    waiting_task: Option<Task>,
+    waiting_task: Option<Waker>,
     /// Configured waterark levels for this writer
     watermarks: Watermarks,
+    /// Gauges for the buffered bytes and watermark state
+    stats: Arc<ConnectionStats>,
 }
 
-impl<T: AsyncWrite> Writer<T> {
+impl<T: AsyncWrite + Unpin> Writer<T> {
     pub fn new(dst: T) -> Self {
+        Writer::with_stats(dst, Arc::new(ConnectionStats::default()))
+    }
+
+    /// Creates a new Writer which records its buffering state in `stats`
+    pub fn with_stats(dst: T, stats: Arc<ConnectionStats>) -> Self {
         Writer {
             dst,
             buffer: OutboundBuffer::with_capacity(INIT_BUF_CAPACITY),
@@ -113,9 +122,11 @@ impl<T: AsyncWrite> Writer<T> {
             pending_bytes: 0,
             waiting_task: None,
             watermarks: (LOW_WATERMARK, HIGH_WATERMARK).into(),
+            stats,
         }
     }
 
+    #[allow(dead_code)]
     pub fn set_watermarks(&mut self, high: usize, low: usize) {
         self.watermarks = (high, low).into();
     }
@@ -124,51 +135,51 @@ impl<T: AsyncWrite> Writer<T> {
     ///
     /// Returns WriteError::NotReady if the buffer is in high water.
     /// Use `poll_buffer_ready()` to ensure the buffer can accept more data.
-    pub fn buffer_data(&mut self, data: Bytes) -> Result<(usize), WriteError> {
+    pub fn buffer_data(&mut self, data: Bytes) -> Result<usize, WriteError> {
         if self.write_state == WriteState::HighWatermarkReached {
             return Err(WriteError::NotReady(data));
         }
 
         self.pending_bytes += data.len();
+        self.stats.set_pending_bytes(self.pending_bytes);
         self.buffer.add_data(data);
 
         if self.watermarks.high < self.pending_bytes {
+            debug!(
+                pending_bytes = self.pending_bytes,
+                high = self.watermarks.high,
+                "high watermark reached"
+            );
+            self.stats.enter_high_watermark();
             self.write_state = WriteState::HighWatermarkReached;
         }
 
-        let remaining = {
-            if self.watermarks.high < self.pending_bytes {
-                0
-            } else {
-                self.watermarks.high - self.pending_bytes
-            }
-        };
-        Ok(remaining)
+        Ok(self.watermarks.high.saturating_sub(self.pending_bytes))
     }
 
-    /// Returns `Async::Ready` when the outbound buffer can accept another entry.
+    /// Returns `Poll::Ready` when the outbound buffer can accept another entry.
     ///
     /// This function will attempt to flush the buffer if it is currently above the
     /// high watermark.
     ///
     /// # Errors
     /// An IO error is returned if the writer has encountered an error prior to this call.
-    pub fn poll_buffer_ready(&mut self) -> Poll<(), std::io::Error> {
+    pub fn poll_buffer_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
         if let WriteState::Error = self.write_state {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "writer error",
-            ));
+            return Poll::Ready(Err(std::io::Error::other("writer error")));
         } else if self.write_state == WriteState::HighWatermarkReached {
-            self.poll_flush()?;
+            if let Poll::Ready(Err(err)) = self.poll_flush(cx) {
+                return Poll::Ready(Err(err));
+            }
 
             if self.write_state == WriteState::HighWatermarkReached {
-                // TODO should task be passed into function?
-                self.waiting_task = Some(task::current());
-                return Ok(Async::NotReady);
+                self.waiting_task = Some(cx.waker().clone());
+                self.stats.block_writer();
+                return Poll::Pending;
             }
         }
-        Ok(Async::Ready(()))
+        self.stats.unblock_writer();
+        Poll::Ready(Ok(()))
     }
 
     /// Returns whether the current buffer has bytes remaining to be written
@@ -186,24 +197,43 @@ impl<T: AsyncWrite> Writer<T> {
 
     /// Writes outbound buffer's entries to `self.dst`, flushing `dst` after all entries have
     /// been written.
-    pub fn poll_flush(&mut self) -> Poll<(), std::io::Error> {
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
         if self.current.is_none() {
             self.advance();
         }
 
         while self.has_remaining() {
             let (bytes_flushed, remaining) = {
-                let mut buf = self.current.as_mut().unwrap();
+                let buf = self.current.as_mut().unwrap();
                 let remaining = buf.remaining();
-                let bytes_flushed = try_ready!(AsyncWrite::write_buf(&mut self.dst, buf));
+                let bytes_flushed = match ready!(Pin::new(&mut self.dst).poll_write(cx, buf.chunk()))
+                {
+                    Ok(n) if n > 0 => n,
+                    res => {
+                        // Whatever was buffered can no longer be written
+                        self.write_state = WriteState::Error;
+                        let err = res.err();
+                        return Poll::Ready(Err(
+                            err.unwrap_or_else(|| std::io::ErrorKind::WriteZero.into())
+                        ));
+                    }
+                };
+                buf.advance(bytes_flushed);
                 self.pending_bytes -= bytes_flushed;
+                self.stats.set_pending_bytes(self.pending_bytes);
                 (bytes_flushed, remaining)
             };
 
             if self.watermarks.low > self.pending_bytes && self.write_state.is_blocked() {
+                debug!(
+                    pending_bytes = self.pending_bytes,
+                    low = self.watermarks.low,
+                    "writable below low watermark"
+                );
+                self.stats.leave_high_watermark();
                 self.write_state = WriteState::Writable;
-                if let Some(task) = self.waiting_task.take() {
-                    task.notify();
+                if let Some(waker) = self.waiting_task.take() {
+                    waker.wake();
                 }
             }
 
@@ -213,13 +243,17 @@ impl<T: AsyncWrite> Writer<T> {
             }
         }
 
-        try_ready!(self.dst.poll_flush());
-        Ok(Async::Ready(()))
+        Pin::new(&mut self.dst).poll_flush(cx)
     }
 
-    pub fn write_and_flush(&mut self, data: Bytes) -> Poll<(), std::io::Error> {
+    #[allow(dead_code)]
+    pub fn write_and_flush(
+        &mut self,
+        data: Bytes,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
         self.buffer_data(data)?;
-        self.poll_flush()
+        self.poll_flush(cx)
     }
 
     /// Returns whether the internal buffer can be written to
@@ -231,12 +265,27 @@ impl<T: AsyncWrite> Writer<T> {
 /// Wraps `Writer` with a frame-friendly API
 pub struct FrameWriter<T> {
     writer: Writer<T>,
+    /// Whether a checksum trailer is appended to each frame
+    checksums: bool,
+    /// Longest encoded frame accepted for writing
+    max_frame_len: usize,
 }
 
-impl<T: AsyncWrite> FrameWriter<T> {
+impl<T: AsyncWrite + Unpin> FrameWriter<T> {
     pub fn new(dst: T) -> Self {
         FrameWriter {
             writer: Writer::new(dst),
+            checksums: false,
+            max_frame_len: frames::DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+
+    /// Creates a new FrameWriter which records the frames it buffers in `stats`
+    pub fn with_stats(dst: T, stats: Arc<ConnectionStats>) -> Self {
+        FrameWriter {
+            writer: Writer::with_stats(dst, stats),
+            checksums: false,
+            max_frame_len: frames::DEFAULT_MAX_FRAME_LEN,
         }
     }
 
@@ -244,24 +293,91 @@ impl<T: AsyncWrite> FrameWriter<T> {
         self.writer.is_writable()
     }
 
-    pub fn poll_buffer_ready(&mut self) -> Poll<(), std::io::Error> {
-        self.writer.poll_buffer_ready()
+    pub fn poll_buffer_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.writer.poll_buffer_ready(cx)
+    }
+
+    /// Writes and flushes every buffered frame
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.writer.poll_flush(cx)
+    }
+
+    /// Records subsequently buffered frames in `stats`
+    pub fn set_stats(&mut self, stats: Arc<ConnectionStats>) {
+        self.writer.stats = stats;
+    }
+
+    /// Consumes this writer, returning the destination it writes to
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        self.writer.dst
+    }
+
+    /// Appends a checksum to subsequently buffered frames if `enabled`
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.checksums = enabled;
     }
 
-    pub fn buffer_frame(&mut self, frame: Frame) -> Result<(usize), WriteError> {
-        let size = frame.encoded_len() + frames::FRAME_HEAD_LEN as usize;
-        // TODO buffer provider
-        let mut buf = BytesMut::with_capacity(size);
-        let _res = frame.encode_into(&mut buf);
-        let buf = buf.freeze();
-        self.writer.buffer_data(buf)
+    /// Rejects subsequently buffered frames longer than `max_frame_len`, including their length
+    /// prefix and checksum
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
     }
 
-    pub fn buffer_and_flush(&mut self, frame: Frame) -> Poll<(usize), WriteError> {
+    pub fn buffer_frame(&mut self, frame: Frame) -> Result<usize, WriteError> {
+        let buf = encode_frame(&frame, self.checksums, self.max_frame_len)?.freeze();
+        let size = buf.len();
+        let frame_type = frame.frame_type();
+        let remaining = self.writer.buffer_data(buf)?;
+        self.writer.stats.record_outbound(frame_type, size);
+        Ok(remaining)
+    }
+
+    pub fn buffer_and_flush(
+        &mut self,
+        frame: Frame,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<usize, WriteError>> {
         let remaining = self.buffer_frame(frame)?;
-        match self.writer.poll_flush().map_err(|_| WriteError::Io)? {
-            Async::Ready(_) => Ok(Async::Ready(remaining)),
-            Async::NotReady => Ok(Async::NotReady),
-        }
+        ready!(self.writer.poll_flush(cx)).map_err(|_| WriteError::Io)?;
+        Poll::Ready(Ok(remaining))
+    }
+}
+
+/// Encodes `frame` with the length prefix expected by the remote's `length_delimited` decoder,
+/// appending a checksum trailer if `checksums` is set.
+///
+/// Fails if the frame, including its prefix and trailer, is longer than `max_frame_len`.
+pub(crate) fn encode_frame(
+    frame: &Frame,
+    checksums: bool,
+    max_frame_len: usize,
+) -> Result<BytesMut, WriteError> {
+    let mut size = frame.encoded_len() + frames::FRAME_HEAD_LEN as usize;
+    if checksums {
+        size += frames::CHECKSUM_LEN;
+    }
+    if size > max_frame_len {
+        warn!(
+            len = size,
+            max = max_frame_len,
+            "frame exceeds maximum length"
+        );
+        return Err(WriteError::FrameTooLarge {
+            len: size,
+            max: max_frame_len,
+        });
+    }
+    // TODO buffer provider
+    let mut buf = BytesMut::with_capacity(size);
+    // The length prefix includes itself
+    buf.put_u32(size as u32);
+    let _res = frame.encode_into(&mut buf);
+    // Frames are encoded without the checksum flag, which depends only on the writer
+    if checksums {
+        buf[4 + frames::FLAGS_OFFSET] |= frames::FLAG_CHECKSUM;
+        let checksum = frames::checksum(&buf[4..]);
+        buf.put_u32(checksum);
     }
+    Ok(buf)
 }
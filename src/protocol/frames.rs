@@ -1,17 +1,43 @@
 //! Frames are the core of the message transport layer, allowing applications to build
 //! custom protocols atop this library.
 
+use crate::compression::Codec;
+use crate::stream::StreamId;
 use bytes::Buf;
 use bytes::BufMut;
 use bytes::Bytes;
-use bytes::IntoBuf;
+use bytes::BytesMut;
 use std;
-use std::fmt::Debug;
-use stream::StreamId;
 
 pub const MAGIC_NUM: u32 = 0xC0A1BA11;
-// (frame length) + (magic # length) + (frame type)
-pub const FRAME_HEAD_LEN: u32 = 4 + 4 + 1;
+// (frame length) + (magic # length) + (frame type) + (flags)
+pub const FRAME_HEAD_LEN: u32 = 4 + 4 + 1 + 1;
+/// Offset of the flags byte within an encoded frame, excluding its length prefix
+pub const FLAGS_OFFSET: usize = 4 + 1;
+/// Length of the CRC32C trailer appended to each frame once checksums are enabled
+pub const CHECKSUM_LEN: usize = 4;
+/// Largest encoded frame read by default, including its length prefix and checksum
+pub const DEFAULT_MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+/// Smallest maximum frame length an end may advertise, which leaves room for every control frame
+pub const MIN_MAX_FRAME_LEN: usize = 1024;
+/// Bytes a Data frame adds to its payload on the wire: its head and fields, but not its checksum
+pub const DATA_OVERHEAD: usize = FRAME_HEAD_LEN as usize + 4 + 4 + 4;
+
+/// The frame ends with a CRC32C checksum trailer
+pub const FLAG_CHECKSUM: u8 = 0x01;
+/// The frame may be skipped by a receiver which does not know its type
+pub const FLAG_IGNORABLE: u8 = 0x02;
+/// The Data frame is followed by further fragments of the same message
+pub const FLAG_MORE_FRAGMENTS: u8 = 0x04;
+/// The Data frame's payload is compressed with LZ4
+pub const FLAG_LZ4: u8 = 0x08;
+/// The Data frame's payload is compressed with zstd
+pub const FLAG_ZSTD: u8 = 0x10;
+/// Flags of a Data frame which select the codec of its payload. At most one of them may be set.
+pub const FLAG_CODEC_MASK: u8 = FLAG_LZ4 | FLAG_ZSTD;
+
+/// Frame types from this one up are reserved for extensions defined by applications
+pub const MIN_EXTENSION_TYPE: u8 = 0x80;
 
 #[derive(Debug)]
 pub enum FramingError {
@@ -19,6 +45,8 @@ pub enum FramingError {
     UnsupportedFrameType,
     InvalidMagicNum,
     InvalidFrame,
+    /// The frame's checksum trailer does not match its contents
+    ChecksumMismatch,
     Io(std::io::Error),
 }
 
@@ -36,6 +64,12 @@ pub enum Frame {
     Data(Data),
     Ping(u32, StreamId),
     Pong(u32, StreamId),
+    Handshake(Handshake),
+    Resume(Resume),
+    Ack(Ack),
+    Auth(Auth),
+    /// Frame of a type defined by the application
+    Extension(Extension),
 
     /// Catch-all for unknown frame types
     Unknown,
@@ -49,46 +83,113 @@ impl Frame {
             Frame::Data(_) => FrameType::Data,
             Frame::Ping(..) => FrameType::Ping,
             Frame::Pong(..) => FrameType::Pong,
+            Frame::Handshake(_) => FrameType::Handshake,
+            Frame::Resume(_) => FrameType::Resume,
+            Frame::Ack(_) => FrameType::Ack,
+            Frame::Auth(_) => FrameType::Auth,
+            Frame::Extension(_) => FrameType::Extension,
             Frame::Unknown => FrameType::Unknown,
         }
     }
 
-    pub fn decode_from<B: IntoBuf + Debug>(buf: B) -> Result<Self, FramingError> {
-        let mut buf = buf.into_buf();
+    /// Returns the flags sent in the head of this frame.
+    ///
+    /// Only Data and Extension frames carry flags. `FLAG_CHECKSUM` describes how a frame was
+    /// written rather than the frame itself, so it is stripped when decoding and ignored when
+    /// encoding. The `FLAG_CODEC_MASK` bits of a Data frame are taken from its `codec`.
+    pub fn flags(&self) -> u8 {
+        match *self {
+            Frame::Data(ref data) => data.flags & !FLAG_CODEC_MASK | data.codec.flag(),
+            Frame::Extension(ref ext) => ext.flags,
+            _ => 0,
+        }
+    }
+
+    /// Returns true if all of `flags` are set
+    pub fn has_flags(&self, flags: u8) -> bool {
+        self.flags() & flags == flags
+    }
+
+    /// Decodes a frame, without the checksum trailer its head may announce.
+    ///
+    /// Fails if a frame of a type which carries no flags has any other than `FLAG_CHECKSUM` set.
+    pub fn decode_from<B: Buf>(mut buf: B) -> Result<Self, FramingError> {
         let head = FrameHead::decode_from(&mut buf)?;
-        match head.frame_type {
-            FrameType::StreamRequest => StreamRequest::decode_from(&mut buf),
-            FrameType::Data => Data::decode_from(&mut buf),
-            FrameType::CreditUpdate => CreditUpdate::decode_from(&mut buf),
+        let flags = head.flags & !FLAG_CHECKSUM;
+        match head.frame_type() {
+            FrameType::Data | FrameType::Extension | FrameType::Unknown => (),
+            _ if flags != 0 => return Err(FramingError::InvalidFrame),
+            _ => (),
+        }
+        match head.frame_type() {
+            FrameType::StreamRequest => {
+                StreamRequest::decode_from(&mut buf).map(Frame::StreamRequest)
+            }
+            FrameType::Data => {
+                let mut data = Data::decode_from(&mut buf)?;
+                data.codec = Codec::from_flags(flags).ok_or(FramingError::InvalidFrame)?;
+                data.flags = flags & !FLAG_CODEC_MASK;
+                Ok(Frame::Data(data))
+            }
+            FrameType::CreditUpdate => CreditUpdate::decode_from(&mut buf).map(Frame::CreditUpdate),
             FrameType::Ping => {
-                let id = buf.get_u32_be();
-                let stream = buf.get_u32_be().into();
+                if buf.remaining() < 8 {
+                    return Err(FramingError::InvalidFrame);
+                }
+                let id = buf.get_u32();
+                let stream = buf.get_u32().into();
                 Ok(Frame::Ping(id, stream))
             }
             FrameType::Pong => {
-                let id = buf.get_u32_be();
-                let stream = buf.get_u32_be().into();
+                if buf.remaining() < 8 {
+                    return Err(FramingError::InvalidFrame);
+                }
+                let id = buf.get_u32();
+                let stream = buf.get_u32().into();
                 Ok(Frame::Pong(id, stream))
             }
-            _ => unimplemented!(),
+            FrameType::Handshake => Handshake::decode_from(&mut buf).map(Frame::Handshake),
+            FrameType::Resume => Resume::decode_from(&mut buf).map(Frame::Resume),
+            FrameType::Ack => Ack::decode_from(&mut buf).map(Frame::Ack),
+            FrameType::Auth => Auth::decode_from(&mut buf).map(Frame::Auth),
+            FrameType::Extension => {
+                let mut ext = Extension::decode_from(&mut buf)?;
+                ext.frame_type = head.frame_type;
+                ext.flags = flags;
+                Ok(Frame::Extension(ext))
+            }
+            // The remote allows us to skip frames we do not understand
+            FrameType::Unknown if head.has(FLAG_IGNORABLE) => Ok(Frame::Unknown),
+            FrameType::Unknown => Err(FramingError::UnsupportedFrameType),
         }
     }
 
+    /// Encodes the frame, leaving `FLAG_CHECKSUM` to be set by a writer which appends a checksum
+    #[allow(clippy::result_unit_err)]
     pub fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
-        let head = FrameHead::new(self.frame_type());
+        let flags = self.flags() & !FLAG_CHECKSUM;
+        let head = match *self {
+            Frame::Extension(ref ext) => FrameHead::with_type_byte(ext.frame_type, flags),
+            _ => FrameHead::with_flags(self.frame_type(), flags),
+        };
         head.encode_into(dst, self.encoded_len() as u32);
         match *self {
             Frame::StreamRequest(ref frame) => frame.encode_into(dst),
             Frame::CreditUpdate(ref frame) => frame.encode_into(dst),
             Frame::Data(ref frame) => frame.encode_into(dst),
+            Frame::Handshake(ref frame) => frame.encode_into(dst),
+            Frame::Resume(ref frame) => frame.encode_into(dst),
+            Frame::Ack(ref frame) => frame.encode_into(dst),
+            Frame::Auth(ref frame) => frame.encode_into(dst),
+            Frame::Extension(ref frame) => frame.encode_into(dst),
             Frame::Ping(id, stream) => {
-                dst.put_u32_be(id);
-                dst.put_u32_be(stream.into());
+                dst.put_u32(id);
+                dst.put_u32(stream.into());
                 Ok(())
             }
             Frame::Pong(id, stream) => {
-                dst.put_u32_be(id);
-                dst.put_u32_be(stream.into());
+                dst.put_u32(id);
+                dst.put_u32(stream.into());
                 Ok(())
             }
             _ => Err(()),
@@ -101,13 +202,26 @@ impl Frame {
             Frame::StreamRequest(ref frame) => frame.encoded_len(),
             Frame::CreditUpdate(ref frame) => frame.encoded_len(),
             Frame::Data(ref frame) => frame.encoded_len(),
-            _ => 0,
+            Frame::Handshake(ref frame) => frame.encoded_len(),
+            Frame::Resume(ref frame) => frame.encoded_len(),
+            Frame::Ack(ref frame) => frame.encoded_len(),
+            Frame::Auth(ref frame) => frame.encoded_len(),
+            Frame::Extension(ref frame) => frame.encoded_len(),
+            Frame::Ping(..) | Frame::Pong(..) => 4 + 4, // id + stream_id
+            Frame::Unknown => 0,
         }
     }
 }
 
+/// Encoding of a frame's contents, following its head.
+///
+/// Applications implement this for the contents of their extension frames, which are carried by
+/// `Extension` frames.
 pub trait FrameExt {
-    fn decode_from<B: Buf>(src: &mut B) -> Result<Frame, FramingError>;
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError>
+    where
+        Self: Sized;
+    #[allow(clippy::result_unit_err)]
     fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()>;
     fn encoded_len(&self) -> usize;
 }
@@ -115,7 +229,9 @@ pub trait FrameExt {
 // Head of each frame
 #[derive(Debug)]
 pub struct FrameHead {
-    frame_type: FrameType,
+    /// Type byte as sent on the wire, which may be an extension's
+    frame_type: u8,
+    flags: u8,
 }
 
 #[derive(Debug)]
@@ -130,13 +246,77 @@ pub struct CreditUpdate {
     pub credit: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Data<B = Bytes> {
     pub stream_id: StreamId,
     pub seq_num: u32,
+    /// Codec the payload was compressed with, sent in the `FLAG_CODEC_MASK` bits of the flags
+    pub codec: Codec,
+    /// Flags sent in the frame's head, other than those selecting the codec
+    pub flags: u8,
     pub payload: B,
 }
 
+/// The peer supports resuming the session after the connection is lost
+pub const HANDSHAKE_RESUMABLE: u8 = 0x01;
+/// The sender continues the session identified by the handshake's `session_id`
+pub const HANDSHAKE_RESUME: u8 = 0x02;
+/// The sender requires both ends to prove knowledge of a pre-shared key before the connection is
+/// used
+pub const HANDSHAKE_AUTH: u8 = 0x04;
+/// The sender appends a checksum to every frame following the handshakes, and expects the remote
+/// to do the same
+pub const HANDSHAKE_CHECKSUM: u8 = 0x08;
+
+/// First frame sent by each end of a connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    /// Identifies the session of the connecting end
+    pub session_id: u64,
+    pub flags: u8,
+    /// Largest frame the sender reads, which the remote must not exceed
+    pub max_frame_len: u32,
+}
+
+/// Exchanged for every stream after a session has been resumed, so that the sender can replay
+/// the Data frames which were lost with the previous connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resume {
+    pub stream_id: StreamId,
+    /// Sequence number of the first Data frame the receiver has not seen yet
+    pub next_seq: u32,
+}
+
+/// Acknowledges every Data frame on the stream up to, but excluding, `next_seq`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ack {
+    pub stream_id: StreamId,
+    pub next_seq: u32,
+}
+
+/// Step of the shared-secret authentication which follows the handshake.
+///
+/// Each end first sends its nonce with an empty proof, then its proof with an empty nonce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Auth {
+    pub nonce: Bytes,
+    pub proof: Bytes,
+}
+
+/// Frame of a type from `MIN_EXTENSION_TYPE` up, whose contents are defined by the application.
+///
+/// Extension frames are routed by the connection's `ExtensionRegistry`, and are not subject to
+/// flow control or sequencing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extension {
+    pub frame_type: u8,
+    /// Stream the frame is delivered to, if its type is registered for stream delivery
+    pub stream_id: StreamId,
+    /// Flags sent in the frame's head
+    pub flags: u8,
+    pub payload: Bytes,
+}
+
 /// Byte-mappings for frame types
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Ord, PartialOrd, Eq)]
@@ -146,7 +326,13 @@ pub enum FrameType {
     CreditUpdate = 0x03,
     Ping = 0x04,
     Pong = 0x05,
+    Handshake = 0x06,
+    Resume = 0x07,
+    Ack = 0x08,
+    Auth = 0x09,
     Unknown, // Not needed
+    /// Any type from `MIN_EXTENSION_TYPE` up
+    Extension = MIN_EXTENSION_TYPE,
 }
 
 impl From<u8> for FrameType {
@@ -157,14 +343,33 @@ impl From<u8> for FrameType {
             0x03 => FrameType::CreditUpdate,
             0x04 => FrameType::Ping,
             0x05 => FrameType::Pong,
+            0x06 => FrameType::Handshake,
+            0x07 => FrameType::Resume,
+            0x08 => FrameType::Ack,
+            0x09 => FrameType::Auth,
+            MIN_EXTENSION_TYPE..=0xFF => FrameType::Extension,
             _ => FrameType::Unknown,
         }
     }
 }
 
+/// Returns the CRC32C checksum of an encoded frame, excluding its length prefix
+pub fn checksum(frame: &[u8]) -> u32 {
+    crc32c::crc32c(frame)
+}
+
 impl FrameHead {
     pub fn new(frame_type: FrameType) -> Self {
-        FrameHead { frame_type }
+        FrameHead::with_flags(frame_type, 0)
+    }
+
+    pub fn with_flags(frame_type: FrameType, flags: u8) -> Self {
+        FrameHead::with_type_byte(frame_type as u8, flags)
+    }
+
+    /// Creates the head of a frame whose type is given as sent on the wire
+    pub fn with_type_byte(frame_type: u8, flags: u8) -> Self {
+        FrameHead { frame_type, flags }
     }
 
     // Encodes own fields and entire frame length into `dst`.
@@ -174,9 +379,10 @@ impl FrameHead {
         // Represents total length, including bytes for encoding length
         // NOTE: This is not needed, and thus commented out, if length_delimited is also used for writing (as in the kompcis code)
         //        let len = FRAME_HEAD_LEN + content_len;
-        //        dst.put_u32_be(len);
-        dst.put_u32_be(MAGIC_NUM);
-        dst.put_u8(self.frame_type as u8);
+        //        dst.put_u32(len);
+        dst.put_u32(MAGIC_NUM);
+        dst.put_u8(self.frame_type);
+        dst.put_u8(self.flags);
     }
 
     pub fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
@@ -185,21 +391,35 @@ impl FrameHead {
             return Err(FramingError::BufferCapacity);
         }
 
-        let magic_check = src.get_u32_be();
+        let magic_check = src.get_u32();
 
         if magic_check != MAGIC_NUM {
             return Err(FramingError::InvalidMagicNum);
         }
 
-        let frame_type: FrameType = src.get_u8().into();
-        let head = FrameHead::new(frame_type);
-        Ok(head)
+        let frame_type = src.get_u8();
+        let flags = src.get_u8();
+        Ok(FrameHead::with_type_byte(frame_type, flags))
     }
 
     pub fn frame_type(&self) -> FrameType {
+        self.frame_type.into()
+    }
+
+    /// Returns the type byte as sent on the wire
+    pub fn type_byte(&self) -> u8 {
         self.frame_type
     }
 
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Returns true if all of `flags` are set
+    pub fn has(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+
     pub fn encoded_len() -> usize {
         FRAME_HEAD_LEN as usize
     }
@@ -214,18 +434,35 @@ impl StreamRequest {
     }
 }
 
+impl Handshake {
+    pub fn new(session_id: u64, flags: u8, max_frame_len: u32) -> Self {
+        Handshake {
+            session_id,
+            flags,
+            max_frame_len,
+        }
+    }
+
+    /// Returns true if all of `flags` are set
+    pub fn has(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+}
+
 impl Data {
     pub fn new(stream_id: StreamId, seq_num: u32, payload: Bytes) -> Self {
         Data {
             stream_id,
             seq_num,
+            codec: Codec::None,
+            flags: 0,
             // TODO Could piggy-back "backlog" of buffers like Flink to proactively request more consumer buffers
             payload,
         }
     }
 
     pub fn with_raw_payload(stream_id: StreamId, seq_num: u32, raw_bytes: &[u8]) -> Self {
-        Data::new(stream_id, seq_num, Bytes::from(raw_bytes))
+        Data::new(stream_id, seq_num, Bytes::copy_from_slice(raw_bytes))
     }
 
     pub fn encoded_len(&self) -> usize {
@@ -242,24 +479,68 @@ impl Data {
     }
 }
 
+impl Extension {
+    pub fn new(frame_type: u8, stream_id: StreamId, payload: Bytes) -> Self {
+        Extension {
+            frame_type,
+            stream_id,
+            flags: 0,
+            payload,
+        }
+    }
+
+    /// Creates an extension frame carrying `frame` as its payload
+    pub fn encode<F: FrameExt>(
+        frame_type: u8,
+        stream_id: StreamId,
+        frame: &F,
+    ) -> Result<Self, FramingError> {
+        let mut payload = BytesMut::with_capacity(frame.encoded_len());
+        frame
+            .encode_into(&mut payload)
+            .map_err(|_| FramingError::InvalidFrame)?;
+        Ok(Extension::new(frame_type, stream_id, payload.freeze()))
+    }
+
+    /// Decodes the payload of this frame as an `F`
+    pub fn decode<F: FrameExt>(&self) -> Result<F, FramingError> {
+        F::decode_from(&mut self.payload.clone())
+    }
+
+    /// Allows receivers which do not know this frame's type to skip it
+    pub fn ignorable(mut self) -> Self {
+        self.flags |= FLAG_IGNORABLE;
+        self
+    }
+
+    /// Returns true if all of `flags` are set
+    pub fn has(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+
+    /// Returns true if the type of this frame lies in the range reserved for extensions
+    pub fn is_valid_type(frame_type: u8) -> bool {
+        frame_type >= MIN_EXTENSION_TYPE
+    }
+}
+
 impl FrameExt for StreamRequest {
-    fn decode_from<B: Buf>(src: &mut B) -> Result<Frame, FramingError> {
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
         if src.remaining() < 8 {
             return Err(FramingError::InvalidFrame);
         }
-        let stream_id: StreamId = src.get_u32_be().into();
-        let credit = src.get_u32_be();
-        let stream_req = StreamRequest {
+        let stream_id: StreamId = src.get_u32().into();
+        let credit = src.get_u32();
+        Ok(StreamRequest {
             stream_id,
             credit_capacity: credit,
-        };
-        Ok(Frame::StreamRequest(stream_req))
+        })
     }
 
     fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
         assert!(dst.remaining_mut() >= self.encoded_len());
-        dst.put_u32_be(self.stream_id.into());
-        dst.put_u32_be(self.credit_capacity);
+        dst.put_u32(self.stream_id.into());
+        dst.put_u32(self.credit_capacity);
         Ok(())
     }
 
@@ -269,48 +550,261 @@ impl FrameExt for StreamRequest {
 }
 
 impl FrameExt for Data {
-    fn decode_from<B: Buf>(src: &mut B) -> Result<Frame, FramingError> {
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
         if src.remaining() < 12 {
             return Err(FramingError::InvalidFrame);
         }
-        let stream_id = src.get_u32_be().into();
-        let seq_num = src.get_u32_be();
-        let _len = src.get_u32_be();
-        let payload = src.collect();
-        let data_frame = Data {
+        let stream_id = src.get_u32().into();
+        let seq_num = src.get_u32();
+        let _len = src.get_u32();
+        let payload = src.copy_to_bytes(src.remaining());
+        Ok(Data {
             stream_id,
             seq_num,
+            codec: Codec::None,
+            flags: 0,
             payload,
-        };
-        Ok(Frame::Data(data_frame))
+        })
     }
 
     fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
         // NOTE: This method _COPIES_ the owned bytes into `dst` rather than extending with the owned bytes
         let payload_len = Bytes::len(&self.payload);
         assert!(dst.remaining_mut() >= (self.encoded_len()));
-        dst.put_u32_be(self.stream_id.into());
-        dst.put_u32_be(self.seq_num);
-        dst.put_u32_be(payload_len as u32);
+        dst.put_u32(self.stream_id.into());
+        dst.put_u32(self.seq_num);
+        dst.put_u32(payload_len as u32);
         dst.put_slice(&self.payload);
         Ok(())
     }
 
     fn encoded_len(&self) -> usize {
-        4 + 4 + 4 + Bytes::len(&self.payload)
+        4 + 4 + 4 + Bytes::len(&self.payload) // stream_id + seq_num + len + payload
     }
 }
 
 impl FrameExt for CreditUpdate {
-    fn decode_from<B: Buf>(src: &mut B) -> Result<Frame, FramingError> {
-        unimplemented!()
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
+        if src.remaining() < 8 {
+            return Err(FramingError::InvalidFrame);
+        }
+        let stream_id: StreamId = src.get_u32().into();
+        let credit = src.get_u32();
+        Ok(CreditUpdate { stream_id, credit })
     }
 
     fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
-        unimplemented!()
+        assert!(dst.remaining_mut() >= self.encoded_len());
+        dst.put_u32(self.stream_id.into());
+        dst.put_u32(self.credit);
+        Ok(())
     }
 
     fn encoded_len(&self) -> usize {
         4 + 4 // stream_id + credit
     }
 }
+
+impl FrameExt for Handshake {
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
+        if src.remaining() < 13 {
+            return Err(FramingError::InvalidFrame);
+        }
+        let session_id = src.get_u64();
+        let flags = src.get_u8();
+        let max_frame_len = src.get_u32();
+        Ok(Handshake::new(session_id, flags, max_frame_len))
+    }
+
+    fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
+        assert!(dst.remaining_mut() >= self.encoded_len());
+        dst.put_u64(self.session_id);
+        dst.put_u8(self.flags);
+        dst.put_u32(self.max_frame_len);
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        8 + 1 + 4 // session_id + flags + max_frame_len
+    }
+}
+
+impl FrameExt for Resume {
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
+        if src.remaining() < 8 {
+            return Err(FramingError::InvalidFrame);
+        }
+        let stream_id: StreamId = src.get_u32().into();
+        let next_seq = src.get_u32();
+        Ok(Resume {
+            stream_id,
+            next_seq,
+        })
+    }
+
+    fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
+        assert!(dst.remaining_mut() >= self.encoded_len());
+        dst.put_u32(self.stream_id.into());
+        dst.put_u32(self.next_seq);
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + 4 // stream_id + next_seq
+    }
+}
+
+impl FrameExt for Ack {
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
+        if src.remaining() < 8 {
+            return Err(FramingError::InvalidFrame);
+        }
+        let stream_id: StreamId = src.get_u32().into();
+        let next_seq = src.get_u32();
+        Ok(Ack {
+            stream_id,
+            next_seq,
+        })
+    }
+
+    fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
+        assert!(dst.remaining_mut() >= self.encoded_len());
+        dst.put_u32(self.stream_id.into());
+        dst.put_u32(self.next_seq);
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + 4 // stream_id + next_seq
+    }
+}
+
+impl FrameExt for Auth {
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
+        if src.remaining() < 1 {
+            return Err(FramingError::InvalidFrame);
+        }
+        let nonce_len = src.get_u8() as usize;
+        if src.remaining() < nonce_len {
+            return Err(FramingError::InvalidFrame);
+        }
+        let rest: Bytes = src.copy_to_bytes(src.remaining());
+        Ok(Auth {
+            nonce: rest.slice(..nonce_len),
+            proof: rest.slice(nonce_len..),
+        })
+    }
+
+    fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
+        assert!(dst.remaining_mut() >= self.encoded_len());
+        if self.nonce.len() > u8::MAX as usize {
+            return Err(());
+        }
+        dst.put_u8(self.nonce.len() as u8);
+        dst.put_slice(&self.nonce);
+        dst.put_slice(&self.proof);
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + self.nonce.len() + self.proof.len() // nonce_len + nonce + proof
+    }
+}
+
+impl FrameExt for Extension {
+    /// Decodes the frame's stream and payload; its type and flags are taken from the head
+    fn decode_from<B: Buf>(src: &mut B) -> Result<Self, FramingError> {
+        if src.remaining() < 4 {
+            return Err(FramingError::InvalidFrame);
+        }
+        let stream_id: StreamId = src.get_u32().into();
+        let payload = src.copy_to_bytes(src.remaining());
+        Ok(Extension::new(MIN_EXTENSION_TYPE, stream_id, payload))
+    }
+
+    fn encode_into<B: BufMut>(&self, dst: &mut B) -> Result<(), ()> {
+        assert!(dst.remaining_mut() >= self.encoded_len());
+        dst.put_u32(self.stream_id.into());
+        dst.put_slice(&self.payload);
+        Ok(())
+    }
+
+    fn encoded_len(&self) -> usize {
+        4 + self.payload.len() // stream_id + payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_flags_on_frames_without_flags() {
+        let encode = |flags: u8| {
+            let ping = Frame::Ping(1, StreamId(1));
+            let mut buf = BytesMut::with_capacity(FRAME_HEAD_LEN as usize + ping.encoded_len());
+            ping.encode_into(&mut buf).unwrap();
+            buf[FLAGS_OFFSET] |= flags;
+            buf.freeze()
+        };
+        assert!(matches!(
+            Frame::decode_from(encode(FLAG_CHECKSUM)),
+            Ok(Frame::Ping(1, StreamId(1)))
+        ));
+        assert!(matches!(
+            Frame::decode_from(encode(FLAG_MORE_FRAGMENTS)),
+            Err(FramingError::InvalidFrame)
+        ));
+    }
+
+    #[test]
+    fn carries_codec_in_flags_of_data_frames() {
+        let encode = |data: Data| {
+            let frame = Frame::Data(data);
+            let mut buf = BytesMut::with_capacity(FRAME_HEAD_LEN as usize + frame.encoded_len());
+            frame.encode_into(&mut buf).unwrap();
+            buf
+        };
+        let mut data = Data::with_raw_payload(StreamId(1), 2, b"payload");
+        data.codec = Codec::Zstd;
+        data.flags = FLAG_MORE_FRAGMENTS;
+        let mut buf = encode(data);
+        assert_eq!(buf.len(), DATA_OVERHEAD - 4 + b"payload".len());
+        assert_eq!(buf[FLAGS_OFFSET], FLAG_MORE_FRAGMENTS | FLAG_ZSTD);
+        match Frame::decode_from(buf.clone().freeze()) {
+            Ok(Frame::Data(data)) => {
+                assert_eq!(data.codec, Codec::Zstd);
+                assert_eq!(data.flags, FLAG_MORE_FRAGMENTS);
+                assert_eq!(data.payload(), Bytes::from_static(b"payload"));
+            }
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        // The codec field takes precedence over codec flags set by hand
+        let mut data = Data::with_raw_payload(StreamId(1), 2, b"payload");
+        data.flags = FLAG_LZ4;
+        assert_eq!(encode(data)[FLAGS_OFFSET], 0);
+
+        buf[FLAGS_OFFSET] |= FLAG_CODEC_MASK;
+        assert!(matches!(
+            Frame::decode_from(buf.freeze()),
+            Err(FramingError::InvalidFrame)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_ping_and_pong() {
+        for frame in &[Frame::Ping(1, StreamId(2)), Frame::Pong(1, StreamId(2))] {
+            let mut buf = BytesMut::with_capacity(FRAME_HEAD_LEN as usize + frame.encoded_len());
+            frame.encode_into(&mut buf).unwrap();
+            let full = buf.len();
+            for len in FRAME_HEAD_LEN as usize - 4..full {
+                assert!(matches!(
+                    Frame::decode_from(&buf[..len]),
+                    Err(FramingError::InvalidFrame)
+                ));
+            }
+            assert!(Frame::decode_from(&buf[..full]).is_ok());
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Listener which accepts connections and spawns a `ConnectionDriver` for each of them.
+
+use crate::connection::next_connection_id;
+use crate::connection::ConnectionConfig;
+use crate::connection::ConnectionDriver;
+use crate::connection::ConnectionHandle;
+use crate::connection::SharedConnectionContext;
+use crate::protocol::frames::{self, Handshake};
+use crate::socket::SocketConfig;
+use crate::stream::IncomingStreams;
+#[cfg(feature = "tls")]
+use crate::transport::tls::{TlsListener, TlsServerConfig};
+use crate::transport::Listener;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::Stream;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// Time an accepted connection has to send its handshake by default
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of accepted connections whose handshakes are awaited at once by default
+pub const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Configuration of a `Server`
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Options applied to every accepted socket
+    pub socket: SocketConfig,
+    /// Options of every accepted connection
+    pub connection: ConnectionConfig,
+    /// Time an accepted connection has to send its handshake before it is dropped
+    pub handshake_timeout: Duration,
+    /// Number of accepted connections whose handshakes are awaited at once; further connections
+    /// are only accepted once one of them completes
+    pub max_pending_handshakes: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            socket: SocketConfig::default(),
+            connection: ConnectionConfig::default(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_pending_handshakes: DEFAULT_MAX_PENDING_HANDSHAKES,
+        }
+    }
+}
+
+type Accepted = (ConnectionHandle, IncomingStreams);
+
+/// Handshake of an accepted connection, resolving to its handles unless it failed or resumed an
+/// earlier session
+type Accepting = BoxFuture<'static, Option<Accepted>>;
+
+/// Resumable sessions, keyed by the session id chosen by the connecting end
+#[derive(Clone, Default)]
+struct Sessions {
+    sessions: Arc<Mutex<HashMap<u64, SharedConnectionContext>>>,
+}
+
+impl Sessions {
+    /// Decides which session the connection belongs to, returning its handle if it is a new one
+    fn route<I, O>(&self, driver: &mut ConnectionDriver<I, O>, peer: &Handshake) -> Option<Accepted>
+    where
+        I: AsyncRead + Unpin,
+        O: AsyncWrite + Unpin,
+    {
+        let mut sessions = self.sessions.lock().unwrap();
+        if peer.has(frames::HANDSHAKE_RESUME) {
+            let session = sessions
+                .get(&peer.session_id)
+                .filter(|ctx| !ctx.lock().unwrap().has_err())
+                .cloned();
+            if session.is_none() {
+                debug!(
+                    session_id = peer.session_id,
+                    "cannot resume unknown session"
+                );
+            }
+            // Rejected connections are dropped by the remote as soon as it sees our handshake
+            driver.accept_session(session);
+            return None;
+        }
+        driver.accept_session(None);
+        let ctx = driver.clone_ctx();
+        if ctx.lock().unwrap().config().resumption.is_some()
+            && peer.has(frames::HANDSHAKE_RESUMABLE)
+        {
+            sessions.insert(peer.session_id, ctx);
+        }
+        Some((driver.handle(), driver.incoming_streams()))
+    }
+
+    /// Forgets the session once it has failed, or fails it if it is not resumed in time
+    fn on_driver_exit(&self, ctx: SharedConnectionContext, cfg: &ConnectionConfig) {
+        let (session_id, generation, failed) = {
+            let ctx = ctx.lock().unwrap();
+            if !ctx.has_err() && !ctx.is_disconnected() {
+                // Superseded by a newer transport
+                return;
+            }
+            (ctx.session_id(), ctx.generation(), ctx.has_err())
+        };
+        if failed {
+            self.remove(&session_id, &ctx);
+            return;
+        }
+        let timeout = match cfg.resumption {
+            Some(ref resumption) => resumption.timeout,
+            None => return,
+        };
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let mut ctx_guard = ctx.lock().unwrap();
+            if ctx_guard.is_disconnected() && ctx_guard.generation() == generation {
+                ctx_guard.abandon();
+                drop(ctx_guard);
+                sessions.remove(&session_id, &ctx);
+            }
+        });
+    }
+
+    fn remove(&self, session_id: &u64, ctx: &SharedConnectionContext) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(session_id)
+            .is_some_and(|current| Arc::ptr_eq(current, ctx))
+        {
+            sessions.remove(session_id);
+        }
+    }
+}
+
+/// Stream of connections accepted on a TCP listener or, on Unix, a Unix domain socket.
+///
+/// Each accepted socket is configured, assigned a unique `ConnectionId`, and handed to a
+/// `ConnectionDriver` whose reader and writer are spawned on the tokio runtime. Once the remote's handshake has been
+/// received, the stream yields a handle for opening streams on the new connection together with
+/// the streams opened by the remote end. Connections which resume an earlier session continue it
+/// instead, and are not yielded again. Connections which do not send their handshake within
+/// `ServerConfig::handshake_timeout` are dropped.
+pub struct Server<L: Listener = TcpListener> {
+    listener: L,
+    cfg: ServerConfig,
+    sessions: Sessions,
+    handshakes: FuturesUnordered<Accepting>,
+}
+
+impl Server<TcpListener> {
+    /// Binds a listener to `addr`
+    pub async fn bind(addr: &SocketAddr, cfg: ServerConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Server::from_listener(listener, cfg))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Server<TlsListener<TcpListener>> {
+    /// Binds a listener to `addr` which encrypts every accepted connection with TLS
+    pub async fn bind_tls(
+        addr: &SocketAddr,
+        tls: &TlsServerConfig,
+        cfg: ServerConfig,
+    ) -> io::Result<Self> {
+        let config = tls.build()?;
+        let mut listener = TlsListener::new(TcpListener::bind(addr).await?, config);
+        listener.set_handshake_limits(tls.handshake_timeout, tls.max_pending_handshakes);
+        Ok(Server::from_listener(listener, cfg))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.get_ref().local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl Server<UnixListener> {
+    /// Binds a listener to the Unix domain socket at `path`, which must not exist yet.
+    ///
+    /// `ServerConfig::socket` only applies to TCP and is ignored. Must be called from within the
+    /// tokio runtime.
+    pub fn bind_unix<P: AsRef<Path>>(path: P, cfg: ServerConfig) -> io::Result<Self> {
+        UnixListener::bind(path).map(|listener| Server::from_listener(listener, cfg))
+    }
+}
+
+impl<L: Listener> Server<L> {
+    /// Accepts connections from an already bound `listener`
+    pub fn from_listener(listener: L, cfg: ServerConfig) -> Self {
+        Server {
+            listener,
+            cfg,
+            sessions: Sessions::default(),
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+
+    fn spawn_connection(&mut self, socket: L::Io) {
+        if let Err(err) = self.listener.configure(&socket, &self.cfg.socket) {
+            warn!(error = %err, "could not configure accepted socket");
+        }
+        let (rx, tx) = tokio::io::split(socket);
+        let cfg = self.cfg.connection.clone();
+        let driver = ConnectionDriver::accept(rx, tx, next_connection_id(), cfg.clone());
+        let sessions = self.sessions.clone();
+        let handshake = tokio::time::timeout(self.cfg.handshake_timeout, driver.handshake());
+
+        let accepted = async move {
+            let (mut driver, peer) = match handshake.await {
+                Ok(Ok(handshake)) => handshake,
+                Ok(Err(err)) => {
+                    debug!(error = %err, "connection ended");
+                    return None;
+                }
+                Err(_) => {
+                    debug!("connection dropped without completing its handshake");
+                    return None;
+                }
+            };
+            let conn = sessions.route(&mut driver, &peer);
+            let ctx = driver.clone_ctx();
+            // Reading and writing are scheduled independently, so neither direction has to wait
+            // for the other
+            let (reader, writer) = driver.split();
+            tokio::spawn(async move {
+                if let Err(err) = writer.await {
+                    debug!(error = %err, "writer stopped");
+                }
+            });
+            tokio::spawn(async move {
+                let res = reader.await;
+                sessions.on_driver_exit(ctx, &cfg);
+                if let Err(err) = res {
+                    debug!(error = %err, "connection ended");
+                }
+            });
+            conn
+        };
+        self.handshakes.push(accepted.boxed());
+    }
+}
+
+impl<L: Listener> Stream for Server<L> {
+    type Item = io::Result<(ConnectionHandle, IncomingStreams)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            while self.handshakes.len() < self.cfg.max_pending_handshakes.max(1) {
+                match self.listener.poll_accept(cx) {
+                    Poll::Ready(Ok((socket, addr))) => {
+                        debug!(peer = %addr, "accepted connection");
+                        self.spawn_connection(socket);
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                    Poll::Pending => break,
+                }
+            }
+            // A finished handshake makes room for another connection, so accept again before
+            // waiting
+            match self.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Some(conn))) => return Poll::Ready(Some(Ok(conn))),
+                Poll::Ready(Some(None)) => (),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frames::{Data, Frame};
+    use crate::stream::StreamId;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::net::TcpStream;
+
+    /// Returns the first frame received on the first stream of the first accepted connection
+    async fn first_frame<L: Listener>(mut server: Server<L>) -> Frame {
+        let (_handle, mut incoming) = server.next().await.unwrap().unwrap();
+        let mut stream = incoming.next().await.unwrap().unwrap();
+        stream.next().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn streams_over_loopback() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = Server::bind(&addr, ServerConfig::default()).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let received = tokio::spawn(first_frame(server));
+
+        let (rx, tx) = tokio::io::split(TcpStream::connect(addr).await.unwrap());
+        let driver = ConnectionDriver::with_io(rx, tx, next_connection_id());
+        let handle = driver.handle();
+        tokio::spawn(driver);
+        let mut stream = handle.open_stream(StreamId(1), 1024).await.unwrap();
+        let data = Data::new(stream.stream_id(), 0, Bytes::from("hello"));
+        stream.send_frame(Frame::Data(data)).unwrap();
+
+        match received.await.unwrap() {
+            Frame::Data(data) => assert_eq!(data.payload(), Bytes::from("hello")),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_connections_which_never_send_handshake() {
+        use tokio::io::AsyncReadExt;
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let cfg = ServerConfig {
+            handshake_timeout: Duration::from_millis(100),
+            max_pending_handshakes: 1,
+            ..ServerConfig::default()
+        };
+        let server = Server::bind(&addr, cfg).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let received = tokio::spawn(first_frame(server));
+
+        // Takes up the only handshake slot without ever sending anything
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let (rx, tx) = tokio::io::split(TcpStream::connect(addr).await.unwrap());
+        let driver = ConnectionDriver::with_io(rx, tx, next_connection_id());
+        let handle = driver.handle();
+        tokio::spawn(driver);
+        let mut stream = handle.open_stream(StreamId(1), 1024).await.unwrap();
+        stream.send(Bytes::from("hello")).await.unwrap();
+
+        let mut buf = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), silent.read_to_end(&mut buf));
+        assert!(closed.await.is_ok());
+        match received.await.unwrap() {
+            Frame::Data(data) => assert_eq!(data.payload(), Bytes::from("hello")),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn streams_over_unix_socket() {
+        use crate::client::{Client, ClientConfig};
+
+        let path = std::env::temp_dir().join(format!("spaniel-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = Server::bind_unix(&path, ServerConfig::default()).unwrap();
+        let received = tokio::spawn(first_frame(server));
+
+        let client = Client::connect_unix(&path, ClientConfig::default())
+            .await
+            .unwrap();
+        let mut stream = client.open_stream(StreamId(1), 1024).await.unwrap();
+        stream.send(Bytes::from("hello")).await.unwrap();
+
+        let frame = received.await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        match frame {
+            Frame::Data(data) => assert_eq!(data.payload(), Bytes::from("hello")),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+}
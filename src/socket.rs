@@ -0,0 +1,40 @@
+//! Options applied to TCP sockets before they are handed to a `ConnectionDriver`.
+
+use socket2::SockRef;
+use std::io;
+use tokio::net::TcpStream;
+
+/// Socket options applied to every TCP connection
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    /// Whether to set `TCP_NODELAY`, disabling Nagle's algorithm
+    pub nodelay: bool,
+    /// Size of the socket's send buffer (`SO_SNDBUF`), or the OS default if `None`
+    pub send_buffer_size: Option<usize>,
+    /// Size of the socket's receive buffer (`SO_RCVBUF`), or the OS default if `None`
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl SocketConfig {
+    /// Applies the configured options to `socket`
+    pub fn configure(&self, socket: &TcpStream) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.send_buffer_size {
+            SockRef::from(socket).set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            SockRef::from(socket).set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
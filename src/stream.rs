@@ -1,16 +1,29 @@
-use connection::ConnectionError;
-use connection::SharedConnectionContext;
-use flow_control::Credits;
-use flow_control::FC_DENOMINATOR;
-use flow_control::FC_NUMERATOR;
+use crate::compression;
+use crate::compression::{Codec, CompressionConfig};
+use crate::connection::ConnectionError;
+use crate::connection::Framing;
+use crate::connection::SharedConnectionContext;
+use crate::extension;
+use crate::flow_control::Credits;
+use crate::flow_control::FlowControlRatio;
+use crate::flow_control::FC_DENOMINATOR;
+use crate::flow_control::FC_NUMERATOR;
+use crate::fragment::{FragmentConfig, Reassembly};
+use crate::metrics::{ConnectionStats, StreamMetrics, StreamStats};
+use crate::protocol::frames;
+use crate::protocol::frames::Frame;
+use bytes::Bytes;
 use futures;
-use futures::sync::mpsc::Receiver;
-use futures::task::{self, Task};
-use futures::Async;
-use futures::Poll;
-use protocol::frames;
-use protocol::frames::Frame;
+use futures::channel::mpsc::{Receiver, Sender};
+use futures::future::poll_fn;
+use futures::StreamExt;
 use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use tracing::Span;
 
 #[derive(Debug, Clone, Copy, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub struct StreamId(pub u32);
@@ -41,6 +54,20 @@ impl From<u32> for StreamId {
     }
 }
 
+/// Fails with `QueueFull` unless `outbound` can queue another frame right away.
+///
+/// A sender which is ready is guaranteed to queue its next frame.
+fn poll_outbound_ready(outbound: &mut Sender<Frame>) -> Result<(), ConnectionError> {
+    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+    match outbound.poll_ready(&mut cx) {
+        Poll::Ready(res) => res.map_err(|_| ConnectionError::Closed),
+        Poll::Pending => Err(ConnectionError::QueueFull),
+    }
+}
+
+/// State of a stream, shared by the connection and the stream's handles
+pub type SharedStreamState = Arc<Mutex<StreamState>>;
+
 /// Data structure tracking an individual stream
 #[derive(Debug)]
 pub struct StreamState {
@@ -48,24 +75,223 @@ pub struct StreamState {
     pub data_buffer: VecDeque<frames::Data>,
     pub data: Receiver<frames::Frame>,
     // Task waiting to be able to send data
-    pub send_task: Option<Task>,
+    pub send_task: Option<Waker>,
     // Task waiting to receive data from `data_buffer`
-    pub recv_task: Option<Task>,
+    pub recv_task: Option<Waker>,
+    /// Span under which this stream's events are recorded
+    pub span: Span,
+    /// Counters describing the stream's traffic and stalls
+    pub stats: StreamStats,
+    /// Whether the stream was opened by this end of the connection
+    pub local: bool,
+    /// Sequence number assigned to the next outbound Data frame
+    pub next_send_seq: u32,
+    /// Sequence number of the next inbound Data frame
+    pub next_recv_seq: u32,
+    /// Inbound Data frames received since the last `Ack` was sent
+    pub unacked: u32,
+    /// Outbound Data frames which have not been acknowledged by the remote yet
+    pub replay: VecDeque<frames::Data>,
+    /// Set while the remote has not told us where to resume the stream after a reconnect
+    pub awaiting_resume: bool,
+    /// Error which failed this stream alone, if any
+    pub err: Option<ConnectionError>,
+    /// Error which failed the whole connection, if any
+    pub conn_err: Option<ConnectionError>,
+    /// Whether outbound Data frames use up the stream's credit
+    pub flow_controlled: bool,
+    /// Fraction of the credit capacity which must be available before returned credit is
+    /// announced to the remote
+    pub credit_update_ratio: FlowControlRatio,
+    /// Maximum number of unacknowledged frames, if frames are kept for replay at all
+    pub replay_capacity: Option<usize>,
+    /// Compression of outbound Data payloads, if any
+    pub compression: Option<CompressionConfig>,
+    /// Splitting of outbound messages and reassembly of inbound ones
+    pub fragments: FragmentConfig,
+    /// Fragments of the inbound message whose last fragment has not been received yet
+    pub reassembly: Reassembly,
+    /// How frames are written for the remote, or `None` if they are handed to it without being
+    /// encoded
+    pub framing: Option<Framing>,
+    /// Held by the handle sending a message, so that no other Data frame is sent between its
+    /// fragments
+    pub sending: Arc<AsyncMutex<()>>,
 }
 
 impl StreamState {
+    pub fn new(credit_capacity: u32, data: Receiver<frames::Frame>, span: Span) -> Self {
+        StreamState {
+            credits: Credits::new(credit_capacity),
+            data_buffer: VecDeque::new(),
+            data,
+            send_task: None,
+            recv_task: None,
+            span,
+            stats: StreamStats::default(),
+            local: false,
+            next_send_seq: 0,
+            next_recv_seq: 0,
+            unacked: 0,
+            replay: VecDeque::new(),
+            awaiting_resume: false,
+            err: None,
+            conn_err: None,
+            flow_controlled: false,
+            credit_update_ratio: FlowControlRatio(FC_NUMERATOR, FC_DENOMINATOR),
+            replay_capacity: None,
+            compression: None,
+            fragments: FragmentConfig::default(),
+            reassembly: Reassembly::default(),
+            framing: Some(Framing::default()),
+            sending: Arc::new(AsyncMutex::new(())),
+        }
+    }
+
+    /// Numbers `data` in the order it is sent on the stream and queues it on `outbound`, unless it
+    /// is only kept for replay until the stream is resumed.
+    ///
+    /// Queueing while the stream is locked keeps the frames of a stream in sequence, without
+    /// locking the connection.
+    pub fn send_data(
+        &mut self,
+        mut data: frames::Data,
+        outbound: &mut Sender<Frame>,
+    ) -> Result<(), ConnectionError> {
+        if let Some(err) = self.conn_err.as_ref().or(self.err.as_ref()) {
+            return Err(err.clone());
+        }
+        if let Some(capacity) = self.replay_capacity {
+            if self.replay.len() >= capacity {
+                return Err(ConnectionError::ReplayBufferFull);
+            }
+        }
+        // Checked before compression, so that whether a payload can be sent does not depend on
+        // how well it compresses
+        if let Some(framing) = self.framing {
+            framing.check_len(framing.data_frame_len(data.payload_ref().len()))?;
+        }
+
+        // Frames kept for replay are not queued until the stream is resumed
+        let queued = self.replay_capacity.is_none() || !self.awaiting_resume;
+        if queued {
+            // Checked before the frame takes credit and a sequence number, which are lost if the
+            // frame cannot be queued
+            poll_outbound_ready(outbound)?;
+        }
+
+        // TODO move into own FC module
+        if self.flow_controlled {
+            let size = data.payload_ref().len() as u32;
+            if !self.credits.has_capacity(size) {
+                return Err(ConnectionError::InsufficientCredit);
+            }
+            let _res = self.credits.use_credit(size);
+            trace!(
+                used = size,
+                available = self.credits.available(),
+                "credit used by outbound data"
+            );
+        }
+        self.stats.record_outbound(data.payload_ref().len());
+        // Credit was used up by the uncompressed payload. Payloads which are not encoded on their
+        // way to the remote gain nothing from being compressed.
+        if let (Some(ref cfg), Some(_)) = (&self.compression, self.framing) {
+            let (codec, payload) = compression::compress(cfg, std::mem::take(&mut data.payload));
+            data.codec = codec;
+            data.payload = payload;
+        }
+
+        data.seq_num = self.next_send_seq;
+        self.next_send_seq = data.seq_num.wrapping_add(1);
+        if self.replay_capacity.is_some() {
+            self.replay.push_back(data.clone());
+        }
+        if queued {
+            outbound.try_send(Frame::Data(data))?;
+        }
+        // Otherwise replayed once the remote tells us where to resume
+        Ok(())
+    }
+
+    /// Returns the number of credits available for the stream, or `Poll::Pending` if there are
+    /// none or the replay buffer is full.
+    ///
+    /// Upon returning `Poll::Pending` the task is woken up once the remote grants more credit or
+    /// acknowledges frames, or once the stream fails.
+    pub fn poll_capacity(
+        &mut self,
+        conn_stats: &ConnectionStats,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<u32, ConnectionError>> {
+        if let Some(err) = self.conn_err.as_ref().or(self.err.as_ref()) {
+            return Poll::Ready(Err(err.clone()));
+        }
+        if let Some(capacity) = self.replay_capacity {
+            if self.replay.len() >= capacity {
+                self.span
+                    .in_scope(|| trace!("waiting for acknowledgements"));
+                self.send_task = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        let remaining = self.credits.available();
+        if remaining == 0 {
+            self.span.in_scope(|| trace!("waiting for stream credit"));
+            self.stats.credit_stalls += 1;
+            self.stats.send_blocked.block();
+            conn_stats.record_credit_stall();
+            self.send_task = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.stats.send_blocked.unblock();
+        Poll::Ready(Ok(remaining))
+    }
+
+    /// Captures the current values of the stream's metrics
+    pub fn metrics(&self, stream_id: StreamId) -> StreamMetrics {
+        StreamMetrics {
+            stream_id,
+            credit_available: self.credits.available(),
+            credit_capacity: self.credits.capacity(),
+            inbound: self.stats.inbound,
+            outbound: self.stats.outbound,
+            head_of_line_stalls: self.stats.head_of_line_stalls,
+            credit_stalls: self.stats.credit_stalls,
+            sequence_gaps: self.stats.sequence_gaps,
+            duplicates: self.stats.duplicates,
+            send_blocked_ratio: self.stats.send_blocked.ratio(),
+        }
+    }
+
+    /// Drops the replayable frames which the remote has received
+    pub fn acknowledge(&mut self, next_seq: u32) {
+        while let Some(true) = self
+            .replay
+            .front()
+            .map(|data| seq_before(data.seq_num, next_seq))
+        {
+            self.replay.pop_front();
+        }
+    }
+
     pub fn notify_data_rx(&mut self) {
-        if let Some(task) = self.recv_task.take() {
-            task.notify();
+        if let Some(waker) = self.recv_task.take() {
+            waker.wake();
         }
     }
     pub fn notify_data_tx(&mut self) {
-        if let Some(task) = self.send_task.take() {
-            task.notify();
+        if let Some(waker) = self.send_task.take() {
+            waker.wake();
         }
     }
 }
 
+/// Returns true if sequence number `a` precedes `b`, allowing for wrap-around
+pub fn seq_before(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
 pub struct IncomingStreams {
     ctx: SharedConnectionContext,
 }
@@ -82,63 +308,248 @@ pub struct StreamRequester {
     pub ctx: SharedConnectionContext,
 }
 
+/// Handle of a stream, for sending and receiving its frames.
+///
+/// Handles lock only the state of their own stream, and queue frames on the connection's
+/// outbound channel directly, so streams used from different threads do not contend with each
+/// other or with the connection's driver.
 pub struct StreamRef {
     stream_id: StreamId,
     ctx: SharedConnectionContext,
+    state: SharedStreamState,
+    outbound: Sender<Frame>,
+    /// Counters of the whole connection
+    stats: Arc<ConnectionStats>,
 }
 
 impl StreamRef {
+    pub(crate) fn new(
+        stream_id: StreamId,
+        ctx: SharedConnectionContext,
+        state: SharedStreamState,
+        outbound: Sender<Frame>,
+        stats: Arc<ConnectionStats>,
+    ) -> Self {
+        StreamRef {
+            stream_id,
+            ctx,
+            state,
+            outbound,
+            stats,
+        }
+    }
+
     pub fn clone_ctx(&self) -> SharedConnectionContext {
         self.ctx.clone()
     }
 
+    /// Queues `frame` on the connection.
+    ///
+    /// Data frames fail with `MessageInProgress` while another handle of the stream is sending a
+    /// message.
     pub fn send_frame(&mut self, frame: Frame) -> Result<(), ConnectionError> {
-        let mut ctx = self.ctx.lock().unwrap();
-        let ctx = &mut *ctx;
-        ctx.send_frame(frame)
+        match frame {
+            Frame::Data(data) => {
+                let _sending = self
+                    .sending()
+                    .try_lock_owned()
+                    .map_err(|_| ConnectionError::MessageInProgress)?;
+                self.queue_data(data)
+            }
+            frame => {
+                let state = self.state.lock().unwrap();
+                if let Some(ref err) = state.conn_err {
+                    return Err(err.clone());
+                }
+                self.outbound.try_send(frame)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Numbers and queues `data`, whether or not a message is being sent
+    fn queue_data(&mut self, data: frames::Data) -> Result<(), ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        let span = state.span.clone();
+        let _enter = span.enter();
+        state.send_data(data, &mut self.outbound)
+    }
+
+    /// Waits until no other handle of the stream is sending a message, returning the guard which
+    /// keeps others from sending Data frames until it is dropped
+    async fn lock_sending(&self) -> OwnedMutexGuard<()> {
+        self.sending().lock_owned().await
+    }
+
+    fn sending(&self) -> Arc<AsyncMutex<()>> {
+        self.state.lock().unwrap().sending.clone()
+    }
+
+    /// Sends `payload` in a Data frame, numbered by the connection.
+    ///
+    /// Fails instead of waiting if the stream lacks credit or room for replay, or the connection
+    /// lacks room to queue the frame; use `send` to wait.
+    pub fn send_data(&mut self, payload: Bytes) -> Result<(), ConnectionError> {
+        let data = frames::Data::new(self.stream_id, 0, payload);
+        self.send_frame(Frame::Data(data))
+    }
+
+    /// Waits until the stream can send another Data frame, returning the credit available to it.
+    ///
+    /// Also waits for room on the connection's outbound channel.
+    pub fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<u32, ConnectionError>> {
+        let remaining = ready!(self.state.lock().unwrap().poll_capacity(&self.stats, cx))?;
+        ready!(self.outbound.poll_ready(cx)).map_err(|_| ConnectionError::Closed)?;
+        Poll::Ready(Ok(remaining))
+    }
+
+    /// Sends `payload` in a Data frame once the stream has credit and room for it, and no other
+    /// handle is sending a message
+    pub async fn send(&mut self, payload: Bytes) -> Result<(), ConnectionError> {
+        let _sending = self.lock_sending().await;
+        poll_fn(|cx| self.poll_send_ready(cx)).await?;
+        self.queue_data(frames::Data::new(self.stream_id, 0, payload))
+    }
+
+    /// Sends `payload` as a single message of any length, which the remote receives as one Data
+    /// frame.
+    ///
+    /// The message is split into fragments no larger than the configured fragment size or the
+    /// stream's available credit, each of which is sent once the stream has credit and room for
+    /// it. The remote fails the stream if the message exceeds the length it reassembles.
+    ///
+    /// Other handles of the stream wait to send Data frames until the whole message is sent.
+    pub async fn send_message(&mut self, payload: Bytes) -> Result<(), ConnectionError> {
+        let _sending = self.lock_sending().await;
+        self.send_fragments(payload, true).await
+    }
+
+    /// Sends the chunks yielded by `chunks` as a single message, like `send_message`.
+    ///
+    /// The message ends once `chunks` does, so chunks are sent without waiting for the whole
+    /// message to be produced.
+    pub async fn send_message_stream<S>(&mut self, chunks: S) -> Result<(), ConnectionError>
+    where
+        S: futures::Stream<Item = Bytes> + Unpin,
+    {
+        let _sending = self.lock_sending().await;
+        let mut chunks = chunks;
+        let mut current = chunks.next().await.unwrap_or_default();
+        loop {
+            // The last chunk is only known once `chunks` has ended
+            let next = chunks.next().await;
+            self.send_fragments(current, next.is_none()).await?;
+            match next {
+                Some(chunk) => current = chunk,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Sends `payload` in fragments, flagging the last one as the end of the message if `last` is
+    /// set
+    async fn send_fragments(&mut self, payload: Bytes, last: bool) -> Result<(), ConnectionError> {
+        let mut payload = payload;
+        if payload.is_empty() && !last {
+            return Ok(());
+        }
+        let (fragment_size, flow_controlled) = {
+            let state = self.state.lock().unwrap();
+            // Each fragment has to fit in a single frame
+            let max_payload = state.framing.map_or(usize::MAX, |framing| {
+                framing
+                    .max_frame_len
+                    .saturating_sub(framing.data_frame_len(0))
+            });
+            let fragment_size = state.fragments.fragment_size.min(max_payload);
+            (fragment_size.max(1), state.flow_controlled)
+        };
+        loop {
+            let credit = poll_fn(|cx| self.poll_send_ready(cx)).await?;
+            let mut len = payload.len().min(fragment_size);
+            if flow_controlled {
+                len = len.min(credit as usize);
+            }
+            let mut data = frames::Data::new(self.stream_id, 0, payload.split_to(len));
+            if !last || !payload.is_empty() {
+                data.flags |= frames::FLAG_MORE_FRAGMENTS;
+            }
+            self.queue_data(data)?;
+            if payload.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sends an extension frame on this stream, regardless of its credit.
+    ///
+    /// Fails if its type lies outside the range reserved for extensions, or if it is longer than
+    /// the remote reads.
+    pub fn send_extension(&mut self, mut ext: frames::Extension) -> Result<(), ConnectionError> {
+        let framing = {
+            let state = self.state.lock().unwrap();
+            if let Some(ref err) = state.conn_err {
+                return Err(err.clone());
+            }
+            state.framing
+        };
+        ext.stream_id = self.stream_id;
+        extension::send_extension(&mut self.outbound, framing, ext)
     }
 
     pub fn stream_id(&self) -> StreamId {
         self.stream_id
     }
 
+    /// Compresses the payloads of subsequently sent Data frames as configured by `cfg`, or sends
+    /// them uncompressed if `None`. Payloads sent on local connections are never compressed.
+    ///
+    /// Fails if this build does not support the configured codec.
+    pub fn set_compression(&self, cfg: Option<CompressionConfig>) -> Result<(), ConnectionError> {
+        if let Some(ref cfg) = cfg {
+            if !cfg.codec.is_supported() {
+                return Err(ConnectionError::Compression);
+            }
+        }
+        self.state.lock().unwrap().compression = cfg;
+        Ok(())
+    }
+
+    /// Returns a snapshot of this stream's metrics
+    pub fn metrics(&self) -> Option<StreamMetrics> {
+        Some(self.state.lock().unwrap().metrics(self.stream_id))
+    }
+
     // TODO errors
     // TODO expose configurable credit update strategy
+    #[allow(clippy::result_unit_err)]
     pub fn return_credit(&mut self, credit: u32) -> Result<(), ()> {
-        let mut ctx = self.ctx.lock().unwrap();
-        let ctx = &mut *ctx;
-
-        let credit_update: Option<frames::Frame> = {
-            let stream = match ctx.get_stream_state_mut(&self.stream_id) {
-                None => return Err(()),
-                Some(state) => state,
-            };
-
-            let initial = stream.credits.available();
-            let available = stream.credits.add_credit(credit);
-            let capacity = stream.credits.capacity();
-            let thr = (capacity * FC_NUMERATOR / FC_DENOMINATOR) as u32;
-
-            let unannounced_credits = available - initial;
-            let past_threshold = available >= thr;
-
-            if past_threshold {
-                // Only send incremental updates
-                let credit_update = frames::Frame::CreditUpdate(frames::CreditUpdate {
-                    stream_id: self.stream_id,
-                    credit: unannounced_credits,
-                });
-                Some(credit_update)
-            } else {
-                None
-            }
-        };
-        credit_update.map(|frame| {
-            ctx.send_frame(frame).map_err(|err| {
-                println!("Could not send credit frame!! {:?}", err);
+        let mut stream = self.state.lock().unwrap();
+        let span = stream.span.clone();
+        let _enter = span.enter();
+
+        let initial = stream.credits.available();
+        let available = stream.credits.add_credit(credit);
+        trace!(returned = credit, available, "credit returned");
+        let capacity = stream.credits.capacity();
+        let FlowControlRatio(numerator, denominator) = stream.credit_update_ratio;
+        let thr =
+            (u64::from(capacity) * u64::from(numerator) / u64::from(denominator.max(1))) as u32;
+
+        let unannounced_credits = available - initial;
+        let past_threshold = available >= thr;
+
+        if past_threshold && stream.conn_err.is_none() {
+            // Only send incremental updates
+            let credit_update = frames::Frame::CreditUpdate(frames::CreditUpdate {
+                stream_id: self.stream_id,
+                credit: unannounced_credits,
+            });
+            if self.outbound.try_send(credit_update).is_err() {
+                warn!("could not send credit update");
                 // TODO handle
-            })
-        });
+            }
+        }
         Ok(())
     }
 }
@@ -148,95 +559,154 @@ impl Clone for StreamRef {
         StreamRef {
             stream_id: self.stream_id,
             ctx: self.ctx.clone(),
+            state: self.state.clone(),
+            outbound: self.outbound.clone(),
+            stats: self.stats.clone(),
         }
     }
 }
 
 impl futures::Stream for IncomingStreams {
-    type Item = StreamRef;
-    type Error = (); // TODO
+    type Item = Result<StreamRef, ConnectionError>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let stream_id = {
-            let mut ctx = self.ctx.lock().unwrap();
-            let ctx = &mut *ctx;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut ctx = self.ctx.lock().unwrap();
+        let ctx = &mut *ctx;
 
-            if ctx.has_err() {
-                return Ok(Async::Ready(None));
-            }
+        if let Some(err) = ctx.err() {
+            return Poll::Ready(Some(Err(err)));
+        }
 
-            if let Some(ev) = ctx.next_stream() {
-                ev.stream_id
-            } else {
-                ctx.new_stream_task = Some(task::current());
-                return Ok(Async::NotReady);
+        while let Some(ev) = ctx.next_stream() {
+            if let Some(stream) = ctx.stream_ref(&self.ctx, ev.stream_id) {
+                return Poll::Ready(Some(Ok(stream)));
             }
-        };
-        let stream = StreamRef {
-            stream_id,
-            ctx: self.ctx.clone(),
-        };
-        Ok(Async::Ready(Some(stream)))
+        }
+        ctx.new_stream_task = Some(cx.waker().clone());
+        Poll::Pending
     }
 }
 
 impl futures::Stream for StreamRef {
-    type Item = frames::Frame;
-    type Error = (); // TOODO
+    type Item = Result<frames::Frame, ConnectionError>;
+
+    /// Yields the frames received on this stream, reassembling fragmented messages.
+    ///
+    /// Frames which were already received are yielded before the connection's or the stream's
+    /// error, if any.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut me = self.state.lock().unwrap();
+        let me = &mut *me;
+        let span = me.span.clone();
+        let _enter = span.enter();
+
+        loop {
+            let mut data = match Pin::new(&mut me.data).poll_next(cx) {
+                Poll::Ready(Some(Frame::Data(data))) => data,
+                Poll::Ready(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                res => {
+                    return match me.conn_err.clone().or_else(|| me.err.clone()) {
+                        Some(err) => Poll::Ready(Some(Err(err))),
+                        None => {
+                            // Woken up by the connection upon failure
+                            me.recv_task = Some(cx.waker().clone());
+                            res.map(|_| None)
+                        }
+                    };
+                }
+            };
+            if data.codec != Codec::None {
+                let payload = std::mem::take(&mut data.payload);
+                match compression::decompress(data.codec, payload) {
+                    Ok(payload) => {
+                        data.codec = Codec::None;
+                        data.payload = payload;
+                    }
+                    Err(err) => {
+                        warn!(seq_num = data.seq_num, "could not decompress payload");
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+            }
+            let max_len = me.fragments.max_message_len;
+            match me.reassembly.push(data, max_len) {
+                Ok(Some(data)) => return Poll::Ready(Some(Ok(Frame::Data(data)))),
+                // Wait for the message's next fragment
+                Ok(None) => (),
+                Err(err) => {
+                    warn!(max_len, "message exceeds maximum length");
+                    // The connection drops the stream's later frames, and `reassembly` the
+                    // fragments of the message which were already queued
+                    me.err = Some(err.clone());
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+    }
+}
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut ctx = self.ctx.lock().unwrap();
-        let ctx = &mut *ctx;
+/// Sends each item in a Data frame, waiting for credit and room for replay like `send`.
+///
+/// Frames are queued on the connection's outbound channel as they are sent, so flushing only
+/// returns once the driver can take more of them.
+impl futures::Sink<Bytes> for StreamRef {
+    type Error = ConnectionError;
 
-        let me = {
-            match ctx.get_stream_state_mut(&self.stream_id) {
-                None => return Err(()),
-                Some(stream_state) => stream_state,
-            }
-        };
-        me.data.poll().map_err(|why| {
-            println!("Error polling for data; {:?}", why);
-        })
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_ready(cx).map_ok(|_| ())
+    }
+
+    fn start_send(self: Pin<&mut Self>, payload: Bytes) -> Result<(), Self::Error> {
+        self.get_mut().send_data(payload)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut()
+            .outbound
+            .poll_ready(cx)
+            .map_err(|_| ConnectionError::Closed)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
     }
 }
 
-impl futures::Future for StreamRequester {
-    type Item = StreamRef;
-    type Error = ();
+impl Future for StreamRequester {
+    type Output = Result<StreamRef, ConnectionError>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        {
-            let mut ctx = self.ctx.lock().unwrap();
-            let ctx = &mut *ctx;
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Ready(self.get_mut().request())
+    }
+}
 
-            match ctx.get_stream_state_mut(&self.stream_id) {
-                Some(_) => return Err(()), // TODO StreamAlreadyExists
-                None => (),
-            };
-            let (tx, rx) = futures::sync::mpsc::channel(1);
-            let state = StreamState {
-                data_buffer: VecDeque::new(),
-                credits: Credits::new(self.credit),
-                send_task: None,
-                recv_task: None,
-                data: rx,
-            };
-            ctx.stream_senders.insert(self.stream_id, tx);
-            ctx.stream_states.insert(self.stream_id, state);
-            let sr = frames::StreamRequest::new(self.stream_id, self.credit);
+impl StreamRequester {
+    fn request(&mut self) -> Result<StreamRef, ConnectionError> {
+        let mut ctx = self.ctx.lock().unwrap();
+        let ctx = &mut *ctx;
 
-            // TODO this should really be driven by the ConnectionDriver's IoHandle to get appropriate
-            // TODO feedback on success :-\
-            ctx.send_frame(frames::Frame::StreamRequest(sr))
-                .map_err(|_| ())?;
+        if let Some(err) = ctx.err() {
+            return Err(err);
         }
-
-        let stream = StreamRef {
-            stream_id: self.stream_id,
-            ctx: self.ctx.clone(),
-        };
+        if ctx.stream_states.contains_key(&self.stream_id) {
+            return Err(ConnectionError::InvalidStreamId); // TODO StreamAlreadyExists
+        }
+        let (tx, rx) = futures::channel::mpsc::channel(1);
+        let mut state = ctx.new_stream_state(self.stream_id, self.credit, rx);
+        state.local = true;
+        let span = state.span.clone();
+        let _enter = span.enter();
+        debug!(credit_capacity = self.credit, "requesting stream");
+        ctx.stream_senders.insert(self.stream_id, tx);
+        ctx.stream_states
+            .insert(self.stream_id, Arc::new(Mutex::new(state)));
+        let sr = frames::StreamRequest::new(self.stream_id, self.credit);
+
+        // TODO this should really be driven by the ConnectionDriver's IoHandle to get appropriate
+        // TODO feedback on success :-\
+        ctx.send_frame(frames::Frame::StreamRequest(sr))?;
 
         // Hand off ownership of this stream
-        Ok(Async::Ready(stream))
+        Ok(ctx.stream_ref(&self.ctx, self.stream_id).unwrap())
     }
 }
@@ -0,0 +1,347 @@
+//! In-memory duplex pipes, for connecting drivers within a single process.
+//!
+//! Each end of a pipe is an `AsyncRead + AsyncWrite` which reads what the other end wrote. The
+//! number of bytes in flight in each direction is bounded, reads and writes can be split into small
+//! chunks to exercise partial frames, and written bytes can be held back for a fixed latency.
+
+use super::{Connector, Listener};
+use bytes::{Buf, Bytes};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::{self, Ready};
+use futures::Stream;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+/// Configuration of a `Pipe`
+#[derive(Debug, Clone)]
+pub struct PipeConfig {
+    /// Maximum number of bytes in flight in each direction before writes block
+    pub capacity: usize,
+    /// Maximum number of bytes transferred by a single read or write, or `None` for no limit
+    pub chunk_size: Option<usize>,
+    /// Time after which written bytes become readable, or `None` to deliver them immediately
+    pub latency: Option<Duration>,
+}
+
+impl Default for PipeConfig {
+    fn default() -> Self {
+        PipeConfig {
+            capacity: 64 * 1024,
+            chunk_size: None,
+            latency: None,
+        }
+    }
+}
+
+impl PipeConfig {
+    fn chunk(&self, len: usize) -> usize {
+        self.chunk_size
+            .map_or(len, |chunk_size| cmp::min(len, chunk_size))
+    }
+}
+
+/// Bytes flowing in one direction of a pipe
+#[derive(Default)]
+struct Channel {
+    /// Written chunks, with the time at which each becomes readable
+    chunks: VecDeque<(Instant, Bytes)>,
+    buffered: usize,
+    /// Set once the writing end has shut down or been dropped
+    write_closed: bool,
+    /// Set once the reading end has been dropped
+    read_closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Channel {
+    fn notify_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn notify_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// One end of an in-memory duplex pipe
+pub struct Pipe {
+    rx: Arc<Mutex<Channel>>,
+    tx: Arc<Mutex<Channel>>,
+    cfg: PipeConfig,
+    /// Timer waiting for the next chunk to become readable
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+/// Creates a connected pair of pipe ends
+pub fn pipe(cfg: PipeConfig) -> (Pipe, Pipe) {
+    let a = Arc::new(Mutex::new(Channel::default()));
+    let b = Arc::new(Mutex::new(Channel::default()));
+    let left = Pipe {
+        rx: a.clone(),
+        tx: b.clone(),
+        cfg: cfg.clone(),
+        delay: None,
+    };
+    let right = Pipe {
+        rx: b,
+        tx: a,
+        cfg,
+        delay: None,
+    };
+    (left, right)
+}
+
+/// Driver of a connection over one end of a pipe
+#[cfg(test)]
+pub(crate) type PipeDriver =
+    crate::connection::ConnectionDriver<tokio::io::ReadHalf<Pipe>, tokio::io::WriteHalf<Pipe>>;
+
+/// Creates the drivers of both ends of a connection over a pipe, the first opening it with
+/// `client` and the second accepting it with `server`
+#[cfg(test)]
+pub(crate) fn drivers(
+    client: crate::connection::ConnectionConfig,
+    server: crate::connection::ConnectionConfig,
+) -> (PipeDriver, PipeDriver) {
+    use crate::connection::{next_connection_id, ConnectionDriver};
+
+    let (left, right) = pipe(PipeConfig::default());
+    let (rx, tx) = tokio::io::split(left);
+    let client = ConnectionDriver::with_config(rx, tx, next_connection_id(), client);
+    let (rx, tx) = tokio::io::split(right);
+    let server = ConnectionDriver::accept(rx, tx, next_connection_id(), server);
+    (client, server)
+}
+
+/// Spawns the drivers of a connection over a pipe, returning the handles of both ends and the
+/// streams accepted by the second one
+#[cfg(test)]
+pub(crate) fn connect(
+    client: crate::connection::ConnectionConfig,
+    server: crate::connection::ConnectionConfig,
+) -> (
+    crate::connection::ConnectionHandle,
+    crate::connection::ConnectionHandle,
+    crate::stream::IncomingStreams,
+) {
+    let (client, mut server) = drivers(client, server);
+    let (client_handle, server_handle) = (client.handle(), server.handle());
+    let incoming = server.incoming_streams();
+    tokio::spawn(client);
+    tokio::spawn(server);
+    (client_handle, server_handle, incoming)
+}
+
+impl Pipe {
+    /// Waits until `ready_at`, returning false if the calling task has to be woken up later
+    fn poll_delay(&mut self, ready_at: Instant, cx: &mut Context<'_>) -> bool {
+        let ready_at = time::Instant::from_std(ready_at);
+        let delay = self
+            .delay
+            .get_or_insert_with(|| Box::pin(time::sleep_until(ready_at)));
+        if delay.deadline() != ready_at {
+            delay.as_mut().reset(ready_at);
+        }
+        delay.as_mut().poll(cx).is_ready()
+    }
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let rx = self.rx.clone();
+            let mut guard = rx.lock().unwrap();
+            let rx = &mut *guard;
+            let ready_at = match rx.chunks.front_mut() {
+                Some(&mut (ready_at, ref mut chunk)) if ready_at <= Instant::now() => {
+                    let n = self.cfg.chunk(cmp::min(buf.remaining(), chunk.len()));
+                    buf.put_slice(&chunk[..n]);
+                    chunk.advance(n);
+                    if chunk.is_empty() {
+                        rx.chunks.pop_front();
+                    }
+                    rx.buffered -= n;
+                    rx.notify_writer();
+                    return Poll::Ready(Ok(()));
+                }
+                Some(&mut (ready_at, _)) => ready_at,
+                None if rx.write_closed => return Poll::Ready(Ok(())),
+                None => {
+                    rx.reader = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            };
+            drop(guard);
+            if !self.poll_delay(ready_at, cx) {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut tx = self.tx.lock().unwrap();
+        if tx.read_closed || tx.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let available = self.cfg.capacity.saturating_sub(tx.buffered);
+        if available == 0 {
+            tx.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = self.cfg.chunk(cmp::min(buf.len(), available));
+        let ready_at = Instant::now() + self.cfg.latency.unwrap_or_default();
+        tx.chunks
+            .push_back((ready_at, Bytes::copy_from_slice(&buf[..n])));
+        tx.buffered += n;
+        tx.notify_reader();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut tx = self.tx.lock().unwrap();
+        tx.write_closed = true;
+        tx.notify_reader();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        if let Ok(mut tx) = self.tx.lock() {
+            tx.write_closed = true;
+            tx.notify_reader();
+        }
+        if let Ok(mut rx) = self.rx.lock() {
+            rx.read_closed = true;
+            rx.notify_writer();
+        }
+    }
+}
+
+/// Creates a listener together with a connector which establishes pipes to it, so that a
+/// `Server` and `Client` can be connected within one process
+pub fn listener(cfg: PipeConfig) -> (MemoryListener, MemoryConnector) {
+    let (tx, rx) = mpsc::unbounded();
+    let listener = MemoryListener { rx };
+    let connector = MemoryConnector { cfg, tx };
+    (listener, connector)
+}
+
+/// Listener accepting pipes established by its `MemoryConnector`s
+pub struct MemoryListener {
+    rx: UnboundedReceiver<Pipe>,
+}
+
+impl Listener for MemoryListener {
+    type Io = Pipe;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Pipe, String)>> {
+        match ready!(Pin::new(&mut self.rx).poll_next(cx)) {
+            Some(pipe) => Poll::Ready(Ok((pipe, "memory".to_owned()))),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+/// Establishes pipes to a `MemoryListener`
+#[derive(Clone)]
+pub struct MemoryConnector {
+    cfg: PipeConfig,
+    tx: UnboundedSender<Pipe>,
+}
+
+impl fmt::Display for MemoryConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("memory")
+    }
+}
+
+impl Connector for MemoryConnector {
+    type Io = Pipe;
+    type Future = Ready<io::Result<Pipe>>;
+
+    fn connect(&self) -> Self::Future {
+        let (local, remote) = pipe(self.cfg.clone());
+        match self.tx.unbounded_send(remote) {
+            Ok(()) => future::ok(local),
+            Err(_) => future::err(io::ErrorKind::ConnectionRefused.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{next_connection_id, ConnectionConfig, ConnectionDriver};
+    use crate::stream::StreamId;
+    use futures::{StreamExt, TryStreamExt};
+
+    #[tokio::test]
+    async fn drives_connections_over_chunked_delayed_pipe() {
+        let (client, server) = pipe(PipeConfig {
+            capacity: 16,
+            chunk_size: Some(3),
+            latency: Some(Duration::from_millis(5)),
+        });
+
+        let (rx, tx) = tokio::io::split(client);
+        let initiator = ConnectionDriver::with_io(rx, tx, next_connection_id());
+        let handle = initiator.handle();
+        tokio::spawn(initiator);
+
+        let (rx, tx) = tokio::io::split(server);
+        let cfg = ConnectionConfig::default();
+        let mut acceptor = ConnectionDriver::accept(rx, tx, next_connection_id(), cfg);
+        let mut incoming = acceptor.incoming_streams();
+        tokio::spawn(acceptor);
+
+        let mut stream = handle.open_stream(StreamId(1), 1024).await.unwrap();
+        for i in 0..10 {
+            stream
+                .send_data(Bytes::from(format!("frame {}", i)))
+                .unwrap();
+        }
+        let received = incoming.next().await.unwrap().unwrap();
+        let frames: Vec<_> = received.take(10).try_collect().await.unwrap();
+
+        let payloads: Vec<_> = frames
+            .into_iter()
+            .map(|frame| match frame {
+                crate::frames::Frame::Data(data) => data.payload(),
+                other => panic!("unexpected frame: {:?}", other),
+            })
+            .collect();
+        let expected: Vec<_> = (0..10)
+            .map(|i| Bytes::from(format!("frame {}", i)))
+            .collect();
+        assert_eq!(payloads, expected);
+    }
+}
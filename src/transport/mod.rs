@@ -0,0 +1,142 @@
+//! Transports which connections are driven over.
+//!
+//! `Server` accepts connections from any `Listener`, and `Client` establishes them through any
+//! `Connector`, so the same configuration and handshake apply over TCP, over Unix domain sockets
+//! on Unix, and over in-memory pipes within one process.
+
+use crate::socket::SocketConfig;
+use futures::future::{BoxFuture, FutureExt};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+pub mod memory;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(unix)]
+pub use self::unix::UnixConnector;
+
+/// Source of accepted transports
+pub trait Listener: Send + Unpin + 'static {
+    type Io: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// Accepts a transport, together with a description of its remote end
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Io, String)>>;
+
+    /// Applies the socket options in `cfg` to an accepted transport
+    fn configure(&self, _io: &Self::Io, _cfg: &SocketConfig) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Establishes transports to a single remote end
+pub trait Connector: fmt::Display + Send + Unpin + 'static {
+    type Io: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+    type Future: Future<Output = io::Result<Self::Io>> + Send + Unpin + 'static;
+
+    fn connect(&self) -> Self::Future;
+
+    /// Applies the socket options in `cfg` to an established transport
+    fn configure(&self, _io: &Self::Io, _cfg: &SocketConfig) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Listener for TcpListener {
+    type Io = TcpStream;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, String)>> {
+        let (socket, addr) = ready!(TcpListener::poll_accept(self, cx))?;
+        Poll::Ready(Ok((socket, addr.to_string())))
+    }
+
+    fn configure(&self, io: &TcpStream, cfg: &SocketConfig) -> io::Result<()> {
+        cfg.configure(io)
+    }
+}
+
+/// Connects to a TCP address
+#[derive(Debug, Clone)]
+pub struct TcpConnector {
+    addr: SocketAddr,
+}
+
+impl TcpConnector {
+    pub fn new(addr: SocketAddr) -> Self {
+        TcpConnector { addr }
+    }
+}
+
+impl fmt::Display for TcpConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.addr.fmt(f)
+    }
+}
+
+impl Connector for TcpConnector {
+    type Io = TcpStream;
+    type Future = BoxFuture<'static, io::Result<TcpStream>>;
+
+    fn connect(&self) -> Self::Future {
+        TcpStream::connect(self.addr).boxed()
+    }
+
+    fn configure(&self, io: &TcpStream, cfg: &SocketConfig) -> io::Result<()> {
+        cfg.configure(io)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{Connector, Listener};
+    use futures::future::{BoxFuture, FutureExt};
+    use std::fmt;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::task::{Context, Poll};
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// Socket options only apply to TCP, so accepted Unix domain sockets are used as they are
+    impl Listener for UnixListener {
+        type Io = UnixStream;
+
+        fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(UnixStream, String)>> {
+            let (socket, addr) = ready!(UnixListener::poll_accept(self, cx))?;
+            Poll::Ready(Ok((socket, format!("{:?}", addr))))
+        }
+    }
+
+    /// Connects to a Unix domain socket at a filesystem path
+    #[derive(Debug, Clone)]
+    pub struct UnixConnector {
+        path: PathBuf,
+    }
+
+    impl UnixConnector {
+        pub fn new<P: AsRef<Path>>(path: P) -> Self {
+            UnixConnector {
+                path: path.as_ref().to_path_buf(),
+            }
+        }
+    }
+
+    impl fmt::Display for UnixConnector {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.path.display().fmt(f)
+        }
+    }
+
+    impl Connector for UnixConnector {
+        type Io = UnixStream;
+        type Future = BoxFuture<'static, io::Result<UnixStream>>;
+
+        fn connect(&self) -> Self::Future {
+            UnixStream::connect(self.path.clone()).boxed()
+        }
+    }
+}
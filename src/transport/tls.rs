@@ -0,0 +1,467 @@
+//! TLS encryption of transports, enabled by the `tls` feature.
+//!
+//! `TlsListener` and `TlsConnector` wrap any other `Listener` or `Connector`, and complete the TLS
+//! handshake before the transport is handed to a `ConnectionDriver`. The connection's own
+//! handshake then runs over the encrypted transport as usual.
+
+use super::{Connector, Listener};
+pub use crate::server::{DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_MAX_PENDING_HANDSHAKES};
+use crate::socket::SocketConfig;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use rustls;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile;
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsAcceptor;
+
+fn invalid_input<E: fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+/// Reads every certificate from the PEM file at `path`
+pub fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first PKCS#8, PKCS#1 or SEC1 private key from the PEM file at `path`
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => (),
+            None => return Err(invalid_input("no private key found")),
+        }
+    }
+}
+
+/// TLS settings of the accepting end
+#[derive(Debug, Clone)]
+pub struct TlsServerConfig {
+    /// Certificate chain presented to clients, starting with the server's own certificate
+    pub cert_chain: Vec<Certificate>,
+    pub private_key: PrivateKey,
+    /// Roots which client certificates must chain to, or `None` to accept unauthenticated clients
+    pub client_roots: Option<RootCertStore>,
+    /// Time an accepted transport has to complete its handshake before it is dropped
+    pub handshake_timeout: Duration,
+    /// Number of accepted transports whose handshakes run at once; further transports are only
+    /// accepted once one of them completes
+    pub max_pending_handshakes: usize,
+}
+
+impl TlsServerConfig {
+    pub fn build(&self) -> io::Result<Arc<rustls::ServerConfig>> {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match self.client_roots {
+            Some(ref roots) => builder.with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots.clone()).boxed(),
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        builder
+            .with_single_cert(self.cert_chain.clone(), self.private_key.clone())
+            .map(Arc::new)
+            .map_err(invalid_input)
+    }
+}
+
+/// TLS settings of the connecting end
+#[derive(Debug, Clone)]
+pub struct TlsClientConfig {
+    /// Roots which the server's certificate must chain to
+    pub roots: RootCertStore,
+    /// Name which the server's certificate must be valid for
+    pub server_name: String,
+    /// Certificate chain and private key presented to servers which authenticate clients
+    pub identity: Option<(Vec<Certificate>, PrivateKey)>,
+    /// Time the server has to complete the handshake before the connection attempt fails
+    pub handshake_timeout: Duration,
+}
+
+impl TlsClientConfig {
+    pub fn build(&self) -> io::Result<Arc<rustls::ClientConfig>> {
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.roots.clone());
+        let config = match self.identity {
+            Some((ref cert_chain, ref key)) => builder
+                .with_client_auth_cert(cert_chain.clone(), key.clone())
+                .map_err(invalid_input)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// Transport encrypted with TLS
+pub struct TlsStream<T> {
+    inner: tokio_rustls::TlsStream<T>,
+}
+
+impl<T> TlsStream<T> {
+    /// Returns the transport underneath the encryption
+    pub fn get_ref(&self) -> &T {
+        self.inner.get_ref().0
+    }
+
+    /// Returns the certificates presented by the remote, leaf first
+    pub fn peer_certificates(&self) -> Option<&[Certificate]> {
+        self.inner.get_ref().1.peer_certificates()
+    }
+}
+
+impl<T> From<tokio_rustls::TlsStream<T>> for TlsStream<T> {
+    fn from(inner: tokio_rustls::TlsStream<T>) -> Self {
+        TlsStream { inner }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    /// Tells the remote that we are done writing before shutting down the transport
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Handshake of an accepted transport, resolving together with the description of its remote end
+type Accepting<T> = BoxFuture<'static, Result<(TlsStream<T>, String), (io::Error, String)>>;
+
+/// Listener which encrypts the transports accepted by another listener.
+///
+/// Transports whose handshake fails or times out are dropped without being yielded.
+pub struct TlsListener<L: Listener> {
+    inner: L,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Accepting<L::Io>>,
+    handshake_timeout: Duration,
+    max_pending_handshakes: usize,
+}
+
+impl<L: Listener> TlsListener<L> {
+    pub fn new(inner: L, config: Arc<rustls::ServerConfig>) -> Self {
+        TlsListener {
+            inner,
+            acceptor: TlsAcceptor::from(config),
+            handshakes: FuturesUnordered::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_pending_handshakes: DEFAULT_MAX_PENDING_HANDSHAKES,
+        }
+    }
+
+    /// Drops transports which take longer than `timeout` to complete their handshake, and stops
+    /// accepting transports while `max_pending` handshakes are running
+    pub fn set_handshake_limits(&mut self, timeout: Duration, max_pending: usize) {
+        self.handshake_timeout = timeout;
+        self.max_pending_handshakes = cmp::max(max_pending, 1);
+    }
+
+    pub fn get_ref(&self) -> &L {
+        &self.inner
+    }
+}
+
+impl<L: Listener> Listener for TlsListener<L> {
+    type Io = TlsStream<L::Io>;
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Io, String)>> {
+        loop {
+            while self.handshakes.len() < self.max_pending_handshakes {
+                let (io, peer) = match self.inner.poll_accept(cx) {
+                    Poll::Ready(accepted) => accepted?,
+                    Poll::Pending => break,
+                };
+                let handshake =
+                    tokio::time::timeout(self.handshake_timeout, self.acceptor.accept(io));
+                self.handshakes.push(
+                    async move {
+                        match handshake.await {
+                            Ok(Ok(stream)) => {
+                                Ok((TlsStream::from(tokio_rustls::TlsStream::from(stream)), peer))
+                            }
+                            Ok(Err(err)) => Err((err, peer)),
+                            Err(_) => Err((io::ErrorKind::TimedOut.into(), peer)),
+                        }
+                    }
+                    .boxed(),
+                );
+            }
+            // A failed handshake makes room for another transport, so accept again before waiting
+            match self.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(accepted))) => return Poll::Ready(Ok(accepted)),
+                Poll::Ready(Some(Err((err, peer)))) => {
+                    warn!(peer = %peer, error = %err, "TLS handshake failed")
+                }
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn configure(&self, io: &Self::Io, cfg: &SocketConfig) -> io::Result<()> {
+        self.inner.configure(io.get_ref(), cfg)
+    }
+}
+
+/// Connector which encrypts the transports established by another connector
+pub struct TlsConnector<C> {
+    inner: C,
+    connector: tokio_rustls::TlsConnector,
+    server_name: ServerName,
+    handshake_timeout: Duration,
+}
+
+impl<C: Connector> TlsConnector<C> {
+    /// Fails if `server_name` is neither a valid DNS name nor an IP address
+    pub fn new(inner: C, config: Arc<rustls::ClientConfig>, server_name: &str) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name).map_err(invalid_input)?;
+        Ok(TlsConnector {
+            inner,
+            connector: tokio_rustls::TlsConnector::from(config),
+            server_name,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        })
+    }
+
+    /// Fails connection attempts whose handshake takes longer than `timeout` with
+    /// `io::ErrorKind::TimedOut`
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+}
+
+impl<C: Connector> fmt::Display for TlsConnector<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tls://{}", self.inner)
+    }
+}
+
+impl<C: Connector> Connector for TlsConnector<C> {
+    type Io = TlsStream<C::Io>;
+    /// Establishes a transport and completes its TLS handshake, unless it times out
+    type Future = BoxFuture<'static, io::Result<TlsStream<C::Io>>>;
+
+    fn connect(&self) -> Self::Future {
+        let connect = self.inner.connect();
+        let connector = self.connector.clone();
+        let server_name = self.server_name.clone();
+        let handshake_timeout = self.handshake_timeout;
+        async move {
+            let io = connect.await?;
+            let handshake = connector.connect(server_name, io);
+            let stream = match tokio::time::timeout(handshake_timeout, handshake).await {
+                Ok(stream) => stream?,
+                Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+            };
+            Ok(TlsStream::from(tokio_rustls::TlsStream::from(stream)))
+        }
+        .boxed()
+    }
+
+    fn configure(&self, io: &Self::Io, cfg: &SocketConfig) -> io::Result<()> {
+        self.inner.configure(io.get_ref(), cfg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{BackoffConfig, Client, ClientConfig, Health};
+    use crate::frames::Frame;
+    use crate::server::{Server, ServerConfig};
+    use crate::stream::StreamId;
+    use bytes::Bytes;
+    use rcgen;
+
+    /// Generates a self-signed certificate for `name`
+    fn self_signed(name: &str) -> (Certificate, PrivateKey) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        (
+            Certificate(cert.serialize_der().unwrap()),
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    fn roots(cert: &Certificate) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        roots
+    }
+
+    struct Setup {
+        server: TlsServerConfig,
+        client: TlsClientConfig,
+    }
+
+    fn mutual_tls() -> Setup {
+        let (server_cert, server_key) = self_signed("localhost");
+        let (client_cert, client_key) = self_signed("client");
+        Setup {
+            server: TlsServerConfig {
+                cert_chain: vec![server_cert.clone()],
+                private_key: server_key,
+                client_roots: Some(roots(&client_cert)),
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+                max_pending_handshakes: DEFAULT_MAX_PENDING_HANDSHAKES,
+            },
+            client: TlsClientConfig {
+                roots: roots(&server_cert),
+                server_name: "localhost".to_owned(),
+                identity: Some((vec![client_cert], client_key)),
+                handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            },
+        }
+    }
+
+    fn client_config() -> ClientConfig {
+        ClientConfig {
+            backoff: BackoffConfig {
+                max_retries: Some(0),
+                ..BackoffConfig::default()
+            },
+            ..ClientConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_over_mutual_tls() {
+        let setup = mutual_tls();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind_tls(&addr, &setup.server, ServerConfig::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = Client::connect_tls(addr, &setup.client, client_config()).unwrap();
+
+        let received = tokio::spawn(async move {
+            let (_handle, mut incoming) = server.next().await.unwrap().unwrap();
+            let mut stream = incoming.next().await.unwrap().unwrap();
+            stream.next().await
+        });
+        let client = client.await.unwrap();
+        let mut stream = client.open_stream(StreamId(1), 1024).await.unwrap();
+        stream.send_data(Bytes::from("hello")).unwrap();
+
+        match received.await.unwrap() {
+            Some(Ok(Frame::Data(data))) => assert_eq!(data.payload(), Bytes::from("hello")),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_transports_which_stall_their_handshake() {
+        let mut setup = mutual_tls();
+        setup.server.handshake_timeout = Duration::from_millis(100);
+        setup.server.max_pending_handshakes = 1;
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind_tls(&addr, &setup.server, ServerConfig::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // Takes up the only handshake slot without ever sending anything
+        let _stalled = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let client =
+            tokio::spawn(Client::connect_tls(addr, &setup.client, client_config()).unwrap());
+        let accepted = tokio::time::timeout(Duration::from_secs(5), server.next()).await;
+        assert!(matches!(accepted, Ok(Some(Ok(_)))));
+        assert!(client.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn times_out_handshakes_which_server_never_answers() {
+        use crate::transport::TcpConnector;
+
+        let setup = mutual_tls();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accepts the connection but never answers the client's hello
+        let _silent = tokio::spawn(async move {
+            let accepted = listener.accept().await;
+            futures::future::pending::<()>().await;
+            drop(accepted);
+        });
+
+        let mut connector = TlsConnector::new(
+            TcpConnector::new(addr),
+            setup.client.build().unwrap(),
+            &setup.client.server_name,
+        )
+        .unwrap();
+        connector.set_handshake_timeout(Duration::from_millis(100));
+        let connected = tokio::time::timeout(Duration::from_secs(5), connector.connect()).await;
+        match connected {
+            Ok(Err(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            Ok(Ok(_)) => panic!("handshake completed"),
+            Err(_) => panic!("handshake did not time out"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unexpected_server_name_and_unknown_client() {
+        let setup = mutual_tls();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::bind_tls(&addr, &setup.server, ServerConfig::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+
+        let mut wrong_name = setup.client.clone();
+        wrong_name.server_name = "example.com".to_owned();
+        let mut anonymous = setup.client.clone();
+        anonymous.identity = None;
+        for tls in &[wrong_name, anonymous] {
+            // Clients may only learn that they were rejected after completing their handshake
+            let client = match Client::connect_tls(addr, tls, client_config())
+                .unwrap()
+                .await
+            {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            loop {
+                if let Health::Failed(_) = client.health() {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}